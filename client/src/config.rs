@@ -1,12 +1,24 @@
-use clap::Parser;
-use desklink_common::{deserialize_log_level, PROJECT_NAME};
+use chrono::{Local, NaiveDate};
+use clap::{Parser, ValueEnum};
+use desklink_common::{deserialize_log_level, telemetry::DEFAULT_OTLP_ENDPOINT, PROJECT_NAME};
 use directories::ProjectDirs;
 use serde::{de::Deserializer, Deserialize};
-use std::{collections::HashMap, io, path::PathBuf, str::FromStr};
+use std::{
+    collections::HashMap,
+    env,
+    fmt::{self, Debug, Formatter},
+    io,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
 use thiserror::Error;
-use tonic::transport::Endpoint;
+use tonic::transport::{Certificate, ClientTlsConfig, Endpoint, Identity};
 use tracing::Level;
 
+/// Environment variable overriding the token in the config file
+pub const TOKEN_ENV: &str = "DESKLINK_TOKEN";
+
 #[derive(Error, Debug)]
 pub enum ConfigError {
     #[error("IO error: `{path}`")]
@@ -22,8 +34,8 @@ pub enum ConfigError {
     #[error("Missing config field for {0}")]
     MissingConfigField(&'static str),
 
-    #[error("Preset `{0}` not found")]
-    PresetNotFound(String),
+    #[error("Export starts on {from}, after it ends on {to}")]
+    InvalidExportRange { from: NaiveDate, to: NaiveDate },
 }
 
 mod args {
@@ -39,9 +51,9 @@ mod args {
         #[clap(short, long)]
         pub config: Option<PathBuf>,
 
-        /// Server address and port
+        /// Server address and port, or `unix:<path>` for a Unix socket
         #[clap(short, long)]
-        pub server: Option<Endpoint>,
+        pub server: Option<ServerAddress>,
 
         /// Command
         #[clap(subcommand)]
@@ -53,6 +65,9 @@ mod args {
         /// Check the current position and velocity of the desk
         Status,
 
+        /// Print desk lifecycle and motion events as they happen
+        Events,
+
         /// Stop desk motion and cancel in-progress commands
         Stop,
 
@@ -64,6 +79,190 @@ mod args {
             /// Monitor desk position and wait until target is reached
             #[clap(short, long)]
             wait: bool,
+
+            /// Stop the desk if deskctl exits before the target is reached
+            #[clap(short, long)]
+            bind: bool,
+
+            /// Control lease held by this client
+            #[clap(short, long)]
+            lease: Option<String>,
+
+            /// Move even if the desk is locked, requires the admin role
+            #[clap(long)]
+            override_lock: bool,
+        },
+
+        /// Manage exclusive control of the desk
+        #[clap(subcommand)]
+        Control(ControlCommand),
+
+        /// Manage presets stored on the server
+        #[clap(subcommand)]
+        Preset(PresetCommand),
+
+        /// Manage moves scheduled on the server
+        #[clap(subcommand)]
+        Schedule(ScheduleCommand),
+
+        /// Alternate between sitting and standing on the server until stopped
+        Cycle {
+            /// Time sitting, e.g. 40m
+            #[clap(long, required_unless_present = "stop")]
+            sit: Option<humantime::Duration>,
+
+            /// Time standing, e.g. 20m
+            #[clap(long, required_unless_present = "stop")]
+            stand: Option<humantime::Duration>,
+
+            /// Sitting position in cm, or a preset name
+            #[clap(long, default_value = "sitting")]
+            sit_at: String,
+
+            /// Standing position in cm, or a preset name
+            #[clap(long, default_value = "standing")]
+            stand_at: String,
+
+            /// Stop the running cycle instead
+            #[clap(long, conflicts_with_all = &["sit", "stand"])]
+            stop: bool,
+        },
+
+        /// Manage the lock refusing moves on the server
+        #[clap(subcommand)]
+        Lock(LockCommand),
+
+        /// Make the server reload its config file
+        Reload,
+
+        /// Report time sitting and standing, today by default
+        Stats {
+            /// Last 7 days, by day
+            #[clap(long, conflicts_with = "month")]
+            week: bool,
+
+            /// Last 4 weeks, by week
+            #[clap(long)]
+            month: bool,
+        },
+
+        /// Dump height samples, moves and daily totals from the server history
+        Export {
+            /// First day to export (YYYY-MM-DD), defaults to the last day
+            #[clap(long)]
+            from: Option<NaiveDate>,
+
+            /// Last day to export (YYYY-MM-DD), defaults to today
+            #[clap(long)]
+            to: Option<NaiveDate>,
+
+            /// Output format
+            #[clap(long, value_enum, default_value_t = ExportFormat::Csv)]
+            format: ExportFormat,
+
+            /// Table to export, CSV holds a single one and defaults to days, JSON all of them
+            #[clap(long, value_enum)]
+            table: Option<ExportTable>,
+        },
+    }
+
+    #[derive(Parser, Debug)]
+    pub enum PresetCommand {
+        /// List server presets
+        List,
+
+        /// Create or update a server preset
+        Set {
+            /// Preset name
+            name: String,
+
+            /// Position in cm, defaults to the current position
+            position: Option<f32>,
+        },
+
+        /// Remove a server preset
+        Rm {
+            /// Preset name
+            name: String,
+        },
+    }
+
+    #[derive(Parser, Debug)]
+    pub enum ScheduleCommand {
+        /// List schedule entries
+        List,
+
+        /// Create or replace a schedule entry
+        Add {
+            /// Entry name
+            name: String,
+
+            /// `daily|weekdays|weekends|mon,tue,.. HH:MM` or `every <duration>`, like `every 45m`
+            when: String,
+
+            /// Positions in cm or preset names, alternated between if several
+            #[clap(required = true)]
+            targets: Vec<String>,
+        },
+
+        /// Remove a schedule entry
+        Rm {
+            /// Entry name
+            name: String,
+        },
+
+        /// Pause a schedule entry
+        Pause {
+            /// Entry name
+            name: String,
+
+            /// Resume the entry instead
+            #[clap(short, long)]
+            resume: bool,
+        },
+    }
+
+    #[derive(Parser, Debug)]
+    pub enum LockCommand {
+        /// Show the active locks
+        Show,
+
+        /// Lock the desk until cleared, `stop` is always allowed
+        Set {
+            /// Moves to refuse
+            #[clap(value_enum, default_value_t = LockScope::All)]
+            scope: LockScope,
+
+            /// Shown to other clients
+            #[clap(short, long)]
+            reason: Option<String>,
+        },
+
+        /// Clear the lock set with `lock set`, config windows stay in effect
+        Clear,
+    }
+
+    #[derive(Parser, Debug)]
+    pub enum ControlCommand {
+        /// Acquire or renew a time-limited control lease
+        Acquire {
+            /// Name shown to other clients
+            #[clap(long)]
+            holder: Option<String>,
+
+            /// Lease duration, e.g. 10m
+            #[clap(short, long)]
+            duration: Option<humantime::Duration>,
+
+            /// Renew this lease instead of acquiring a new one
+            #[clap(short, long)]
+            renew: Option<String>,
+        },
+
+        /// Release a control lease
+        Release {
+            /// Lease to release
+            lease: String,
         },
     }
 }
@@ -75,6 +274,8 @@ mod file {
     pub struct Config {
         pub log: Option<LogConfig>,
         pub client: Option<ClientConfig>,
+        pub tracing: Option<TracingConfig>,
+        #[serde(default)]
         pub presets: HashMap<String, f32>,
     }
 
@@ -84,18 +285,30 @@ mod file {
         pub level: Option<Level>,
     }
 
+    #[derive(Deserialize)]
+    pub struct TracingConfig {
+        pub otlp_endpoint: Option<String>,
+    }
+
     #[derive(Deserialize)]
     pub struct ClientConfig {
-        #[serde(deserialize_with = "deserialize_endpoint")]
-        pub server: Option<Endpoint>,
+        #[serde(deserialize_with = "deserialize_server_address")]
+        pub server: Option<ServerAddress>,
+        pub token: Option<String>,
+        pub tls_ca: Option<PathBuf>,
+        pub tls_cert: Option<PathBuf>,
+        pub tls_key: Option<PathBuf>,
+        pub tls_domain: Option<String>,
     }
 
-    pub fn deserialize_endpoint<'de, D>(deserializer: D) -> Result<Option<Endpoint>, D::Error>
+    pub fn deserialize_server_address<'de, D>(
+        deserializer: D,
+    ) -> Result<Option<ServerAddress>, D::Error>
     where
         D: Deserializer<'de>,
     {
         let name: Option<String> = Option::deserialize(deserializer)?;
-        name.map(|name| Endpoint::from_str(&name).map_err(serde::de::Error::custom))
+        name.map(|name| ServerAddress::from_str(&name).map_err(serde::de::Error::custom))
             .transpose()
     }
 }
@@ -104,6 +317,8 @@ mod file {
 pub struct Config {
     pub log: LogConfig,
     pub client: ClientConfig,
+    /// Trace export, disabled if not configured
+    pub tracing: Option<TracingConfig>,
     pub command: Command,
 }
 
@@ -113,15 +328,170 @@ pub struct LogConfig {
 }
 
 #[derive(Debug)]
+pub struct TracingConfig {
+    /// OTLP/gRPC collector
+    pub otlp_endpoint: String,
+}
+
+#[derive(Clone, Debug)]
+pub enum ServerAddress {
+    Tcp(Box<Endpoint>),
+    Unix(PathBuf),
+}
+
+impl FromStr for ServerAddress {
+    type Err = tonic::transport::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some(path) => Ok(ServerAddress::Unix(PathBuf::from(path))),
+            None => Ok(ServerAddress::Tcp(Box::new(Endpoint::from_str(s)?))),
+        }
+    }
+}
+
 pub struct ClientConfig {
-    pub server: Endpoint,
+    pub server: ServerAddress,
+    /// Bearer token sent with every request
+    pub token: Option<String>,
+    /// Only used for TCP connections
+    pub tls: Option<TlsConfig>,
+}
+
+impl Debug for ClientConfig {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClientConfig")
+            .field("server", &self.server)
+            .field("token", &self.token.as_ref().map(|_| "<redacted>"))
+            .field("tls", &self.tls)
+            .finish()
+    }
+}
+
+#[derive(Debug)]
+pub struct TlsConfig {
+    /// PEM CA certificates for verifying the server
+    pub ca: PathBuf,
+    /// PEM certificate chain and private key for mutual TLS
+    pub identity: Option<(PathBuf, PathBuf)>,
+    /// Name to verify the server certificate against, defaults to the server host
+    pub domain: Option<String>,
+}
+
+impl TlsConfig {
+    pub fn load(&self) -> Result<ClientTlsConfig, ConfigError> {
+        let mut tls =
+            ClientTlsConfig::new().ca_certificate(Certificate::from_pem(read_file(&self.ca)?));
+        if let Some((cert, key)) = &self.identity {
+            tls = tls.identity(Identity::from_pem(read_file(cert)?, read_file(key)?));
+        }
+        if let Some(domain) = &self.domain {
+            tls = tls.domain_name(domain);
+        }
+        Ok(tls)
+    }
 }
 
 #[derive(Debug)]
 pub enum Command {
     Status,
+    Events,
     Stop,
-    To { target: f32, wait: bool },
+    To {
+        target: Target,
+        wait: bool,
+        bind: bool,
+        lease: Option<String>,
+        override_lock: bool,
+    },
+    AcquireControl {
+        holder: Option<String>,
+        duration: Option<Duration>,
+        renew: Option<String>,
+    },
+    ReleaseControl {
+        lease: String,
+    },
+    ListPresets,
+    SetPreset {
+        name: String,
+        position: Option<f32>,
+    },
+    DeletePreset {
+        name: String,
+    },
+    ListSchedule,
+    SetScheduleEntry {
+        name: String,
+        when: String,
+        targets: Vec<String>,
+    },
+    DeleteScheduleEntry {
+        name: String,
+    },
+    PauseScheduleEntry {
+        name: String,
+        paused: bool,
+    },
+    StartCycle {
+        sit: Duration,
+        stand: Duration,
+        /// Positions in cm or server preset names
+        sit_target: String,
+        stand_target: String,
+    },
+    StopCycle,
+    ShowLock,
+    /// Clears the lock if unset
+    SetLock {
+        scope: Option<LockScope>,
+        reason: String,
+    },
+    ReloadConfig,
+    Stats {
+        period: StatsPeriod,
+    },
+    Export {
+        from: NaiveDate,
+        to: NaiveDate,
+        format: ExportFormat,
+        /// All tables if unset
+        tables: Vec<ExportTable>,
+    },
+}
+
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LockScope {
+    /// Moves started by the server, like scheduled ones
+    Automatic,
+    All,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum StatsPeriod {
+    Day,
+    Week,
+    Month,
+}
+
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+    Csv,
+    Json,
+}
+
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ExportTable {
+    Samples,
+    Moves,
+    Days,
+}
+
+#[derive(Debug)]
+pub enum Target {
+    Position(f32),
+    /// A preset that is not defined in the client config, resolved by the server
+    Preset(String),
 }
 
 impl Config {
@@ -160,29 +530,191 @@ impl Config {
                     .or_else(|| toml_config.log.and_then(|l| l.level))
                     .unwrap_or(Level::INFO),
             },
-            client: ClientConfig {
-                server: args
+            client: {
+                let (server, token, tls_ca, tls_cert, tls_key, tls_domain) =
+                    match toml_config.client {
+                        Some(client) => (
+                            client.server,
+                            client.token,
+                            client.tls_ca,
+                            client.tls_cert,
+                            client.tls_key,
+                            client.tls_domain,
+                        ),
+                        None => (None, None, None, None, None, None),
+                    };
+                let server = args
                     .server
-                    .or_else(|| toml_config.client.and_then(|c| c.server))
-                    .ok_or(ConfigError::MissingConfigField("server address"))?,
+                    .or(server)
+                    .ok_or(ConfigError::MissingConfigField("server address"))?;
+                let identity = match (tls_cert, tls_key) {
+                    (Some(cert), Some(key)) => Some((cert, key)),
+                    (Some(_), None) => {
+                        return Err(ConfigError::MissingConfigField("client TLS key"))
+                    }
+                    (None, Some(_)) => {
+                        return Err(ConfigError::MissingConfigField("client TLS certificate"))
+                    }
+                    (None, None) => None,
+                };
+                let tls = match tls_ca {
+                    Some(ca) => Some(TlsConfig {
+                        ca,
+                        identity,
+                        domain: tls_domain,
+                    }),
+                    None if identity.is_some()
+                        || matches!(&server, ServerAddress::Tcp(endpoint)
+                            if endpoint.uri().scheme_str() == Some("https")) =>
+                    {
+                        return Err(ConfigError::MissingConfigField("TLS CA certificate"))
+                    }
+                    None => None,
+                };
+                ClientConfig {
+                    server,
+                    token: env::var(TOKEN_ENV).ok().or(token),
+                    tls,
+                }
             },
+            tracing: toml_config.tracing.map(|tracing| TracingConfig {
+                otlp_endpoint: tracing
+                    .otlp_endpoint
+                    .unwrap_or_else(|| DEFAULT_OTLP_ENDPOINT.to_owned()),
+            }),
             command: match args.command {
                 args::Command::Status => Command::Status,
+                args::Command::Events => Command::Events,
                 args::Command::Stop => Command::Stop,
-                args::Command::To { target, wait } => Command::To {
-                    target: {
-                        target.parse::<f32>().or_else(|_| {
-                            toml_config
-                                .presets
-                                .get(&target)
-                                .copied()
-                                .ok_or(ConfigError::PresetNotFound(target))
-                        })?
+                args::Command::To {
+                    target,
+                    wait,
+                    bind,
+                    lease,
+                    override_lock,
+                } => Command::To {
+                    target: match target.parse::<f32>() {
+                        Ok(position) => Target::Position(position),
+                        Err(_) => match toml_config.presets.get(&target) {
+                            Some(position) => Target::Position(*position),
+                            None => Target::Preset(target),
+                        },
                     },
                     wait,
+                    bind,
+                    lease,
+                    override_lock,
+                },
+                args::Command::Control(args::ControlCommand::Acquire {
+                    holder,
+                    duration,
+                    renew,
+                }) => Command::AcquireControl {
+                    holder,
+                    duration: duration.map(Into::into),
+                    renew,
+                },
+                args::Command::Control(args::ControlCommand::Release { lease }) => {
+                    Command::ReleaseControl { lease }
+                }
+                args::Command::Preset(args::PresetCommand::List) => Command::ListPresets,
+                args::Command::Preset(args::PresetCommand::Set { name, position }) => {
+                    Command::SetPreset { name, position }
+                }
+                args::Command::Preset(args::PresetCommand::Rm { name }) => {
+                    Command::DeletePreset { name }
+                }
+                args::Command::Schedule(args::ScheduleCommand::List) => Command::ListSchedule,
+                args::Command::Schedule(args::ScheduleCommand::Add {
+                    name,
+                    when,
+                    targets,
+                }) => Command::SetScheduleEntry {
+                    name,
+                    when,
+                    targets,
+                },
+                args::Command::Schedule(args::ScheduleCommand::Rm { name }) => {
+                    Command::DeleteScheduleEntry { name }
+                }
+                args::Command::Schedule(args::ScheduleCommand::Pause { name, resume }) => {
+                    Command::PauseScheduleEntry {
+                        name,
+                        paused: !resume,
+                    }
+                }
+                args::Command::Cycle { stop: true, .. } => Command::StopCycle,
+                args::Command::Cycle {
+                    sit,
+                    stand,
+                    sit_at,
+                    stand_at,
+                    ..
+                } => {
+                    // client presets are sent as positions
+                    let resolve = |target: String| match toml_config.presets.get(&target) {
+                        Some(position) => position.to_string(),
+                        None => target,
+                    };
+                    Command::StartCycle {
+                        sit: sit.expect("Sitting time is required").into(),
+                        stand: stand.expect("Standing time is required").into(),
+                        sit_target: resolve(sit_at),
+                        stand_target: resolve(stand_at),
+                    }
+                }
+                args::Command::Lock(args::LockCommand::Show) => Command::ShowLock,
+                args::Command::Lock(args::LockCommand::Set { scope, reason }) => Command::SetLock {
+                    scope: Some(scope),
+                    reason: reason.unwrap_or_default(),
+                },
+                args::Command::Lock(args::LockCommand::Clear) => Command::SetLock {
+                    scope: None,
+                    reason: String::new(),
                 },
+                args::Command::Reload => Command::ReloadConfig,
+                args::Command::Stats { week, month } => Command::Stats {
+                    period: if month {
+                        StatsPeriod::Month
+                    } else if week {
+                        StatsPeriod::Week
+                    } else {
+                        StatsPeriod::Day
+                    },
+                },
+                args::Command::Export {
+                    from,
+                    to,
+                    format,
+                    table,
+                } => {
+                    let to = to.unwrap_or_else(|| Local::now().date_naive());
+                    let from = from.unwrap_or(to);
+                    if from > to {
+                        return Err(ConfigError::InvalidExportRange { from, to });
+                    }
+                    Command::Export {
+                        from,
+                        to,
+                        format,
+                        tables: match (table, format) {
+                            (Some(table), _) => vec![table],
+                            (None, ExportFormat::Csv) => vec![ExportTable::Days],
+                            (None, ExportFormat::Json) => {
+                                vec![ExportTable::Samples, ExportTable::Moves, ExportTable::Days]
+                            }
+                        },
+                    }
+                }
             },
         };
         Ok(config)
     }
 }
+
+fn read_file(path: &Path) -> Result<Vec<u8>, ConfigError> {
+    std::fs::read(path).map_err(|error| ConfigError::IoError {
+        path: path.to_owned(),
+        error,
+    })
+}
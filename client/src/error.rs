@@ -0,0 +1,50 @@
+use crate::Position;
+use anyhow::anyhow;
+use desklink_common::rpc::{error_detail::Code, ErrorDetail};
+use std::time::Duration;
+use tonic::Status;
+
+/// Turn an error status from deskd into a message for humans
+pub(crate) fn describe(status: Status) -> anyhow::Error {
+    let detail = match ErrorDetail::from_status(&status) {
+        Some(detail) => detail,
+        None => return status.into(),
+    };
+    match Code::from_i32(detail.code) {
+        Some(Code::OutOfRange) => match detail.allowed_range {
+            Some(range) if status.code() == tonic::Code::PermissionDenied => anyhow!(
+                "Not allowed to move the desk outside {} to {}",
+                range.min.cm().trim(),
+                range.max.cm().trim(),
+            ),
+            Some(range) => anyhow!(
+                "Target {} is out of range, the desk can move between {} and {}",
+                detail.requested.cm().trim(),
+                range.min.cm().trim(),
+                range.max.cm().trim(),
+            ),
+            None => anyhow!("Target {} is out of range", detail.requested.cm().trim()),
+        },
+        Some(Code::Busy) => anyhow!("The desk is busy with another command, please try again"),
+        Some(Code::Aborted) => anyhow!("The move was aborted"),
+        Some(Code::Stalled) => anyhow!("The desk stalled before reaching the target"),
+        Some(Code::Disconnected) => anyhow!("deskd is not connected to the desk"),
+        Some(Code::Unauthorized) => anyhow!("Not authorized: {}", status.message()),
+        Some(Code::ControlHeld) => anyhow!(
+            "The desk is controlled by {} for another {}",
+            detail.lease_holder,
+            humantime::format_duration(Duration::from_secs(
+                u64::from(detail.lease_remaining_ms) / 1000
+            )),
+        ),
+        Some(Code::NotFound) => anyhow!("{}", status.message()),
+        Some(Code::Locked) => anyhow!(
+            "{}, admins can move anyway with --override-lock",
+            status.message()
+        ),
+        Some(Code::InvalidArgument) | Some(Code::FailedPrecondition) => {
+            anyhow!("{}", status.message())
+        }
+        Some(Code::Internal) | Some(Code::Unknown) | None => status.into(),
+    }
+}
@@ -1,21 +1,121 @@
 use anyhow::Result;
-use config::Command;
-use subcommands::{status, stop, to};
+use config::{ClientConfig, Command, ServerAddress};
+use desklink_common::{rpc::desk_service_client::DeskServiceClient, telemetry};
+use subcommands::{
+    control, cycle, events, export, lock, preset, reload, schedule, stats, status, stop, to,
+};
+use tokio::net::UnixStream;
+use tonic::{
+    codegen::InterceptedService,
+    metadata::{Ascii, MetadataValue},
+    service::Interceptor,
+    transport::{Channel, Endpoint, Uri},
+    Request, Status,
+};
+use tower::service_fn;
 
 pub mod config;
+mod error;
 mod subcommands;
 
-type Client = desklink_common::rpc::desk_service_client::DeskServiceClient<
-    tonic::transport::channel::Channel,
->;
+type Client = DeskServiceClient<InterceptedService<Channel, RequestMetadata>>;
+
+/// Attaches the bearer token and the trace context to every request
+#[derive(Clone)]
+pub struct RequestMetadata {
+    authorization: Option<MetadataValue<Ascii>>,
+}
+
+impl Interceptor for RequestMetadata {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        if let Some(value) = &self.authorization {
+            request
+                .metadata_mut()
+                .insert("authorization", value.clone());
+        }
+        telemetry::inject(request.metadata_mut());
+        Ok(request)
+    }
+}
+
+pub async fn connect(config: ClientConfig) -> Result<Client> {
+    let authorization = config
+        .token
+        .map(|token| {
+            let mut value = MetadataValue::try_from(format!("Bearer {}", token))?;
+            value.set_sensitive(true);
+            Ok::<_, anyhow::Error>(value)
+        })
+        .transpose()?;
+    let channel = match config.server {
+        ServerAddress::Tcp(endpoint) => {
+            let mut endpoint = *endpoint;
+            if let Some(tls) = config.tls {
+                endpoint = endpoint.tls_config(tls.load()?)?;
+            }
+            endpoint.connect().await?
+        }
+        ServerAddress::Unix(path) => {
+            // the URI is required by the endpoint but ignored by the connector
+            Endpoint::from_static("http://localhost")
+                .connect_with_connector(service_fn(move |_: Uri| UnixStream::connect(path.clone())))
+                .await?
+        }
+    };
+    Ok(DeskServiceClient::with_interceptor(
+        channel,
+        RequestMetadata { authorization },
+    ))
+}
 
 pub async fn run(client: Client, command: Command) -> Result<()> {
     match command {
-        Command::Status => status::run(client).await?,
-        Command::Stop => stop::run(client).await?,
-        Command::To { target, wait } => to::run(client, target, wait).await?,
+        Command::Status => status::run(client).await,
+        Command::Events => events::run(client).await,
+        Command::Stop => stop::run(client).await,
+        Command::To {
+            target,
+            wait,
+            bind,
+            lease,
+            override_lock,
+        } => to::run(client, target, wait, bind, lease, override_lock).await,
+        Command::AcquireControl {
+            holder,
+            duration,
+            renew,
+        } => control::acquire(client, holder, duration, renew).await,
+        Command::ReleaseControl { lease } => control::release(client, lease).await,
+        Command::ListPresets => preset::list(client).await,
+        Command::SetPreset { name, position } => preset::set(client, name, position).await,
+        Command::DeletePreset { name } => preset::delete(client, name).await,
+        Command::ListSchedule => schedule::list(client).await,
+        Command::SetScheduleEntry {
+            name,
+            when,
+            targets,
+        } => schedule::set(client, name, when, targets).await,
+        Command::DeleteScheduleEntry { name } => schedule::delete(client, name).await,
+        Command::PauseScheduleEntry { name, paused } => schedule::pause(client, name, paused).await,
+        Command::StartCycle {
+            sit,
+            stand,
+            sit_target,
+            stand_target,
+        } => cycle::start(client, sit, stand, sit_target, stand_target).await,
+        Command::StopCycle => cycle::stop(client).await,
+        Command::ShowLock => lock::show(client).await,
+        Command::SetLock { scope, reason } => lock::set(client, scope, reason).await,
+        Command::ReloadConfig => reload::run(client).await,
+        Command::Stats { period } => stats::run(client, period).await,
+        Command::Export {
+            from,
+            to,
+            format,
+            tables,
+        } => export::run(client, from, to, format, tables).await,
     }
-    Ok(())
+    .map_err(error::describe)
 }
 
 trait Position {
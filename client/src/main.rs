@@ -1,6 +1,8 @@
 use anyhow::Result;
 use desklink_client::config::Config;
-use desklink_common::rpc::desk_service_client::DeskServiceClient;
+use desklink_common::telemetry;
+use tracing::{info_span, Instrument};
+use tracing_subscriber::{filter::LevelFilter, fmt, prelude::*};
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<()> {
@@ -9,12 +11,27 @@ async fn main() -> Result<()> {
     #[cfg(debug_assertions)]
     println!("{:#?}", config);
 
-    // Logger
-    tracing_subscriber::fmt()
-        .with_max_level(config.log.level)
+    // Logger, spans are also exported if tracing is configured
+    let otel = config
+        .tracing
+        .as_ref()
+        .map(|tracing| telemetry::tracer(&tracing.otlp_endpoint, "deskctl"))
+        .transpose()?
+        .map(|tracer| tracing_opentelemetry::layer().with_tracer(tracer));
+    tracing_subscriber::registry()
+        .with(fmt::layer())
+        .with(otel)
+        .with(LevelFilter::from_level(config.log.level))
         .init();
 
-    // Run command
-    let client = DeskServiceClient::connect(config.client.server).await?;
-    desklink_client::run(client, config.command).await
+    // Run command, as the root span continued by the server
+    let span = info_span!("deskctl", command = ?config.command);
+    let result = async {
+        let client = desklink_client::connect(config.client).await?;
+        desklink_client::run(client, config.command).await
+    }
+    .instrument(span)
+    .await;
+    telemetry::shutdown().await;
+    result
 }
@@ -0,0 +1,41 @@
+use crate::Client;
+use desklink_common::rpc::{
+    AcquireControlRequest, AcquireControlResponse, ReleaseControlRequest, ReleaseControlResponse,
+};
+use std::time::Duration;
+use tonic::Status;
+
+pub(crate) async fn acquire(
+    mut client: Client,
+    holder: Option<String>,
+    duration: Option<Duration>,
+    renew: Option<String>,
+) -> Result<(), Status> {
+    let AcquireControlResponse {
+        lease_id,
+        duration_ms,
+    } = client
+        .acquire_control(AcquireControlRequest {
+            holder: holder.unwrap_or_default(),
+            duration_ms: duration
+                .map(|d| d.as_millis().try_into().unwrap_or(u32::MAX))
+                .unwrap_or(0),
+            lease_id: renew.unwrap_or_default(),
+        })
+        .await?
+        .into_inner();
+    println!(
+        "Lease: {}\nExpires in: {}",
+        lease_id,
+        humantime::format_duration(Duration::from_secs(u64::from(duration_ms) / 1000))
+    );
+    Ok(())
+}
+
+pub(crate) async fn release(mut client: Client, lease: String) -> Result<(), Status> {
+    let ReleaseControlResponse {} = client
+        .release_control(ReleaseControlRequest { lease_id: lease })
+        .await?
+        .into_inner();
+    Ok(())
+}
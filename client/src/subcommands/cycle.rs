@@ -0,0 +1,56 @@
+use crate::Client;
+use desklink_common::rpc::{
+    get_cycle_response::Posture, GetCycleResponse, StartCycleRequest, StartCycleResponse,
+    StopCycleRequest, StopCycleResponse,
+};
+use std::time::Duration;
+use tonic::Status;
+
+pub(crate) async fn start(
+    mut client: Client,
+    sit: Duration,
+    stand: Duration,
+    sit_target: String,
+    stand_target: String,
+) -> Result<(), Status> {
+    let StartCycleResponse {} = client
+        .start_cycle(StartCycleRequest {
+            sit_ms: sit.as_millis() as u64,
+            stand_ms: stand.as_millis() as u64,
+            sit_target,
+            stand_target,
+        })
+        .await?
+        .into_inner();
+    Ok(())
+}
+
+pub(crate) async fn stop(mut client: Client) -> Result<(), Status> {
+    let StopCycleResponse {} = client.stop_cycle(StopCycleRequest {}).await?.into_inner();
+    Ok(())
+}
+
+/// State of a running cycle, for the status output
+pub(crate) fn describe(cycle: &GetCycleResponse) -> String {
+    let posture = match cycle.posture() {
+        Posture::Sitting => "sitting",
+        Posture::Standing => "standing",
+    };
+    let phase = if cycle.paused {
+        "paused after manual movement".to_owned()
+    } else {
+        format!("{} for {}", posture, minutes(cycle.remaining_ms))
+    };
+    format!(
+        "{} (sit {} at {}, stand {} at {})",
+        phase,
+        minutes(cycle.sit_ms),
+        cycle.sit_target,
+        minutes(cycle.stand_ms),
+        cycle.stand_target
+    )
+}
+
+fn minutes(ms: u64) -> String {
+    format!("{}m", ms.div_ceil(60_000))
+}
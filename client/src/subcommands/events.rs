@@ -0,0 +1,77 @@
+use super::stats::hours;
+use crate::{Client, Position};
+use desklink_common::rpc::{
+    connection_event, goal_event, move_event, subscribe_events_response::Event, ConnectionEvent,
+    GoalEvent, ManualMovementEvent, MoveEvent, SubscribeEventsRequest,
+};
+use tonic::Status;
+
+pub(crate) async fn run(mut client: Client) -> Result<(), Status> {
+    let mut events = client
+        .subscribe_events(SubscribeEventsRequest {})
+        .await?
+        .into_inner();
+    while let Some(response) = events.message().await? {
+        match response.event {
+            Some(Event::Connection(ConnectionEvent { state })) => {
+                let state = match connection_event::State::from_i32(state) {
+                    Some(connection_event::State::Connected) => "connected",
+                    Some(connection_event::State::Disconnected) => "disconnected",
+                    Some(connection_event::State::Reconnecting) => "reconnecting",
+                    None => "unknown",
+                };
+                println!("Desk {}", state);
+            }
+            Some(Event::MoveEvent(MoveEvent {
+                id,
+                outcome,
+                target,
+                position,
+                client,
+                preempted_by,
+            })) => {
+                let outcome = match move_event::Outcome::from_i32(outcome) {
+                    Some(move_event::Outcome::Started) => "started".to_owned(),
+                    Some(move_event::Outcome::Reached) => "reached".to_owned(),
+                    Some(move_event::Outcome::Aborted) => "aborted".to_owned(),
+                    Some(move_event::Outcome::Stalled) => "stalled".to_owned(),
+                    Some(move_event::Outcome::Preempted) => {
+                        format!("preempted by {}", preempted_by)
+                    }
+                    None => "unknown".to_owned(),
+                };
+                println!(
+                    "Move #{} to {} {} at {} (from {})",
+                    id,
+                    target.cm(),
+                    outcome,
+                    position.cm(),
+                    client
+                );
+            }
+            Some(Event::ManualMovement(ManualMovementEvent { position })) => {
+                println!("Manual movement at {}", position.cm());
+            }
+            Some(Event::ConfigReloaded(_)) => println!("Config reloaded"),
+            Some(Event::Goal(GoalEvent {
+                kind,
+                standing_ms,
+                goal_ms,
+            })) => {
+                let kind = match goal_event::Kind::from_i32(kind) {
+                    Some(goal_event::Kind::Reached) => "reached",
+                    Some(goal_event::Kind::Behind) => "behind",
+                    None => "unknown",
+                };
+                println!(
+                    "Standing goal {}, {} of {} today",
+                    kind,
+                    hours(standing_ms),
+                    hours(goal_ms)
+                );
+            }
+            None => {}
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,169 @@
+use crate::{
+    config::{ExportFormat, ExportTable},
+    Client,
+};
+use chrono::{Duration, Local, NaiveDate, SecondsFormat, TimeZone};
+use desklink_common::{
+    day_start_ms,
+    rpc::{ExportRequest, ExportResponse},
+};
+use serde::Serialize;
+use tonic::Status;
+
+#[derive(Serialize)]
+struct Sample {
+    timestamp: String,
+    position_cm: f32,
+    velocity_cm_per_s: f32,
+}
+
+#[derive(Serialize)]
+struct Move {
+    start: String,
+    end: String,
+    start_position_cm: f32,
+    end_position_cm: f32,
+}
+
+#[derive(Serialize)]
+struct Day {
+    date: String,
+    sitting_s: u64,
+    standing_s: u64,
+    unknown_s: u64,
+    transitions: u32,
+    longest_sit_s: u64,
+}
+
+/// Tables not exported are left out
+#[derive(Serialize, Default)]
+struct Tables {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    samples: Option<Vec<Sample>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    moves: Option<Vec<Move>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    days: Option<Vec<Day>>,
+}
+
+pub(crate) async fn run(
+    mut client: Client,
+    from: NaiveDate,
+    to: NaiveDate,
+    format: ExportFormat,
+    tables: Vec<ExportTable>,
+) -> Result<(), Status> {
+    let mut day_starts_ms = Vec::new();
+    let mut day = from + Duration::days(1);
+    while day <= to {
+        day_starts_ms.push(day_start_ms(day));
+        day += Duration::days(1);
+    }
+    let mut chunks = client
+        .export(ExportRequest {
+            start_ms: day_start_ms(from),
+            end_ms: day_start_ms(to + Duration::days(1)),
+            day_starts_ms,
+        })
+        .await?
+        .into_inner();
+
+    let mut output = Tables::default();
+    for table in tables {
+        match table {
+            ExportTable::Samples => output.samples = Some(Vec::new()),
+            ExportTable::Moves => output.moves = Some(Vec::new()),
+            ExportTable::Days => output.days = Some(Vec::new()),
+        }
+    }
+    // chunks may be split into pages, the first one has the stats
+    while let Some(page) = chunks.message().await? {
+        let ExportResponse {
+            start_ms,
+            stats,
+            samples,
+            moves,
+            ..
+        } = page;
+        if let Some(output) = output.samples.as_mut() {
+            output.extend(samples.into_iter().map(|sample| Sample {
+                timestamp: timestamp(sample.timestamp_ms),
+                position_cm: sample.position,
+                velocity_cm_per_s: sample.velocity,
+            }));
+        }
+        if let Some(output) = output.moves.as_mut() {
+            output.extend(moves.into_iter().map(|segment| Move {
+                start: timestamp(segment.start_ms),
+                end: timestamp(segment.end_ms),
+                start_position_cm: segment.start_position,
+                end_position_cm: segment.end_position,
+            }));
+        }
+        if let (Some(output), Some(stats)) = (output.days.as_mut(), stats) {
+            output.push(Day {
+                date: Local
+                    .timestamp_millis_opt(start_ms)
+                    .unwrap()
+                    .format("%Y-%m-%d")
+                    .to_string(),
+                sitting_s: stats.sitting_ms / 1000,
+                standing_s: stats.standing_ms / 1000,
+                unknown_s: stats.unknown_ms / 1000,
+                transitions: stats.transitions,
+                longest_sit_s: stats.longest_sit_ms / 1000,
+            });
+        }
+    }
+    match format {
+        ExportFormat::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&output).expect("Error serializing export")
+        ),
+        // a single table is set for CSV
+        ExportFormat::Csv => {
+            if let Some(samples) = output.samples {
+                println!("timestamp,position_cm,velocity_cm_per_s");
+                for sample in samples {
+                    println!(
+                        "{},{},{}",
+                        sample.timestamp, sample.position_cm, sample.velocity_cm_per_s
+                    );
+                }
+            } else if let Some(moves) = output.moves {
+                println!("start,end,start_position_cm,end_position_cm");
+                for segment in moves {
+                    println!(
+                        "{},{},{},{}",
+                        segment.start,
+                        segment.end,
+                        segment.start_position_cm,
+                        segment.end_position_cm
+                    );
+                }
+            } else if let Some(days) = output.days {
+                println!("date,sitting_s,standing_s,unknown_s,transitions,longest_sit_s");
+                for day in days {
+                    println!(
+                        "{},{},{},{},{},{}",
+                        day.date,
+                        day.sitting_s,
+                        day.standing_s,
+                        day.unknown_s,
+                        day.transitions,
+                        day.longest_sit_s
+                    );
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Local time with offset, which spreadsheets and notebooks parse
+fn timestamp(ms: i64) -> String {
+    Local
+        .timestamp_millis_opt(ms)
+        .unwrap()
+        .to_rfc3339_opts(SecondsFormat::Millis, false)
+}
@@ -0,0 +1,81 @@
+use crate::{config::LockScope, Client};
+use chrono::{Local, TimeZone};
+use desklink_common::rpc::{
+    lock::Scope, GetLockRequest, GetLockResponse, Lock, SetLockRequest, SetLockResponse,
+};
+use tonic::Status;
+
+pub(crate) async fn show(mut client: Client) -> Result<(), Status> {
+    let GetLockResponse { locks, .. } = client.get_lock(GetLockRequest {}).await?.into_inner();
+    if locks.is_empty() {
+        println!("Unlocked");
+    }
+    for lock in locks {
+        println!("{}", describe(&lock));
+    }
+    Ok(())
+}
+
+/// Clears the lock if no scope is given
+pub(crate) async fn set(
+    mut client: Client,
+    scope: Option<LockScope>,
+    reason: String,
+) -> Result<(), Status> {
+    let scope = match scope {
+        None => Scope::None,
+        Some(LockScope::Automatic) => Scope::Automatic,
+        Some(LockScope::All) => Scope::All,
+    };
+    let SetLockResponse {} = client
+        .set_lock(SetLockRequest {
+            scope: scope as i32,
+            reason,
+        })
+        .await?
+        .into_inner();
+    Ok(())
+}
+
+/// An active lock, for the status output
+pub(crate) fn describe(lock: &Lock) -> String {
+    let scope = match lock.scope() {
+        Scope::Automatic => "automatic moves",
+        Scope::All | Scope::None => "all moves",
+    };
+    let time = |ms| {
+        Local
+            .timestamp_millis_opt(ms)
+            .single()
+            .map_or_else(String::new, |time| time.format("%a %H:%M").to_string())
+    };
+    if !lock.calendar_rule.is_empty() {
+        format!(
+            "{}, event `{}` of calendar rule `{}` until {}",
+            scope,
+            lock.event,
+            lock.calendar_rule,
+            time(lock.until_ms)
+        )
+    } else if lock.window.is_empty() {
+        let reason = if lock.reason.is_empty() {
+            String::new()
+        } else {
+            format!(" `{}`", lock.reason)
+        };
+        format!(
+            "{}{}, set by {} on {}",
+            scope,
+            reason,
+            lock.client,
+            time(lock.since_ms)
+        )
+    } else {
+        format!(
+            "{}, window `{}` until {}",
+            scope,
+            lock.window,
+            time(lock.until_ms)
+        )
+    }
+}
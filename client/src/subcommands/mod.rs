@@ -1,3 +1,12 @@
+pub(crate) mod control;
+pub(crate) mod cycle;
+pub(crate) mod events;
+pub(crate) mod export;
+pub(crate) mod lock;
+pub(crate) mod preset;
+pub(crate) mod reload;
+pub(crate) mod schedule;
+pub(crate) mod stats;
 pub(crate) mod status;
 pub(crate) mod stop;
 pub(crate) mod to;
@@ -0,0 +1,56 @@
+use crate::{Client, Position};
+use desklink_common::rpc::{
+    DeletePresetRequest, DeletePresetResponse, GetStateRequest, ListPresetsRequest,
+    ListPresetsResponse, SetPresetRequest, SetPresetResponse,
+};
+use tonic::Status;
+
+pub(crate) async fn list(mut client: Client) -> Result<(), Status> {
+    let ListPresetsResponse { presets } = client
+        .list_presets(ListPresetsRequest {})
+        .await?
+        .into_inner();
+    for preset in presets {
+        println!(
+            "{}: {}{}",
+            preset.name,
+            preset.position.cm(),
+            if preset.from_config {
+                " (server config)"
+            } else {
+                ""
+            }
+        );
+    }
+    Ok(())
+}
+
+pub(crate) async fn set(
+    mut client: Client,
+    name: String,
+    position: Option<f32>,
+) -> Result<(), Status> {
+    let position = match position {
+        Some(position) => position,
+        None => {
+            client
+                .get_state(GetStateRequest {})
+                .await?
+                .into_inner()
+                .position
+        }
+    };
+    let SetPresetResponse {} = client
+        .set_preset(SetPresetRequest { name, position })
+        .await?
+        .into_inner();
+    Ok(())
+}
+
+pub(crate) async fn delete(mut client: Client, name: String) -> Result<(), Status> {
+    let DeletePresetResponse {} = client
+        .delete_preset(DeletePresetRequest { name })
+        .await?
+        .into_inner();
+    Ok(())
+}
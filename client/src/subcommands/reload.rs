@@ -0,0 +1,11 @@
+use crate::Client;
+use desklink_common::rpc::{ReloadConfigRequest, ReloadConfigResponse};
+use tonic::Status;
+
+pub(crate) async fn run(mut client: Client) -> Result<(), Status> {
+    let ReloadConfigResponse {} = client
+        .reload_config(ReloadConfigRequest {})
+        .await?
+        .into_inner();
+    Ok(())
+}
@@ -0,0 +1,77 @@
+use crate::Client;
+use chrono::{Local, TimeZone};
+use desklink_common::rpc::{
+    DeleteScheduleEntryRequest, DeleteScheduleEntryResponse, ListScheduleRequest,
+    ListScheduleResponse, PauseScheduleEntryRequest, PauseScheduleEntryResponse, ScheduleEntry,
+    SetScheduleEntryRequest, SetScheduleEntryResponse,
+};
+use tonic::Status;
+
+pub(crate) async fn list(mut client: Client) -> Result<(), Status> {
+    let ListScheduleResponse { entries } = client
+        .list_schedule(ListScheduleRequest {})
+        .await?
+        .into_inner();
+    for entry in entries {
+        let next_run = if entry.paused {
+            "paused".to_owned()
+        } else {
+            format!(
+                "next {}",
+                Local
+                    .timestamp_millis_opt(entry.next_run_ms)
+                    .unwrap()
+                    .format("%a %Y-%m-%d %H:%M")
+            )
+        };
+        println!(
+            "{}: {} to {}, {}{}",
+            entry.name,
+            entry.when,
+            entry.targets.join(" / "),
+            next_run,
+            if entry.from_config {
+                " (server config)"
+            } else {
+                ""
+            }
+        );
+    }
+    Ok(())
+}
+
+pub(crate) async fn set(
+    mut client: Client,
+    name: String,
+    when: String,
+    targets: Vec<String>,
+) -> Result<(), Status> {
+    let SetScheduleEntryResponse {} = client
+        .set_schedule_entry(SetScheduleEntryRequest {
+            entry: Some(ScheduleEntry {
+                name,
+                when,
+                targets,
+                ..Default::default()
+            }),
+        })
+        .await?
+        .into_inner();
+    Ok(())
+}
+
+pub(crate) async fn delete(mut client: Client, name: String) -> Result<(), Status> {
+    let DeleteScheduleEntryResponse {} = client
+        .delete_schedule_entry(DeleteScheduleEntryRequest { name })
+        .await?
+        .into_inner();
+    Ok(())
+}
+
+pub(crate) async fn pause(mut client: Client, name: String, paused: bool) -> Result<(), Status> {
+    let PauseScheduleEntryResponse {} = client
+        .pause_schedule_entry(PauseScheduleEntryRequest { name, paused })
+        .await?
+        .into_inner();
+    Ok(())
+}
@@ -0,0 +1,98 @@
+use crate::{config::StatsPeriod, Client, Position};
+use chrono::{Duration, Local, NaiveDate};
+use desklink_common::{
+    day_start_ms,
+    rpc::{GetUsageStatsRequest, GetUsageStatsResponse},
+};
+use tonic::Status;
+
+pub(crate) async fn run(mut client: Client, period: StatsPeriod) -> Result<(), Status> {
+    let (days, days_per_row) = match period {
+        StatsPeriod::Day => (1, 1),
+        StatsPeriod::Week => (7, 1),
+        StatsPeriod::Month => (28, 7),
+    };
+    let today = Local::now().date_naive();
+    let first = today - Duration::days(days - 1);
+    let end = today + Duration::days(1);
+
+    let mut rows = Vec::new();
+    let mut start = first;
+    while start < end {
+        let next = start + Duration::days(days_per_row);
+        let label = if days_per_row == 1 {
+            start.format("%a %Y-%m-%d").to_string()
+        } else {
+            format!(
+                "{} to {}",
+                start.format("%m-%d"),
+                (next - Duration::days(1)).format("%m-%d")
+            )
+        };
+        rows.push((label, usage_stats(&mut client, start, next).await?));
+        start = next;
+    }
+    // sits across row boundaries are split between rows, but whole in the total
+    if rows.len() > 1 {
+        rows.push((
+            "Total".to_owned(),
+            usage_stats(&mut client, first, end).await?,
+        ));
+    }
+
+    let thresholds = &rows[0].1;
+    if thresholds.sitting_threshold == thresholds.standing_threshold {
+        println!(
+            "Standing from {}",
+            thresholds.standing_threshold.cm().trim()
+        );
+    } else {
+        println!(
+            "Sitting below {}, standing from {}",
+            thresholds.sitting_threshold.cm().trim(),
+            thresholds.standing_threshold.cm().trim()
+        );
+    }
+    println!(
+        "{:<16}{:>9}{:>10}{:>10}{:>13}{:>13}{:>9}",
+        "", "Sitting", "Standing", "Standing%", "Transitions", "Longest sit", "No data"
+    );
+    for (label, response) in rows {
+        let stats = response.stats.unwrap_or_default();
+        let known = stats.sitting_ms + stats.standing_ms;
+        let standing_share = (stats.standing_ms * 100)
+            .checked_div(known)
+            .map_or_else(|| "-".to_owned(), |percent| format!("{}%", percent));
+        println!(
+            "{:<16}{:>9}{:>10}{:>10}{:>13}{:>13}{:>9}",
+            label,
+            hours(stats.sitting_ms),
+            hours(stats.standing_ms),
+            standing_share,
+            stats.transitions,
+            hours(stats.longest_sit_ms),
+            hours(stats.unknown_ms),
+        );
+    }
+    Ok(())
+}
+
+/// Stats from the start of the local day `start` until the start of `end`
+async fn usage_stats(
+    client: &mut Client,
+    start: NaiveDate,
+    end: NaiveDate,
+) -> Result<GetUsageStatsResponse, Status> {
+    Ok(client
+        .get_usage_stats(GetUsageStatsRequest {
+            start_ms: day_start_ms(start),
+            end_ms: day_start_ms(end),
+        })
+        .await?
+        .into_inner())
+}
+
+pub(crate) fn hours(ms: u64) -> String {
+    let minutes = ms / 60_000;
+    format!("{}h{:02}m", minutes / 60, minutes % 60)
+}
@@ -1,6 +1,10 @@
+use super::{cycle, lock, stats::hours};
 use crate::{Client, Position, Velocity};
-use desklink_common::rpc::{GetStateRequest, GetStateResponse};
-use tonic::Status;
+use desklink_common::rpc::{
+    GetCycleRequest, GetGoalProgressRequest, GetGoalProgressResponse, GetLockRequest,
+    GetStateRequest, GetStateResponse,
+};
+use tonic::{Code, Status};
 
 pub(crate) async fn run(mut client: Client) -> Result<(), Status> {
     let GetStateResponse { position, velocity } =
@@ -10,5 +14,46 @@ pub(crate) async fn run(mut client: Client) -> Result<(), Status> {
         position.cm(),
         velocity.cm_per_s()
     );
+    // servers without a goal, or predating it, report no progress
+    match client.get_goal_progress(GetGoalProgressRequest {}).await {
+        Ok(response) => {
+            let GetGoalProgressResponse {
+                goal_ms,
+                standing_ms,
+                ..
+            } = response.into_inner();
+            println!(
+                "Standing goal: {} of {} ({}%)",
+                hours(standing_ms),
+                hours(goal_ms),
+                (standing_ms * 100).checked_div(goal_ms).unwrap_or(100)
+            );
+        }
+        Err(status)
+            if matches!(
+                status.code(),
+                Code::FailedPrecondition | Code::Unimplemented
+            ) => {}
+        Err(status) => return Err(status),
+    }
+    match client.get_cycle(GetCycleRequest {}).await {
+        Ok(response) => {
+            let response = response.into_inner();
+            if response.active {
+                println!("Cycle: {}", cycle::describe(&response));
+            }
+        }
+        Err(status) if status.code() == Code::Unimplemented => {}
+        Err(status) => return Err(status),
+    }
+    match client.get_lock(GetLockRequest {}).await {
+        Ok(response) => {
+            for active in response.into_inner().locks {
+                println!("Locked: {}", lock::describe(&active));
+            }
+        }
+        Err(status) if status.code() == Code::Unimplemented => {}
+        Err(status) => return Err(status),
+    }
     Ok(())
 }
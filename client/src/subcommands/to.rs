@@ -1,13 +1,28 @@
-use crate::{Client, Position, Velocity};
-use desklink_common::rpc::{StartMoveRequest, StartMoveResponse, SubscribeStateRequest};
-use tonic::Status;
+use crate::{config::Target, Client, Position, Velocity};
+use desklink_common::rpc::{
+    error_detail, ErrorDetail, ListPresetsRequest, StartMoveRequest, StartMoveResponse,
+    SubscribeStateRequest,
+};
+use tonic::{Code, Status};
 use tracing::info;
 
-pub(crate) async fn run(mut client: Client, target: f32, wait: bool) -> Result<(), Status> {
+pub(crate) async fn run(
+    mut client: Client,
+    target: Target,
+    wait: bool,
+    bind: bool,
+    lease: Option<String>,
+    override_lock: bool,
+) -> Result<(), Status> {
+    let (target, preset) = match target {
+        Target::Position(position) => (position, String::new()),
+        Target::Preset(name) => (resolve_preset(&mut client, &name).await?, name),
+    };
+
     let states = if wait {
         Some(
             client
-                .subscribe_state(SubscribeStateRequest {})
+                .subscribe_state(SubscribeStateRequest::default())
                 .await?
                 .into_inner(),
         )
@@ -15,23 +30,57 @@ pub(crate) async fn run(mut client: Client, target: f32, wait: bool) -> Result<(
         None
     };
 
-    let StartMoveResponse {} = client
-        .start_move(StartMoveRequest { target })
-        .await?
-        .into_inner();
+    let start = async {
+        let StartMoveResponse {} = client
+            .start_move(StartMoveRequest {
+                target,
+                lease_id: lease.unwrap_or_default(),
+                stop_on_disconnect: bind,
+                preset,
+                override_lock,
+            })
+            .await?
+            .into_inner();
+        Ok::<_, Status>(())
+    };
 
-    if let Some(mut states) = states {
-        while let Some(state) = states.message().await? {
-            info!(
-                position = state.position.cm(),
-                velocity = state.velocity.cm_per_s(),
-                "Update",
-            );
-            if f32::abs(state.position - target) < 0.1 {
-                break;
+    let monitor = async {
+        if let Some(mut states) = states {
+            while let Some(state) = states.message().await? {
+                info!(
+                    position = state.position.cm(),
+                    velocity = state.velocity.cm_per_s(),
+                    "Update",
+                );
+                if f32::abs(state.position - target) < 0.1 {
+                    break;
+                }
             }
         }
+        Ok::<_, Status>(())
+    };
+
+    if bind {
+        // a bound move only replies once it finishes
+        tokio::try_join!(start, monitor)?;
+    } else {
+        start.await?;
+        monitor.await?;
     }
 
     Ok(())
 }
+
+/// Look up a server preset, so its position can be monitored
+async fn resolve_preset(client: &mut Client, name: &str) -> Result<f32, Status> {
+    let presets = client
+        .list_presets(ListPresetsRequest {})
+        .await?
+        .into_inner()
+        .presets;
+    match presets.into_iter().find(|preset| preset.name == name) {
+        Some(preset) => Ok(preset.position),
+        None => Err(ErrorDetail::new(error_detail::Code::NotFound)
+            .into_status(Code::NotFound, format!("Preset `{}` not found", name))),
+    }
+}
@@ -1,3 +1,4 @@
+use chrono::{Duration, Local, NaiveDate, TimeZone};
 use serde::{de::Deserializer, Deserialize};
 use std::str::FromStr;
 use tracing::Level;
@@ -8,6 +9,133 @@ pub mod rpc {
     tonic::include_proto!("desk_service");
 }
 
+pub mod error {
+    use super::rpc::{error_detail::Code, ErrorDetail};
+    use prost::{bytes::Bytes, Message};
+    use tonic::Status;
+
+    impl ErrorDetail {
+        pub fn new(code: Code) -> Self {
+            ErrorDetail {
+                code: code as i32,
+                ..Default::default()
+            }
+        }
+
+        /// Build an error status with this detail attached
+        pub fn into_status(self, code: tonic::Code, message: impl Into<String>) -> Status {
+            Status::with_details(code, message, Bytes::from(self.encode_to_vec()))
+        }
+
+        /// Extract the detail attached to an error status, if any
+        pub fn from_status(status: &Status) -> Option<Self> {
+            if status.details().is_empty() {
+                None
+            } else {
+                ErrorDetail::decode(status.details()).ok()
+            }
+        }
+    }
+}
+
+/// Trace export over OTLP, with the trace context propagated in the gRPC metadata
+pub mod telemetry {
+    use opentelemetry::{
+        global,
+        propagation::{Extractor, Injector},
+        sdk::{propagation::TraceContextPropagator, trace, Resource},
+        trace::TraceError,
+        Context, KeyValue,
+    };
+    use opentelemetry_otlp::WithExportConfig;
+    use tokio::task;
+    use tonic::{
+        codegen::http::HeaderMap,
+        metadata::{MetadataKey, MetadataMap, MetadataValue},
+    };
+    use tracing::Span;
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+    /// Default endpoint of a local collector
+    pub const DEFAULT_OTLP_ENDPOINT: &str = "http://localhost:4317";
+
+    /// Export spans to the OTLP/gRPC collector at `endpoint` in the background
+    pub fn tracer(endpoint: &str, service_name: &str) -> Result<trace::Tracer, TraceError> {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        opentelemetry_otlp::new_pipeline()
+            .tracing()
+            .with_exporter(
+                opentelemetry_otlp::new_exporter()
+                    .tonic()
+                    .with_endpoint(endpoint),
+            )
+            .with_trace_config(trace::config().with_resource(Resource::new([KeyValue::new(
+                "service.name",
+                service_name.to_owned(),
+            )])))
+            .install_batch(opentelemetry::runtime::Tokio)
+    }
+
+    /// Export the remaining spans, does nothing if export is disabled
+    pub async fn shutdown() {
+        // flushing blocks, while the exporter needs the runtime to make progress
+        task::spawn_blocking(global::shutdown_tracer_provider)
+            .await
+            .unwrap_or(());
+    }
+
+    /// Add the context of the current span to outgoing metadata
+    pub fn inject(metadata: &mut MetadataMap) {
+        let context = Span::current().context();
+        global::get_text_map_propagator(|propagator| {
+            propagator.inject_context(&context, &mut MetadataInjector(metadata))
+        });
+    }
+
+    /// Context of the remote parent span in incoming headers
+    pub fn extract(headers: &HeaderMap) -> Context {
+        global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)))
+    }
+
+    struct MetadataInjector<'a>(&'a mut MetadataMap);
+
+    impl Injector for MetadataInjector<'_> {
+        fn set(&mut self, key: &str, value: String) {
+            if let (Ok(key), Ok(value)) = (
+                MetadataKey::from_bytes(key.as_bytes()),
+                MetadataValue::try_from(value),
+            ) {
+                self.0.insert(key, value);
+            }
+        }
+    }
+
+    struct HeaderExtractor<'a>(&'a HeaderMap);
+
+    impl Extractor for HeaderExtractor<'_> {
+        fn get(&self, key: &str) -> Option<&str> {
+            self.0.get(key).and_then(|value| value.to_str().ok())
+        }
+
+        fn keys(&self) -> Vec<&str> {
+            self.0.keys().map(|key| key.as_str()).collect()
+        }
+    }
+}
+
+/// Unix time of local midnight, or of the first hour after it if midnight is skipped by DST
+pub fn day_start_ms(date: NaiveDate) -> i64 {
+    let midnight = date.and_hms_opt(0, 0, 0).expect("Invalid midnight");
+    (0..24)
+        .find_map(|hour| {
+            Local
+                .from_local_datetime(&(midnight + Duration::hours(hour)))
+                .earliest()
+        })
+        .expect("Day without valid local time")
+        .timestamp_millis()
+}
+
 pub fn deserialize_log_level<'de, D>(deserializer: D) -> Result<Option<Level>, D::Error>
 where
     D: Deserializer<'de>,
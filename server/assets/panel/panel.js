@@ -0,0 +1,153 @@
+"use strict";
+
+const RETRY_MS = 3000;
+
+// Heights the token may move the desk between, the up and down buttons are enabled once loaded
+let limits = null;
+
+const $ = (id) => document.getElementById(id);
+
+function headers() {
+  const token = localStorage.getItem("desklink-token");
+  const headers = { "Content-Type": "application/json" };
+  if (token) {
+    headers["Authorization"] = `Bearer ${token}`;
+  }
+  return headers;
+}
+
+function showError(message) {
+  $("error").textContent = message;
+  $("error").hidden = !message;
+}
+
+async function api(method, path, body, signal) {
+  const response = await fetch(path, {
+    method,
+    headers: headers(),
+    body: body === undefined ? undefined : JSON.stringify(body),
+    signal,
+  });
+  if (!response.ok) {
+    const error = await response.json().catch(() => ({ message: response.statusText }));
+    throw new Error(error.message);
+  }
+  showError("");
+  return response.status === 204 ? null : response.json();
+}
+
+function run(promise) {
+  promise.catch((e) => showError(e.message));
+}
+
+// EventSource cannot send the token, so the event stream is read with fetch
+async function streamState() {
+  const response = await fetch("api/state/stream", { headers: headers() });
+  if (!response.ok) {
+    const error = await response.json().catch(() => ({ message: response.statusText }));
+    throw new Error(error.message);
+  }
+  $("status").textContent = "Connected";
+  const reader = response.body.pipeThrough(new TextDecoderStream()).getReader();
+  let buffer = "";
+  for (;;) {
+    const { value, done } = await reader.read();
+    if (done) {
+      return;
+    }
+    buffer += value;
+    let end;
+    while ((end = buffer.indexOf("\n\n")) >= 0) {
+      handleEvent(buffer.slice(0, end));
+      buffer = buffer.slice(end + 2);
+    }
+  }
+}
+
+function handleEvent(text) {
+  let event = "message";
+  let data = "";
+  for (const line of text.split("\n")) {
+    if (line.startsWith("event:")) {
+      event = line.slice(6).trim();
+    } else if (line.startsWith("data:")) {
+      data += line.slice(5).trim();
+    }
+  }
+  if (event === "state") {
+    const state = JSON.parse(data);
+    $("position").textContent = state.position.toFixed(2);
+    $("status").textContent = state.velocity === 0 ? "Idle" : "Moving";
+  } else if (event === "error") {
+    showError(JSON.parse(data).message);
+  }
+}
+
+async function watchState() {
+  for (;;) {
+    try {
+      await streamState();
+    } catch (e) {
+      showError(e.message);
+    }
+    $("status").textContent = "Disconnected, retrying...";
+    await new Promise((resolve) => setTimeout(resolve, RETRY_MS));
+  }
+}
+
+async function loadLimits() {
+  limits = await api("GET", "api/limits");
+  $("up").disabled = false;
+  $("down").disabled = false;
+}
+
+async function loadPresets() {
+  const presets = await api("GET", "api/presets");
+  const container = $("presets");
+  container.replaceChildren();
+  for (const preset of presets) {
+    const button = document.createElement("button");
+    button.textContent = `${preset.name} (${preset.position.toFixed(1)})`;
+    button.addEventListener("click", () => run(api("POST", "api/move", { preset: preset.name })));
+    container.appendChild(button);
+  }
+}
+
+// Move while the button is held down.
+// The move request lasts until the desk stops, and aborting it on release stops the desk.
+function holdToMove(button, target) {
+  let move = null;
+  button.addEventListener("pointerdown", () => {
+    const controller = new AbortController();
+    move = controller;
+    const body = { target: target(), stop_on_disconnect: true };
+    run(
+      api("POST", "api/move", body, controller.signal).catch((e) => {
+        if (!controller.signal.aborted) {
+          throw e;
+        }
+      }),
+    );
+  });
+  for (const type of ["pointerup", "pointercancel", "pointerleave"]) {
+    button.addEventListener(type, () => {
+      if (move) {
+        move.abort();
+        move = null;
+      }
+    });
+  }
+}
+
+holdToMove($("up"), () => limits.max);
+holdToMove($("down"), () => limits.min);
+$("stop").addEventListener("click", () => run(api("POST", "api/stop")));
+$("token").value = localStorage.getItem("desklink-token") || "";
+$("save-token").addEventListener("click", () => {
+  localStorage.setItem("desklink-token", $("token").value);
+  location.reload();
+});
+
+run(loadLimits());
+run(loadPresets());
+watchState();
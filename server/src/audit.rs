@@ -0,0 +1,185 @@
+use crate::{
+    controllers::MoveReport,
+    events::MoveOutcome,
+    lock::LockScope,
+    utils::{Position, Velocity},
+};
+use serde::Serialize;
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+use thiserror::Error;
+use tokio::sync::watch;
+use tracing::warn;
+
+#[derive(Error, Debug)]
+pub enum AuditError {
+    #[error("IO error: `{path}`")]
+    IoError {
+        path: PathBuf,
+        #[source]
+        error: io::Error,
+    },
+}
+
+/// Command recorded in the audit log
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Action {
+    Move {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        target: Option<f32>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        preset: Option<String>,
+    },
+    Stop,
+    SetPreset {
+        name: String,
+        position: f32,
+    },
+    DeletePreset {
+        name: String,
+    },
+    SetScheduleEntry {
+        name: String,
+        when: String,
+        targets: Vec<String>,
+    },
+    DeleteScheduleEntry {
+        name: String,
+    },
+    PauseScheduleEntry {
+        name: String,
+        paused: bool,
+    },
+    StartCycle {
+        sit_ms: u64,
+        stand_ms: u64,
+        sit_target: String,
+        stand_target: String,
+    },
+    StopCycle,
+    SetLock {
+        scope: LockScope,
+        reason: String,
+    },
+    ClearLock,
+}
+
+#[derive(Serialize)]
+struct Entry<'a> {
+    timestamp: String,
+    client: &'a str,
+    #[serde(flatten)]
+    action: &'a Action,
+    /// Links the outcome of a move to its acceptance
+    #[serde(skip_serializing_if = "Option::is_none")]
+    move_id: Option<u64>,
+    start_position: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    end_position: Option<f32>,
+    outcome: &'a str,
+}
+
+/**
+ * Append-only log of commands, one JSON object per line.
+ * Commands are recorded by the RPC handlers, accepted moves a second time when they finish.
+ */
+pub struct AuditLog {
+    file: Mutex<File>,
+    path: PathBuf,
+    state: watch::Receiver<(Position, Velocity)>,
+}
+
+impl AuditLog {
+    /// `state` provides the position of the desk when a command is recorded
+    pub fn open(
+        path: PathBuf,
+        state: watch::Receiver<(Position, Velocity)>,
+    ) -> Result<Self, AuditError> {
+        let io_error = |error| AuditError::IoError {
+            path: path.clone(),
+            error,
+        };
+        if let Some(directory) = path.parent() {
+            std::fs::create_dir_all(directory).map_err(io_error)?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(io_error)?;
+        Ok(AuditLog {
+            file: Mutex::new(file),
+            path,
+            state,
+        })
+    }
+
+    pub fn record(&self, client: &str, action: Action, outcome: &str) {
+        let start_position = self.state.borrow().0;
+        self.write(&Entry {
+            timestamp: timestamp(),
+            client,
+            action: &action,
+            move_id: None,
+            start_position: start_position.to_cm(),
+            end_position: None,
+            outcome,
+        });
+    }
+
+    /// Record an accepted move, then its outcome once it finishes
+    pub fn record_move(self: &Arc<Self>, client: String, action: Action, report: MoveReport) {
+        let start_position = self.state.borrow().0.to_cm();
+        self.write(&Entry {
+            timestamp: timestamp(),
+            client: &client,
+            action: &action,
+            move_id: Some(report.id),
+            start_position,
+            end_position: None,
+            outcome: "accepted",
+        });
+        let audit = self.clone();
+        tokio::spawn(async move {
+            let outcome = match report.outcome.await {
+                Ok(MoveOutcome::Started) => "started".to_owned(),
+                Ok(MoveOutcome::Reached) => "reached".to_owned(),
+                Ok(MoveOutcome::Aborted) => "aborted".to_owned(),
+                Ok(MoveOutcome::Stalled) => "stalled".to_owned(),
+                Ok(MoveOutcome::Preempted { by }) => format!("preempted by {}", by),
+                // the controller stopped during the move
+                Err(_) => "interrupted".to_owned(),
+            };
+            let end_position = audit.state.borrow().0.to_cm();
+            audit.write(&Entry {
+                timestamp: timestamp(),
+                client: &client,
+                action: &action,
+                move_id: Some(report.id),
+                start_position,
+                end_position: Some(end_position),
+                outcome: &outcome,
+            });
+        });
+    }
+
+    /// Failing to record does not fail the command
+    fn write(&self, entry: &Entry) {
+        let mut line = serde_json::to_vec(entry).expect("Error serializing audit entry");
+        line.push(b'\n');
+        // a single write per entry, so lines are not interleaved
+        if let Err(e) = self.file.lock().unwrap().write_all(&line) {
+            warn!(path = %self.path.display(), "Error writing audit log: {}", e);
+        }
+    }
+}
+
+fn timestamp() -> String {
+    humantime::format_rfc3339_millis(SystemTime::now()).to_string()
+}
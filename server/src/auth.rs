@@ -0,0 +1,185 @@
+use crate::{config::TokenConfig, utils::Position};
+use desklink_common::rpc::{error_detail, ErrorDetail, Range};
+use serde::Deserialize;
+use std::sync::{Arc, RwLock};
+use thiserror::Error;
+use tonic::{service::Interceptor, Code, Request, Status};
+use tracing::{info, warn};
+
+#[derive(Error, Debug)]
+pub enum AuthError {
+    #[error("Missing bearer token")]
+    MissingToken,
+
+    #[error("Malformed authorization header")]
+    MalformedHeader,
+
+    #[error("Invalid token")]
+    InvalidToken,
+
+    #[error("{name} needs the {required:?} role")]
+    Forbidden { name: String, required: Role },
+
+    #[error("{name} may only move the desk between {min:.2} cm and {max:.2} cm")]
+    OutsideWindow { name: String, min: f32, max: f32 },
+}
+
+impl From<AuthError> for Status {
+    fn from(e: AuthError) -> Status {
+        match &e {
+            AuthError::MissingToken | AuthError::MalformedHeader | AuthError::InvalidToken => {
+                ErrorDetail::new(error_detail::Code::Unauthorized)
+                    .into_status(Code::Unauthenticated, format!("{}", e))
+            }
+            AuthError::Forbidden { .. } => ErrorDetail::new(error_detail::Code::Unauthorized)
+                .into_status(Code::PermissionDenied, format!("{}", e)),
+            AuthError::OutsideWindow { min, max, .. } => ErrorDetail {
+                allowed_range: Some(Range {
+                    min: *min,
+                    max: *max,
+                }),
+                ..ErrorDetail::new(error_detail::Code::OutOfRange)
+            }
+            .into_status(Code::PermissionDenied, format!("{}", e)),
+        }
+    }
+}
+
+/// Roles are ordered, each one includes the permissions of the previous ones
+#[derive(Deserialize, Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Read state and events
+    Observe,
+    /// Move and stop the desk
+    #[default]
+    Operate,
+    /// Manage presets, config and schedules
+    Admin,
+}
+
+/// Holder of an accepted token, stored in the request extensions
+#[derive(Clone, Debug)]
+pub struct Identity {
+    pub name: String,
+    pub role: Role,
+    pub min_height: Option<f32>,
+    pub max_height: Option<f32>,
+}
+
+impl Identity {
+    pub fn require(&self, role: Role) -> Result<(), AuthError> {
+        if self.role >= role {
+            Ok(())
+        } else {
+            Err(AuthError::Forbidden {
+                name: self.name.clone(),
+                required: role,
+            })
+        }
+    }
+
+    /// Heights in cm this token may move the desk between
+    pub fn window(&self) -> (f32, f32) {
+        (
+            self.min_height.unwrap_or(Position::MIN_CM),
+            self.max_height.unwrap_or(Position::MAX_CM),
+        )
+    }
+
+    /// Check that a move target lies within the height window of this token
+    pub fn check_target(&self, target: Position) -> Result<(), AuthError> {
+        self.require(Role::Operate)?;
+        let (min, max) = self.window();
+        if (min..=max).contains(&target.to_cm()) {
+            Ok(())
+        } else {
+            Err(AuthError::OutsideWindow {
+                name: self.name.clone(),
+                min,
+                max,
+            })
+        }
+    }
+}
+
+/**
+ * Checks bearer tokens against the configured ones.
+ * Cloning shares the token list, so `set_tokens` applies to all clones.
+ */
+#[derive(Clone)]
+pub struct Authenticator {
+    tokens: Arc<RwLock<Vec<TokenConfig>>>,
+}
+
+impl Authenticator {
+    pub fn new(tokens: Vec<TokenConfig>) -> Self {
+        if tokens.is_empty() {
+            warn!("No tokens configured, authentication is disabled");
+        }
+        Authenticator {
+            tokens: Arc::new(RwLock::new(tokens)),
+        }
+    }
+
+    pub fn set_tokens(&self, tokens: Vec<TokenConfig>) {
+        if tokens.is_empty() {
+            warn!("No tokens configured, authentication is disabled");
+        }
+        *self.tokens.write().unwrap() = tokens;
+    }
+
+    /// Returns `None` if authentication is disabled
+    pub fn authenticate(&self, authorization: Option<&str>) -> Result<Option<Identity>, AuthError> {
+        let tokens = self.tokens.read().unwrap();
+        if tokens.is_empty() {
+            return Ok(None);
+        }
+        let token = authorization
+            .ok_or(AuthError::MissingToken)?
+            .strip_prefix("Bearer ")
+            .ok_or(AuthError::MalformedHeader)?
+            .trim();
+        // check every token so the timing does not reveal which one almost matched
+        let mut identity = None;
+        for config in tokens.iter() {
+            if constant_time_eq(config.token.as_bytes(), token.as_bytes()) {
+                identity = Some(Identity {
+                    name: config.name.clone(),
+                    role: config.role,
+                    min_height: config.min_height,
+                    max_height: config.max_height,
+                });
+            }
+        }
+        identity.map(Some).ok_or(AuthError::InvalidToken)
+    }
+}
+
+impl Interceptor for Authenticator {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        // keep the token out of request logs
+        if let Some(value) = request.metadata_mut().get_mut("authorization") {
+            value.set_sensitive(true);
+        }
+        let authorization = match request.metadata().get("authorization") {
+            Some(value) => Some(value.to_str().map_err(|_| AuthError::MalformedHeader)?),
+            None => None,
+        };
+        match self.authenticate(authorization) {
+            Ok(Some(identity)) => {
+                request.extensions_mut().insert(identity);
+                Ok(request)
+            }
+            Ok(None) => Ok(request),
+            Err(e) => {
+                info!(peer = ?request.remote_addr(), "Rejected request: {}", e);
+                Err(e.into())
+            }
+        }
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -0,0 +1,878 @@
+use crate::{
+    config::CalendarConfig,
+    history::now_ms,
+    lock::{CalendarLock, LockScope, Locks},
+    schedule::{ScheduleError, Target},
+    service::{Automatic, DeskService, Peer},
+};
+use chrono::{
+    Datelike, Duration as ChronoDuration, Local, NaiveDate, NaiveDateTime, Offset, TimeZone, Utc,
+    Weekday,
+};
+use chrono_tz::Tz;
+use desklink_common::rpc::{
+    desk_service_server::DeskService as DeskServiceTrait, StartMoveRequest,
+};
+use ical::{parser::ParserError, property::Property, IcalParser};
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{self, BufReader},
+    ops::Range,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+use thiserror::Error;
+use tokio::{select, sync::Notify, task, time};
+use tonic::Request;
+use tracing::{info, warn};
+
+/// Interval between checks of the calendar file and of events starting
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+/// Events starting longer ago, like while the computer was asleep, do not move the desk
+const MAX_MOVE_DELAY_MS: i64 = 5 * 60 * 1000;
+/// Occurrences are expanded this far around the current time, the locks are refreshed long before
+const LOCK_HORIZON_MS: i64 = 24 * 60 * 60 * 1000;
+/// Bound on the occurrences expanded from a recurrence rule
+const MAX_OCCURRENCES: usize = 100_000;
+
+#[derive(Error, Debug)]
+pub enum CalendarError {
+    #[error("Calendar rule has neither a target nor a lock")]
+    NoAction,
+
+    #[error("Invalid calendar rule target")]
+    InvalidTarget(#[source] ScheduleError),
+
+    #[error("IO error: `{path}`")]
+    IoError {
+        path: PathBuf,
+        #[source]
+        error: io::Error,
+    },
+
+    #[error("iCalendar parsing error")]
+    ParseError(#[from] ParserError),
+}
+
+/// What to do for events matching a rule
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rule {
+    /// Lowercase text to find in the event categories or summary, any event if unset
+    pub pattern: Option<String>,
+    /// Target to move to at the start of the events
+    pub to: Option<Target>,
+    /// Moves refused during the events
+    pub lock: Option<LockScope>,
+}
+
+/// Calendar rule as written in the config file
+#[derive(Deserialize)]
+pub struct RuleSpec {
+    #[serde(rename = "match")]
+    pub pattern: Option<String>,
+    pub to: Option<String>,
+    pub lock: Option<LockScope>,
+}
+
+impl TryFrom<RuleSpec> for Rule {
+    type Error = CalendarError;
+
+    fn try_from(spec: RuleSpec) -> Result<Self, Self::Error> {
+        if spec.to.is_none() && spec.lock.is_none() {
+            return Err(CalendarError::NoAction);
+        }
+        Ok(Rule {
+            pattern: spec.pattern.map(|pattern| pattern.to_lowercase()),
+            to: spec
+                .to
+                .map(|to| to.parse())
+                .transpose()
+                .map_err(CalendarError::InvalidTarget)?,
+            lock: spec.lock,
+        })
+    }
+}
+
+impl Rule {
+    fn matches(&self, event: &Event) -> bool {
+        match &self.pattern {
+            None => true,
+            Some(pattern) => {
+                event.summary.to_lowercase().contains(pattern.as_str())
+                    || event.categories.iter().any(|category| category == pattern)
+            }
+        }
+    }
+}
+
+/// Time zone of an event time
+#[derive(Copy, Clone, Debug)]
+enum Zone {
+    Utc,
+    /// Floating times and unknown time zones are taken as local
+    Local,
+    Named(Tz),
+}
+
+impl Zone {
+    fn timestamp_ms(self, time: NaiveDateTime) -> Option<i64> {
+        match self {
+            Zone::Utc => Some(Utc.from_utc_datetime(&time).timestamp_millis()),
+            Zone::Local => local_timestamp_ms(&Local, time),
+            Zone::Named(tz) => local_timestamp_ms(&tz, time),
+        }
+    }
+}
+
+/// Repeated local times are taken the first time, skipped ones with the offset before the gap, as in RFC 5545
+fn local_timestamp_ms<Z: TimeZone>(zone: &Z, time: NaiveDateTime) -> Option<i64> {
+    if let Some(time) = zone.from_local_datetime(&time).earliest() {
+        return Some(time.timestamp_millis());
+    }
+    let before = zone
+        .from_local_datetime(&(time - ChronoDuration::days(1)))
+        .earliest()?;
+    let offset = ChronoDuration::seconds(before.offset().fix().local_minus_utc().into());
+    Some(Utc.from_utc_datetime(&(time - offset)).timestamp_millis())
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Frequency {
+    Daily,
+    Weekly,
+}
+
+/// The supported subset of recurrence rules
+#[derive(Clone, Debug)]
+struct Recurrence {
+    frequency: Frequency,
+    interval: u32,
+    count: Option<usize>,
+    /// Unix time in milliseconds of the last possible start
+    until_ms: Option<i64>,
+    /// Days of weekly rules, the day of the first start if empty
+    days: Vec<Weekday>,
+}
+
+/// Timed event from the calendar file, all-day events are ignored
+#[derive(Clone, Debug)]
+struct Event {
+    summary: String,
+    /// Lowercase
+    categories: Vec<String>,
+    start: NaiveDateTime,
+    zone: Zone,
+    duration_ms: i64,
+    recurrence: Option<Recurrence>,
+    /// Unix times in milliseconds of excluded or replaced occurrences
+    excluded: HashSet<i64>,
+}
+
+impl Event {
+    /// Occurrences overlapping the range, as ranges of Unix times in milliseconds
+    fn occurrences(&self, range: &Range<i64>) -> Vec<Range<i64>> {
+        let mut occurrences = Vec::new();
+        let mut push = |start: NaiveDateTime| -> bool {
+            let start_ms = match self.zone.timestamp_ms(start) {
+                Some(start_ms) => start_ms,
+                // out of range
+                None => return false,
+            };
+            if start_ms >= range.end {
+                return false;
+            }
+            // durations are only bounded by the parsing
+            let end_ms = start_ms.saturating_add(self.duration_ms);
+            if end_ms > range.start && !self.excluded.contains(&start_ms) {
+                occurrences.push(start_ms..end_ms);
+            }
+            true
+        };
+        let recurrence = match &self.recurrence {
+            Some(recurrence) => recurrence,
+            None => {
+                push(self.start);
+                return occurrences;
+            }
+        };
+        let step = match recurrence.frequency {
+            Frequency::Daily => ChronoDuration::days(recurrence.interval.into()),
+            Frequency::Weekly => ChronoDuration::weeks(recurrence.interval.into()),
+        };
+        let days = if recurrence.days.is_empty() {
+            vec![self.start.weekday()]
+        } else {
+            recurrence.days.clone()
+        };
+        // weeks start on Monday, occurrences before the first start do not count
+        let mut period = self.start.date()
+            - ChronoDuration::days(match recurrence.frequency {
+                Frequency::Daily => 0,
+                Frequency::Weekly => self.start.weekday().num_days_from_monday().into(),
+            });
+        let max = recurrence
+            .count
+            .unwrap_or(MAX_OCCURRENCES)
+            .min(MAX_OCCURRENCES);
+        let mut count = 0;
+        while count < max {
+            let mut starts: Vec<NaiveDateTime> = match recurrence.frequency {
+                Frequency::Daily => vec![period.and_time(self.start.time())],
+                Frequency::Weekly => days
+                    .iter()
+                    .map(|day| {
+                        (period + ChronoDuration::days(day.num_days_from_monday().into()))
+                            .and_time(self.start.time())
+                    })
+                    .filter(|start| *start >= self.start)
+                    .collect(),
+            };
+            starts.sort();
+            for start in starts {
+                if count >= max {
+                    break;
+                }
+                let past_until = recurrence.until_ms.is_some_and(|until_ms| {
+                    self.zone
+                        .timestamp_ms(start)
+                        .is_some_and(|start_ms| start_ms > until_ms)
+                });
+                if past_until || !push(start) {
+                    return occurrences;
+                }
+                count += 1;
+            }
+            period += step;
+        }
+        occurrences
+    }
+}
+
+/// Value and parameters of the first property with the name
+fn property<'a>(properties: &'a [Property], name: &str) -> Option<&'a Property> {
+    properties.iter().find(|property| property.name == name)
+}
+
+fn param<'a>(property: &'a Property, name: &str) -> Option<&'a str> {
+    property
+        .params
+        .as_ref()?
+        .iter()
+        .find(|(param, _)| param.eq_ignore_ascii_case(name))
+        .and_then(|(_, values)| values.first())
+        .map(String::as_str)
+}
+
+/// Date-time of a property, none for dates
+fn date_time(value: &str, tzid: Option<&str>) -> Option<(NaiveDateTime, Zone)> {
+    let (value, utc) = match value.strip_suffix('Z') {
+        Some(value) => (value, true),
+        None => (value, false),
+    };
+    let time = NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%S").ok()?;
+    let zone = if utc {
+        Zone::Utc
+    } else {
+        match tzid.map(|tzid| tzid.trim_matches('"').parse::<Tz>()) {
+            Some(Ok(tz)) => Zone::Named(tz),
+            _ => Zone::Local,
+        }
+    };
+    Some((time, zone))
+}
+
+fn property_time(property: &Property) -> Option<(NaiveDateTime, Zone)> {
+    date_time(property.value.as_deref()?, param(property, "TZID"))
+}
+
+/// ISO 8601 durations as used by iCalendar, like `PT1H30M` or `P1D`, none if invalid or out of range
+fn parse_duration(value: &str) -> Option<i64> {
+    let (sign, value) = match value.strip_prefix('-') {
+        Some(value) => (-1, value),
+        None => (1, value.strip_prefix('+').unwrap_or(value)),
+    };
+    let mut ms: i64 = 0;
+    let mut number = String::new();
+    for c in value.strip_prefix('P')?.chars() {
+        match c {
+            'T' => {}
+            '0'..='9' => number.push(c),
+            unit => {
+                let n: i64 = number.parse().ok()?;
+                number.clear();
+                let unit_ms = match unit {
+                    'W' => 7 * 24 * 60 * 60 * 1000,
+                    'D' => 24 * 60 * 60 * 1000,
+                    'H' => 60 * 60 * 1000,
+                    'M' => 60 * 1000,
+                    'S' => 1000,
+                    _ => return None,
+                };
+                ms = n.checked_mul(unit_ms)?.checked_add(ms)?;
+            }
+        }
+    }
+    Some(sign * ms)
+}
+
+fn parse_recurrence(value: &str, zone: Zone) -> Option<Recurrence> {
+    let parts: HashMap<&str, &str> = value
+        .split(';')
+        .filter_map(|part| part.split_once('='))
+        .collect();
+    let frequency = match *parts.get("FREQ")? {
+        "DAILY" => Frequency::Daily,
+        "WEEKLY" => Frequency::Weekly,
+        _ => return None,
+    };
+    // rules narrowing occurrences in other ways are not supported
+    if parts.keys().any(|key| {
+        !matches!(
+            *key,
+            "FREQ" | "INTERVAL" | "COUNT" | "UNTIL" | "BYDAY" | "WKST"
+        )
+    }) {
+        return None;
+    }
+    let until_ms = match parts.get("UNTIL") {
+        None => None,
+        Some(until) => Some(match date_time(until, None) {
+            Some((until, Zone::Utc)) => Zone::Utc.timestamp_ms(until)?,
+            // floating, in the time zone of the start
+            Some((until, _)) => zone.timestamp_ms(until)?,
+            None => zone.timestamp_ms(
+                NaiveDate::parse_from_str(until, "%Y%m%d")
+                    .ok()?
+                    .and_hms_opt(23, 59, 59)?,
+            )?,
+        }),
+    };
+    let days = match parts.get("BYDAY") {
+        None => Vec::new(),
+        Some(days) => days
+            .split(',')
+            .map(|day| match day {
+                "MO" => Some(Weekday::Mon),
+                "TU" => Some(Weekday::Tue),
+                "WE" => Some(Weekday::Wed),
+                "TH" => Some(Weekday::Thu),
+                "FR" => Some(Weekday::Fri),
+                "SA" => Some(Weekday::Sat),
+                "SU" => Some(Weekday::Sun),
+                _ => None,
+            })
+            .collect::<Option<_>>()?,
+    };
+    if frequency == Frequency::Daily && !days.is_empty() {
+        return None;
+    }
+    Some(Recurrence {
+        frequency,
+        interval: match parts.get("INTERVAL") {
+            Some(interval) => interval.parse().ok().filter(|&interval| interval > 0)?,
+            None => 1,
+        },
+        count: match parts.get("COUNT") {
+            Some(count) => Some(count.parse().ok()?),
+            None => None,
+        },
+        until_ms,
+        days,
+    })
+}
+
+/// Timed events of a calendar file, with replaced occurrences of recurring events excluded
+fn read_events(path: &PathBuf) -> Result<Vec<Event>, CalendarError> {
+    let file = File::open(path).map_err(|error| CalendarError::IoError {
+        path: path.clone(),
+        error,
+    })?;
+    let mut events = Vec::new();
+    // occurrences replaced by another event, by UID
+    let mut replaced: HashMap<String, HashSet<i64>> = HashMap::new();
+    for calendar in IcalParser::new(BufReader::new(file)) {
+        for event in calendar?.events {
+            let properties = &event.properties;
+            let value = |name| property(properties, name).and_then(|p| p.value.clone());
+            let summary = value("SUMMARY").unwrap_or_default();
+            if value("STATUS").as_deref() == Some("CANCELLED") {
+                continue;
+            }
+            let (start, zone) = match property(properties, "DTSTART").and_then(property_time) {
+                Some(start) => start,
+                // all-day events or invalid times
+                None => continue,
+            };
+            let start_ms = match zone.timestamp_ms(start) {
+                Some(start_ms) => start_ms,
+                None => continue,
+            };
+            let uid = value("UID").unwrap_or_default();
+            if let Some(recurrence_id) = property(properties, "RECURRENCE-ID")
+                .and_then(property_time)
+                .and_then(|(time, zone)| zone.timestamp_ms(time))
+            {
+                replaced
+                    .entry(uid.clone())
+                    .or_default()
+                    .insert(recurrence_id);
+            }
+            let duration_ms = match property(properties, "DTEND").and_then(property_time) {
+                Some((end, end_zone)) => end_zone
+                    .timestamp_ms(end)
+                    .map_or(0, |end_ms| end_ms - start_ms),
+                None => value("DURATION")
+                    .and_then(|duration| parse_duration(&duration))
+                    .unwrap_or(0),
+            };
+            let recurrence = match value("RRULE") {
+                Some(rule) => match parse_recurrence(&rule, zone) {
+                    Some(recurrence) => Some(recurrence),
+                    None => {
+                        warn!(%summary, %rule, "Unsupported recurrence, using the first occurrence only");
+                        None
+                    }
+                },
+                None => None,
+            };
+            let excluded = properties
+                .iter()
+                .filter(|property| property.name == "EXDATE")
+                .flat_map(|property| {
+                    let tzid = param(property, "TZID");
+                    property
+                        .value
+                        .iter()
+                        .flat_map(|value| value.split(','))
+                        .filter_map(move |value| date_time(value, tzid))
+                        .collect::<Vec<_>>()
+                })
+                .filter_map(|(time, zone)| zone.timestamp_ms(time))
+                .collect();
+            let categories = properties
+                .iter()
+                .filter(|property| property.name == "CATEGORIES")
+                .flat_map(|property| property.value.iter().flat_map(|value| value.split(',')))
+                .map(|category| category.trim().to_lowercase())
+                .collect();
+            events.push((
+                uid,
+                Event {
+                    summary,
+                    categories,
+                    start,
+                    zone,
+                    duration_ms: duration_ms.max(0),
+                    recurrence,
+                    excluded,
+                },
+            ));
+        }
+    }
+    Ok(events
+        .into_iter()
+        .map(|(uid, mut event)| {
+            if event.recurrence.is_some() {
+                if let Some(replaced) = replaced.get(&uid) {
+                    event.excluded.extend(replaced);
+                }
+            }
+            event
+        })
+        .collect())
+}
+
+/// Events read from the calendar file, and the file version they come from
+struct Loaded {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    events: Vec<Event>,
+}
+
+/**
+ * Rules applied to the events of a local iCalendar file, re-read when it changes.
+ * Rules move the desk at the start of matching events, through the RPC handlers,
+ * or lock the desk during them. Calendar locks do not refuse the calendar's own moves.
+ */
+pub struct Calendar {
+    config: Mutex<Option<CalendarConfig>>,
+    locks: Arc<Locks>,
+    changed: Notify,
+}
+
+impl Calendar {
+    pub fn new(config: Option<CalendarConfig>, locks: Arc<Locks>) -> Self {
+        Calendar {
+            config: Mutex::new(config),
+            locks,
+            changed: Notify::new(),
+        }
+    }
+
+    /// Replace the calendar from the config file
+    pub fn set_config(&self, config: Option<CalendarConfig>) {
+        *self.config.lock().unwrap() = config;
+        self.changed.notify_one();
+    }
+
+    /// Apply the rules as events start, forever
+    pub async fn run(self: Arc<Self>, service: Arc<DeskService>) {
+        let mut loaded: Option<Loaded> = None;
+        let mut last_check = now_ms();
+        loop {
+            let config = self.config.lock().unwrap().clone();
+            let now = now_ms();
+            match config {
+                None => {
+                    loaded = None;
+                    self.locks.set_calendar(Vec::new());
+                }
+                Some(config) => {
+                    // file reads, parsing and recurrence expansion block
+                    let previous = loaded.take();
+                    let (reloaded, calendar_locks, moves) = task::spawn_blocking(move || {
+                        let mut loaded = previous;
+                        reload(&mut loaded, &config.path);
+                        let (calendar_locks, moves) =
+                            check(&config, loaded.as_ref(), last_check, now);
+                        (loaded, calendar_locks, moves)
+                    })
+                    .await
+                    .expect("Calendar check panicked");
+                    loaded = reloaded;
+                    self.locks.set_calendar(calendar_locks);
+                    for (rule, event, target) in moves {
+                        run_move(&service, &rule, &event, target).await;
+                    }
+                }
+            }
+            last_check = now;
+
+            select! {
+                _ = time::sleep(CHECK_INTERVAL) => {}
+                _ = self.changed.notified() => {}
+            }
+        }
+    }
+}
+
+/// Locks of the events around the current time, and moves for the events started since the last check
+fn check(
+    config: &CalendarConfig,
+    loaded: Option<&Loaded>,
+    last_check: i64,
+    now: i64,
+) -> (Vec<CalendarLock>, Vec<(String, String, Target)>) {
+    let events = loaded.map_or(&[][..], |loaded| &loaded.events);
+    let horizon = now - LOCK_HORIZON_MS..now + LOCK_HORIZON_MS;
+    let mut calendar_locks = Vec::new();
+    let mut moves = Vec::new();
+    for event in events {
+        let rules: Vec<_> = config
+            .rules
+            .iter()
+            .filter(|(_, rule)| rule.matches(event))
+            .collect();
+        if rules.is_empty() {
+            continue;
+        }
+        for occurrence in event.occurrences(&horizon) {
+            for (name, rule) in &rules {
+                if let Some(scope) = rule.lock {
+                    calendar_locks.push(CalendarLock {
+                        rule: (*name).clone(),
+                        event: event.summary.clone(),
+                        scope,
+                        range: occurrence.clone(),
+                    });
+                }
+                let starting = last_check < occurrence.start
+                    && occurrence.start <= now
+                    && now - occurrence.start < MAX_MOVE_DELAY_MS;
+                if let (Some(to), true) = (&rule.to, starting) {
+                    moves.push(((*name).clone(), event.summary.clone(), to.clone()));
+                }
+            }
+        }
+    }
+    (calendar_locks, moves)
+}
+
+/// Read the calendar file again if it changed, keeping the last events on errors
+fn reload(loaded: &mut Option<Loaded>, path: &PathBuf) {
+    let modified = std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok();
+    if let Some(loaded) = loaded {
+        if &loaded.path == path && loaded.modified == modified {
+            return;
+        }
+    }
+    match read_events(path) {
+        Ok(events) => {
+            info!(path = %path.display(), events = events.len(), "Calendar loaded");
+            *loaded = Some(Loaded {
+                path: path.clone(),
+                modified,
+                events,
+            });
+        }
+        Err(e) => {
+            warn!(path = %path.display(), "Error reading calendar: {}", e);
+            // retried when the file changes again
+            let events = loaded
+                .take()
+                .filter(|loaded| &loaded.path == path)
+                .map(|loaded| loaded.events)
+                .unwrap_or_default();
+            *loaded = Some(Loaded {
+                path: path.clone(),
+                modified,
+                events,
+            });
+        }
+    }
+}
+
+async fn run_move(service: &DeskService, rule: &str, event: &str, target: Target) {
+    info!(%rule, %event, %target, "Running calendar move");
+    let message = match target {
+        Target::Position(position) => StartMoveRequest {
+            target: position.to_cm(),
+            ..Default::default()
+        },
+        Target::Preset(preset) => StartMoveRequest {
+            preset,
+            ..Default::default()
+        },
+    };
+    let mut request = Request::new(message);
+    request
+        .extensions_mut()
+        .insert(Peer(format!("calendar:{}", rule)));
+    request.extensions_mut().insert(Automatic::Calendar);
+    if let Err(status) = service.start_move(request).await {
+        warn!(%rule, %event, "Calendar move failed: {}", status.message());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs};
+
+    const HOUR_MS: i64 = 60 * 60 * 1000;
+    const DAY_MS: i64 = 24 * HOUR_MS;
+
+    /// Events of a calendar with the given events, read through a temporary file
+    fn events(name: &str, vevents: &[&str]) -> Vec<Event> {
+        let path = env::temp_dir().join(format!(
+            "deskd-calendar-{}-{}.ics",
+            std::process::id(),
+            name
+        ));
+        let mut ics = "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nPRODID:test\r\n".to_owned();
+        for vevent in vevents {
+            ics.push_str("BEGIN:VEVENT\r\n");
+            for line in vevent.lines() {
+                ics.push_str(line.trim());
+                ics.push_str("\r\n");
+            }
+            ics.push_str("END:VEVENT\r\n");
+        }
+        ics.push_str("END:VCALENDAR\r\n");
+        fs::write(&path, ics).unwrap();
+        let events = read_events(&path);
+        fs::remove_file(&path).unwrap();
+        events.unwrap()
+    }
+
+    fn utc_ms(time: &str) -> i64 {
+        Zone::Utc
+            .timestamp_ms(NaiveDateTime::parse_from_str(time, "%Y%m%dT%H%M%S").unwrap())
+            .unwrap()
+    }
+
+    /// Starts of all occurrences of the events in 2024
+    fn starts(events: &[Event]) -> Vec<i64> {
+        let year = utc_ms("20240101T000000")..utc_ms("20250101T000000");
+        let mut starts: Vec<_> = events
+            .iter()
+            .flat_map(|event| event.occurrences(&year))
+            .map(|occurrence| occurrence.start)
+            .collect();
+        starts.sort();
+        starts
+    }
+
+    fn utc_starts(times: &[&str]) -> Vec<i64> {
+        times.iter().map(|time| utc_ms(time)).collect()
+    }
+
+    #[test]
+    fn count() {
+        let events = events(
+            "count",
+            &["UID:a
+               DTSTART:20240101T090000Z
+               DURATION:PT15M
+               RRULE:FREQ=DAILY;COUNT=3"],
+        );
+        assert_eq!(
+            starts(&events),
+            utc_starts(&["20240101T090000", "20240102T090000", "20240103T090000"])
+        );
+        let occurrence = &events[0].occurrences(&(0..i64::MAX))[0];
+        assert_eq!(occurrence.end - occurrence.start, 15 * 60 * 1000);
+    }
+
+    #[test]
+    fn until_is_inclusive() {
+        let events = events(
+            "until",
+            &["UID:a
+               DTSTART:20240101T090000Z
+               DTEND:20240101T100000Z
+               RRULE:FREQ=DAILY;UNTIL=20240103T090000Z"],
+        );
+        assert_eq!(
+            starts(&events),
+            utc_starts(&["20240101T090000", "20240102T090000", "20240103T090000"])
+        );
+    }
+
+    #[test]
+    fn interval_and_days() {
+        // starts on a Wednesday, the Monday before does not count
+        let events = events(
+            "interval",
+            &["UID:a
+               DTSTART:20240103T090000Z
+               DTEND:20240103T100000Z
+               RRULE:FREQ=WEEKLY;INTERVAL=2;BYDAY=WE,MO;COUNT=4"],
+        );
+        assert_eq!(
+            starts(&events),
+            utc_starts(&[
+                "20240103T090000",
+                "20240115T090000",
+                "20240117T090000",
+                "20240129T090000"
+            ])
+        );
+    }
+
+    #[test]
+    fn excluded_dates_count() {
+        let events = events(
+            "exdate",
+            &["UID:a
+               DTSTART:20240101T090000Z
+               DTEND:20240101T100000Z
+               RRULE:FREQ=DAILY;COUNT=3
+               EXDATE:20240102T090000Z"],
+        );
+        assert_eq!(
+            starts(&events),
+            utc_starts(&["20240101T090000", "20240103T090000"])
+        );
+    }
+
+    #[test]
+    fn recurrence_id_replaces_occurrence() {
+        let events = events(
+            "recurrence-id",
+            &[
+                "UID:a
+                 DTSTART:20240101T090000Z
+                 DTEND:20240101T100000Z
+                 RRULE:FREQ=DAILY;COUNT=3",
+                "UID:a
+                 RECURRENCE-ID:20240102T090000Z
+                 DTSTART:20240102T140000Z
+                 DTEND:20240102T150000Z",
+                "UID:b
+                 RECURRENCE-ID:20240103T090000Z
+                 DTSTART:20240103T160000Z
+                 DTEND:20240103T170000Z",
+            ],
+        );
+        // only occurrences of the same event are replaced
+        assert_eq!(
+            starts(&events),
+            utc_starts(&[
+                "20240101T090000",
+                "20240102T140000",
+                "20240103T090000",
+                "20240103T160000"
+            ])
+        );
+    }
+
+    #[test]
+    fn start_skipped_by_dst() {
+        // 02:30 does not exist in Paris on 2024-03-31, it is taken with the offset before the gap
+        let events = events(
+            "dst",
+            &["UID:a
+               DTSTART;TZID=Europe/Paris:20240330T023000
+               DTEND;TZID=Europe/Paris:20240330T033000
+               RRULE:FREQ=DAILY;COUNT=3"],
+        );
+        assert_eq!(
+            starts(&events),
+            utc_starts(&["20240330T013000", "20240331T013000", "20240401T003000"])
+        );
+        assert!(events[0]
+            .occurrences(&(0..i64::MAX))
+            .iter()
+            .all(|occurrence| occurrence.end - occurrence.start == HOUR_MS));
+    }
+
+    #[test]
+    fn occurrences_overlapping_range() {
+        let events = events(
+            "range",
+            &["UID:a
+               DTSTART:20240101T090000Z
+               DTEND:20240101T100000Z
+               RRULE:FREQ=DAILY"],
+        );
+        let start = utc_ms("20240110T093000");
+        let occurrences = events[0].occurrences(&(start..start + DAY_MS));
+        assert_eq!(
+            occurrences,
+            [
+                utc_ms("20240110T090000")..utc_ms("20240110T100000"),
+                utc_ms("20240111T090000")..utc_ms("20240111T100000")
+            ]
+        );
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("PT1H30M"), Some(90 * 60 * 1000));
+        assert_eq!(parse_duration("-P1W"), Some(-7 * DAY_MS));
+        assert_eq!(parse_duration("P1DT1S"), Some(DAY_MS + 1000));
+        assert_eq!(parse_duration("P1X"), None);
+        assert_eq!(parse_duration("PT9223372036854775807S"), None);
+        assert_eq!(parse_duration("P1000000000000000WT1H"), None);
+    }
+
+    #[test]
+    fn long_duration_saturates() {
+        let events = events(
+            "long",
+            &["UID:a
+               DTSTART:20240101T090000Z
+               DURATION:PT9223372036854775S"],
+        );
+        assert_eq!(
+            events[0].occurrences(&(0..i64::MAX)),
+            vec![utc_ms("20240101T090000")..i64::MAX]
+        );
+    }
+}
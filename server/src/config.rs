@@ -1,10 +1,36 @@
+use crate::{
+    auth::Role,
+    calendar::{CalendarError, Rule, RuleSpec},
+    lock::{LockError, LockWindow, LockWindowSpec},
+    schedule::{Entry, EntrySpec, ScheduleError},
+    stats::PostureThresholds,
+    utils::{Position, PositionError},
+};
 use btleplug::api::BDAddr;
+use chrono::NaiveTime;
 use clap::Parser;
-use desklink_common::{deserialize_log_level, PROJECT_NAME};
+use desklink_common::{deserialize_log_level, telemetry::DEFAULT_OTLP_ENDPOINT, PROJECT_NAME};
 use directories::ProjectDirs;
+use http::HeaderValue;
+use rustls_pemfile::Item;
 use serde::Deserialize;
-use std::{ffi::OsString, io, net::SocketAddr, path::PathBuf};
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    fmt::{self, Debug, Formatter},
+    io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 use thiserror::Error;
+use tokio_rustls::rustls::{
+    self,
+    server::{AllowAnyAuthenticatedClient, NoClientAuth},
+    RootCertStore,
+};
+use tonic::transport::{Certificate, Identity, ServerTlsConfig};
 use tracing::Level;
 
 #[derive(Error, Debug)]
@@ -24,6 +50,64 @@ pub enum ConfigError {
 
     #[error("Invalid path to the log file {0}")]
     InvalidLogfile(PathBuf),
+
+    #[error("Cannot determine the storage directory")]
+    NoStorageDirectory,
+
+    #[error("No private key in `{0}`")]
+    NoPrivateKey(PathBuf),
+
+    #[error("Invalid client CA certificate in `{0}`")]
+    InvalidClientCa(PathBuf),
+
+    #[error("Invalid TLS certificate or key")]
+    TlsError(#[from] rustls::Error),
+
+    #[error("Invalid CORS origin `{0}`")]
+    InvalidCorsOrigin(String),
+
+    #[error("Invalid standing threshold")]
+    InvalidStandingThreshold(#[source] PositionError),
+
+    #[error("Invalid sitting threshold")]
+    InvalidSittingThreshold(#[source] PositionError),
+
+    #[error("Sitting threshold is above the standing threshold")]
+    InvertedPostureThresholds,
+
+    #[error("Invalid goal check time `{0}`, expected HH:MM")]
+    InvalidGoalTime(String),
+
+    #[error("The standing goal requires the history")]
+    GoalWithoutHistory,
+
+    #[error("Invalid schedule entry `{name}`")]
+    InvalidScheduleEntry {
+        name: String,
+        #[source]
+        error: ScheduleError,
+    },
+
+    #[error("Invalid lock window `{name}`")]
+    InvalidLockWindow {
+        name: String,
+        #[source]
+        error: LockError,
+    },
+
+    #[error("Invalid calendar rule `{name}`")]
+    InvalidCalendarRule {
+        name: String,
+        #[source]
+        error: CalendarError,
+    },
+
+    #[error("Invalid preset `{name}`")]
+    InvalidPreset {
+        name: String,
+        #[source]
+        error: PositionError,
+    },
 }
 
 mod args {
@@ -50,6 +134,14 @@ mod args {
         /// Server bind address and port
         #[clap(short, long)]
         pub server: Option<SocketAddr>,
+
+        /// Unix socket to serve on
+        #[clap(short, long)]
+        pub unix_socket: Option<PathBuf>,
+
+        /// HTTP gateway bind address and port
+        #[clap(long)]
+        pub http: Option<SocketAddr>,
     }
 }
 
@@ -61,11 +153,24 @@ mod file {
         pub desk: Option<DeskConfig>,
         pub log: Option<LogConfig>,
         pub server: Option<ServerConfig>,
+        pub http: Option<HttpConfig>,
+        pub mqtt: Option<MqttConfig>,
+        pub tracing: Option<TracingConfig>,
+        pub history: Option<HistoryConfig>,
+        pub goal: Option<GoalConfig>,
+        pub scheduler: Option<SchedulerConfig>,
+        pub lock: Option<LockConfig>,
+        pub calendar: Option<CalendarConfig>,
+        pub auth: Option<AuthConfig>,
+        pub storage: Option<StorageConfig>,
+        pub presets: Option<BTreeMap<String, f32>>,
     }
 
     #[derive(Deserialize)]
     pub struct DeskConfig {
         pub address: Option<BDAddr>,
+        pub standing_threshold: Option<f32>,
+        pub sitting_threshold: Option<f32>,
     }
 
     #[derive(Deserialize)]
@@ -75,9 +180,88 @@ mod file {
         pub file: Option<PathBuf>,
     }
 
-    #[derive(Deserialize)]
+    #[derive(Deserialize, Default)]
     pub struct ServerConfig {
         pub address: Option<SocketAddr>,
+        pub unix_socket: Option<PathBuf>,
+        pub unix_socket_mode: Option<u32>,
+        pub tls_cert: Option<PathBuf>,
+        pub tls_key: Option<PathBuf>,
+        pub tls_client_ca: Option<PathBuf>,
+        pub grpc_web: Option<bool>,
+        pub cors_origins: Option<Vec<String>>,
+    }
+
+    #[derive(Deserialize)]
+    pub struct HttpConfig {
+        pub address: Option<SocketAddr>,
+    }
+
+    #[derive(Deserialize)]
+    pub struct MqttConfig {
+        pub host: Option<String>,
+        pub port: Option<u16>,
+        pub client_id: Option<String>,
+        pub username: Option<String>,
+        pub password: Option<String>,
+        pub topic_prefix: Option<String>,
+        pub discovery: Option<bool>,
+        pub discovery_prefix: Option<String>,
+        pub entity: Option<super::HomeAssistantEntity>,
+        pub token: Option<String>,
+        pub trusted: Option<bool>,
+    }
+
+    #[derive(Deserialize)]
+    pub struct TracingConfig {
+        pub otlp_endpoint: Option<String>,
+        pub service_name: Option<String>,
+    }
+
+    #[derive(Deserialize)]
+    pub struct HistoryConfig {
+        pub enabled: Option<bool>,
+        pub rest_interval_s: Option<u64>,
+        pub retention_days: Option<u64>,
+    }
+
+    #[derive(Deserialize)]
+    pub struct GoalConfig {
+        pub standing_minutes: Option<u64>,
+        pub behind_at: Option<String>,
+    }
+
+    #[derive(Deserialize)]
+    pub struct SchedulerConfig {
+        pub manual_grace_minutes: Option<u64>,
+        pub entries: Option<BTreeMap<String, EntrySpec>>,
+    }
+
+    #[derive(Deserialize)]
+    pub struct LockConfig {
+        pub windows: Option<BTreeMap<String, LockWindowSpec>>,
+    }
+
+    #[derive(Deserialize)]
+    pub struct CalendarConfig {
+        pub path: Option<PathBuf>,
+        pub rules: Option<BTreeMap<String, RuleSpec>>,
+    }
+
+    #[derive(Deserialize)]
+    pub struct AuthConfig {
+        pub tokens: Option<Vec<super::TokenConfig>>,
+        pub tokens_file: Option<PathBuf>,
+    }
+
+    #[derive(Deserialize)]
+    pub struct TokensFile {
+        pub tokens: Vec<super::TokenConfig>,
+    }
+
+    #[derive(Deserialize)]
+    pub struct StorageConfig {
+        pub directory: Option<PathBuf>,
     }
 }
 
@@ -86,22 +270,278 @@ pub struct Config {
     pub log: LogConfig,
     pub desk: DeskConfig,
     pub server: ServerConfig,
+    pub http: HttpConfig,
+    /// MQTT bridge, disabled if not configured
+    pub mqtt: Option<MqttConfig>,
+    /// Trace export, disabled if not configured
+    pub tracing: Option<TracingConfig>,
+    /// Height history, on unless disabled
+    pub history: Option<HistoryConfig>,
+    /// Daily standing goal, disabled if not configured
+    pub goal: Option<GoalConfig>,
+    pub scheduler: SchedulerConfig,
+    pub lock: LockConfig,
+    /// Rules for the events of a calendar file, disabled if not configured
+    pub calendar: Option<CalendarConfig>,
+    pub auth: AuthConfig,
+    pub storage: StorageConfig,
+    pub presets: BTreeMap<String, Position>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
 pub struct LogConfig {
     pub level: Level,
     pub file: Option<(PathBuf, OsString)>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
 pub struct DeskConfig {
     pub address: BDAddr,
+    pub posture: PostureThresholds,
 }
 
-#[derive(Debug)]
+pub const DEFAULT_STANDING_THRESHOLD_CM: f32 = 95.0;
+
+/// Both the TCP address and the Unix socket may be unset under socket activation
+#[derive(Debug, PartialEq, Eq)]
 pub struct ServerConfig {
-    pub address: SocketAddr,
+    pub address: Option<SocketAddr>,
+    pub unix_socket: Option<UnixSocketConfig>,
+    /// Plaintext HTTP/2 is served over TCP if TLS is not configured
+    pub tls: Option<TlsConfig>,
+    pub grpc_web: GrpcWebConfig,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct GrpcWebConfig {
+    /// Also accept HTTP/1.1 and gRPC-Web requests from browsers
+    pub enabled: bool,
+    /// Origins allowed to make cross-origin requests, `*` for any
+    pub cors_origins: Vec<HeaderValue>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct UnixSocketConfig {
+    pub path: PathBuf,
+    /// Permission bits of the socket file
+    pub mode: u32,
+}
+
+/// Owner and group can read and write
+pub const DEFAULT_UNIX_SOCKET_MODE: u32 = 0o660;
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct TlsConfig {
+    /// PEM certificate chain of the server
+    pub cert: PathBuf,
+    /// PEM private key of the server
+    pub key: PathBuf,
+    /// PEM CA certificates for verifying clients, client certificates are required if set
+    pub client_ca: Option<PathBuf>,
+}
+
+impl TlsConfig {
+    pub fn load(&self) -> Result<ServerTlsConfig, ConfigError> {
+        let identity = Identity::from_pem(read_file(&self.cert)?, read_file(&self.key)?);
+        let mut tls = ServerTlsConfig::new().identity(identity);
+        if let Some(client_ca) = &self.client_ca {
+            tls = tls.client_ca_root(Certificate::from_pem(read_file(client_ca)?));
+        }
+        Ok(tls)
+    }
+
+    /// The same settings for the HTTP gateway
+    pub fn load_http(&self) -> Result<Arc<rustls::ServerConfig>, ConfigError> {
+        let pem = |path: &Path| {
+            let content = read_file(path)?;
+            rustls_pemfile::read_all(&mut content.as_slice()).map_err(|error| {
+                ConfigError::IoError {
+                    path: path.to_owned(),
+                    error,
+                }
+            })
+        };
+        let certs = pem(&self.cert)?
+            .into_iter()
+            .filter_map(|item| match item {
+                Item::X509Certificate(cert) => Some(rustls::Certificate(cert)),
+                _ => None,
+            })
+            .collect();
+        let key = pem(&self.key)?
+            .into_iter()
+            .find_map(|item| match item {
+                Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key) => {
+                    Some(rustls::PrivateKey(key))
+                }
+                _ => None,
+            })
+            .ok_or_else(|| ConfigError::NoPrivateKey(self.key.clone()))?;
+        let verifier = match &self.client_ca {
+            Some(client_ca) => {
+                let mut roots = RootCertStore::empty();
+                for item in pem(client_ca)? {
+                    if let Item::X509Certificate(cert) = item {
+                        roots
+                            .add(&rustls::Certificate(cert))
+                            .map_err(|_| ConfigError::InvalidClientCa(client_ca.clone()))?;
+                    }
+                }
+                AllowAnyAuthenticatedClient::new(roots)
+            }
+            None => NoClientAuth::new(),
+        };
+        let mut tls = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(certs, key)?;
+        tls.alpn_protocols = vec![b"http/1.1".to_vec()];
+        Ok(Arc::new(tls))
+    }
+}
+
+/// JSON gateway, disabled if no address is set
+#[derive(Debug, PartialEq, Eq)]
+pub struct HttpConfig {
+    pub address: Option<SocketAddr>,
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct MqttConfig {
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    pub credentials: Option<(String, String)>,
+    /// Prefix of the state and command topics
+    pub topic_prefix: String,
+    /// Home Assistant discovery prefix, discovery is disabled if unset
+    pub discovery_prefix: Option<String>,
+    pub entity: HomeAssistantEntity,
+    /// Token of the bridge, from the tokens of `[auth]`
+    pub token: Option<String>,
+    /// Forward commands without a token even if authentication is enabled
+    pub trusted: bool,
+}
+
+impl Debug for MqttConfig {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MqttConfig")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("client_id", &self.client_id)
+            .field(
+                "credentials",
+                &self
+                    .credentials
+                    .as_ref()
+                    .map(|(username, _)| (username, "<redacted>")),
+            )
+            .field("topic_prefix", &self.topic_prefix)
+            .field("discovery_prefix", &self.discovery_prefix)
+            .field("entity", &self.entity)
+            .field("token", &self.token.as_ref().map(|_| "<redacted>"))
+            .field("trusted", &self.trusted)
+            .finish()
+    }
+}
+
+/// How the desk shows up in Home Assistant
+#[derive(Deserialize, Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum HomeAssistantEntity {
+    /// Height slider
+    #[default]
+    Number,
+    /// Open and close buttons, with the height as the cover position
+    Cover,
+}
+
+pub const DEFAULT_MQTT_PORT: u16 = 1883;
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct TracingConfig {
+    /// OTLP/gRPC collector
+    pub otlp_endpoint: String,
+    pub service_name: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HistoryConfig {
+    /// Interval between samples while the desk is at rest
+    pub rest_interval: Duration,
+    /// Age after which samples are deleted, kept forever if unset
+    pub retention: Option<Duration>,
+}
+
+pub const DEFAULT_HISTORY_REST_INTERVAL: Duration = Duration::from_secs(60);
+pub const DEFAULT_HISTORY_RETENTION_DAYS: u64 = 365;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GoalConfig {
+    /// Standing time to reach every day
+    pub standing: Duration,
+    /// Local time at which to warn if the goal is still far off
+    pub behind_at: NaiveTime,
+}
+
+pub const DEFAULT_GOAL_BEHIND_AT: &str = "16:00";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SchedulerConfig {
+    /// Entries are skipped for this long after the desk is moved by hand
+    pub manual_grace: Duration,
+    pub entries: BTreeMap<String, Entry>,
+}
+
+pub const DEFAULT_SCHEDULER_MANUAL_GRACE: Duration = Duration::from_secs(15 * 60);
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LockConfig {
+    pub windows: BTreeMap<String, LockWindow>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CalendarConfig {
+    /// iCalendar file, read again when it changes
+    pub path: PathBuf,
+    pub rules: BTreeMap<String, Rule>,
+}
+
+/// Authentication is disabled if no token is configured
+#[derive(Debug)]
+pub struct AuthConfig {
+    pub tokens: Vec<TokenConfig>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct TokenConfig {
+    /// Identity of the token holder, used in logs
+    pub name: String,
+    pub token: String,
+    #[serde(default)]
+    pub role: Role,
+    /// Lowest position in cm the token may move the desk to
+    pub min_height: Option<f32>,
+    /// Highest position in cm the token may move the desk to
+    pub max_height: Option<f32>,
+}
+
+impl Debug for TokenConfig {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TokenConfig")
+            .field("name", &self.name)
+            .field("token", &"<redacted>")
+            .field("role", &self.role)
+            .field("min_height", &self.min_height)
+            .field("max_height", &self.max_height)
+            .finish()
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct StorageConfig {
+    /// Directory for state that outlives the process
+    pub directory: PathBuf,
 }
 
 impl Config {
@@ -156,19 +596,248 @@ impl Config {
                         .transpose()?,
                 }
             },
-            desk: DeskConfig {
-                address: args
-                    .desk
-                    .or_else(|| toml_config.desk.and_then(|d| d.address))
-                    .ok_or(ConfigError::MissingConfigField("desk MAC address"))?,
+            desk: {
+                let (address, standing_threshold, sitting_threshold) = match toml_config.desk {
+                    Some(desk) => (
+                        desk.address,
+                        desk.standing_threshold,
+                        desk.sitting_threshold,
+                    ),
+                    None => (None, None, None),
+                };
+                let standing =
+                    Position::from_cm(standing_threshold.unwrap_or(DEFAULT_STANDING_THRESHOLD_CM))
+                        .map_err(ConfigError::InvalidStandingThreshold)?;
+                // without a sitting threshold, every height is either sitting or standing
+                let sitting = match sitting_threshold {
+                    Some(cm) => {
+                        Position::from_cm(cm).map_err(ConfigError::InvalidSittingThreshold)?
+                    }
+                    None => standing,
+                };
+                if sitting > standing {
+                    return Err(ConfigError::InvertedPostureThresholds);
+                }
+                DeskConfig {
+                    address: args
+                        .desk
+                        .or(address)
+                        .ok_or(ConfigError::MissingConfigField("desk MAC address"))?,
+                    posture: PostureThresholds { sitting, standing },
+                }
             },
-            server: ServerConfig {
+            server: {
+                let server = toml_config.server.unwrap_or_default();
+                ServerConfig {
+                    address: args.server.or(server.address),
+                    unix_socket: args.unix_socket.or(server.unix_socket).map(|path| {
+                        UnixSocketConfig {
+                            path,
+                            mode: server.unix_socket_mode.unwrap_or(DEFAULT_UNIX_SOCKET_MODE),
+                        }
+                    }),
+                    tls: match (server.tls_cert, server.tls_key) {
+                        (Some(cert), Some(key)) => Some(TlsConfig {
+                            cert,
+                            key,
+                            client_ca: server.tls_client_ca,
+                        }),
+                        (Some(_), None) => {
+                            return Err(ConfigError::MissingConfigField("server TLS key"))
+                        }
+                        (None, None) if server.tls_client_ca.is_none() => None,
+                        (None, _) => {
+                            return Err(ConfigError::MissingConfigField("server TLS certificate"))
+                        }
+                    },
+                    grpc_web: GrpcWebConfig {
+                        enabled: server.grpc_web.unwrap_or(false),
+                        cors_origins: server
+                            .cors_origins
+                            .unwrap_or_default()
+                            .into_iter()
+                            .map(|origin| {
+                                HeaderValue::from_str(&origin)
+                                    .map_err(|_| ConfigError::InvalidCorsOrigin(origin))
+                            })
+                            .collect::<Result<_, _>>()?,
+                    },
+                }
+            },
+            http: HttpConfig {
                 address: args
-                    .server
-                    .or_else(|| toml_config.server.and_then(|s| s.address))
-                    .ok_or(ConfigError::MissingConfigField("server bind address"))?,
+                    .http
+                    .or_else(|| toml_config.http.and_then(|h| h.address)),
             },
+            mqtt: match toml_config.mqtt {
+                Some(mqtt) => Some(MqttConfig {
+                    host: mqtt
+                        .host
+                        .ok_or(ConfigError::MissingConfigField("MQTT broker host"))?,
+                    port: mqtt.port.unwrap_or(DEFAULT_MQTT_PORT),
+                    client_id: mqtt.client_id.unwrap_or_else(|| "deskd".to_owned()),
+                    credentials: match (mqtt.username, mqtt.password) {
+                        (Some(username), password) => {
+                            Some((username, password.unwrap_or_default()))
+                        }
+                        (None, Some(_)) => {
+                            return Err(ConfigError::MissingConfigField("MQTT username"))
+                        }
+                        (None, None) => None,
+                    },
+                    topic_prefix: mqtt.topic_prefix.unwrap_or_else(|| PROJECT_NAME.to_owned()),
+                    discovery_prefix: if mqtt.discovery.unwrap_or(true) {
+                        Some(
+                            mqtt.discovery_prefix
+                                .unwrap_or_else(|| "homeassistant".to_owned()),
+                        )
+                    } else {
+                        None
+                    },
+                    entity: mqtt.entity.unwrap_or_default(),
+                    token: mqtt.token,
+                    trusted: mqtt.trusted.unwrap_or(false),
+                }),
+                None => None,
+            },
+            tracing: toml_config.tracing.map(|tracing| TracingConfig {
+                otlp_endpoint: tracing
+                    .otlp_endpoint
+                    .unwrap_or_else(|| DEFAULT_OTLP_ENDPOINT.to_owned()),
+                service_name: tracing.service_name.unwrap_or_else(|| "deskd".to_owned()),
+            }),
+            history: match toml_config.history {
+                Some(history) if !history.enabled.unwrap_or(true) => None,
+                history => {
+                    let (rest_interval_s, retention_days) = match history {
+                        Some(history) => (history.rest_interval_s, history.retention_days),
+                        None => (None, None),
+                    };
+                    Some(HistoryConfig {
+                        rest_interval: rest_interval_s
+                            .map(Duration::from_secs)
+                            .unwrap_or(DEFAULT_HISTORY_REST_INTERVAL),
+                        retention: match retention_days.unwrap_or(DEFAULT_HISTORY_RETENTION_DAYS) {
+                            0 => None,
+                            days => Some(Duration::from_secs(days * 24 * 60 * 60)),
+                        },
+                    })
+                }
+            },
+            goal: match toml_config.goal {
+                Some(file::GoalConfig {
+                    standing_minutes: Some(standing_minutes),
+                    behind_at,
+                }) => {
+                    let behind_at = behind_at.unwrap_or_else(|| DEFAULT_GOAL_BEHIND_AT.to_owned());
+                    Some(GoalConfig {
+                        standing: Duration::from_secs(standing_minutes * 60),
+                        behind_at: NaiveTime::parse_from_str(&behind_at, "%H:%M")
+                            .map_err(|_| ConfigError::InvalidGoalTime(behind_at))?,
+                    })
+                }
+                Some(_) => return Err(ConfigError::MissingConfigField("goal standing minutes")),
+                None => None,
+            },
+            scheduler: {
+                let (manual_grace_minutes, entries) = match toml_config.scheduler {
+                    Some(scheduler) => (scheduler.manual_grace_minutes, scheduler.entries),
+                    None => (None, None),
+                };
+                SchedulerConfig {
+                    manual_grace: manual_grace_minutes
+                        .map(|minutes| Duration::from_secs(minutes * 60))
+                        .unwrap_or(DEFAULT_SCHEDULER_MANUAL_GRACE),
+                    entries: entries
+                        .unwrap_or_default()
+                        .into_iter()
+                        .map(|(name, spec)| match Entry::try_from(spec) {
+                            Ok(entry) => Ok((name, entry)),
+                            Err(error) => Err(ConfigError::InvalidScheduleEntry { name, error }),
+                        })
+                        .collect::<Result<_, _>>()?,
+                }
+            },
+            lock: LockConfig {
+                windows: toml_config
+                    .lock
+                    .and_then(|lock| lock.windows)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|(name, spec)| match LockWindow::try_from(spec) {
+                        Ok(window) => Ok((name, window)),
+                        Err(error) => Err(ConfigError::InvalidLockWindow { name, error }),
+                    })
+                    .collect::<Result<_, _>>()?,
+            },
+            calendar: match toml_config.calendar {
+                Some(file::CalendarConfig {
+                    path: Some(path),
+                    rules,
+                }) => Some(CalendarConfig {
+                    path,
+                    rules: rules
+                        .unwrap_or_default()
+                        .into_iter()
+                        .map(|(name, spec)| match Rule::try_from(spec) {
+                            Ok(rule) => Ok((name, rule)),
+                            Err(error) => Err(ConfigError::InvalidCalendarRule { name, error }),
+                        })
+                        .collect::<Result<_, _>>()?,
+                }),
+                Some(_) => return Err(ConfigError::MissingConfigField("calendar path")),
+                None => None,
+            },
+            auth: {
+                let (tokens, tokens_file) = match toml_config.auth {
+                    Some(auth) => (auth.tokens, auth.tokens_file),
+                    None => (None, None),
+                };
+                let mut tokens = tokens.unwrap_or_default();
+                if let Some(path) = tokens_file {
+                    tokens.extend(read_tokens_file(&path)?);
+                }
+                AuthConfig { tokens }
+            },
+            storage: StorageConfig {
+                directory: match toml_config.storage.and_then(|s| s.directory) {
+                    Some(directory) => directory,
+                    None => ProjectDirs::from("", "", PROJECT_NAME)
+                        .ok_or(ConfigError::NoStorageDirectory)?
+                        .data_dir()
+                        .to_owned(),
+                },
+            },
+            presets: toml_config
+                .presets
+                .unwrap_or_default()
+                .into_iter()
+                .map(|(name, cm)| match Position::from_cm(cm) {
+                    Ok(position) => Ok((name, position)),
+                    Err(error) => Err(ConfigError::InvalidPreset { name, error }),
+                })
+                .collect::<Result<_, _>>()?,
         };
+        // progress is computed from the stored samples
+        if config.goal.is_some() && config.history.is_none() {
+            return Err(ConfigError::GoalWithoutHistory);
+        }
         Ok(config)
     }
 }
+
+fn read_tokens_file(path: &Path) -> Result<Vec<TokenConfig>, ConfigError> {
+    let content = std::fs::read_to_string(path).map_err(|error| ConfigError::IoError {
+        path: path.to_owned(),
+        error,
+    })?;
+    let tokens_file: file::TokensFile = toml::from_str(&content)?;
+    Ok(tokens_file.tokens)
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>, ConfigError> {
+    std::fs::read(path).map_err(|error| ConfigError::IoError {
+        path: path.to_owned(),
+        error,
+    })
+}
@@ -1,19 +1,33 @@
 mod overshoot;
+pub mod policy;
 
 use crate::{
     desk::{Desk, DeskError},
+    events::{Event, EventStream, MoveOutcome},
+    systemd::Watchdog,
     utils::{Position, Velocity},
 };
 use async_trait::async_trait;
-use futures::Stream;
-use std::{cmp::Ordering, future::Future, pin::Pin, ptr::NonNull, sync::Mutex};
+use futures::{future, Stream};
+use policy::{Lease, Policy};
+use std::{
+    cmp::Ordering,
+    future::Future,
+    pin::Pin,
+    ptr::NonNull,
+    sync::{
+        atomic::{self, AtomicU64},
+        Mutex,
+    },
+    time::Duration,
+};
 use thiserror::Error;
 use tokio::{
     select,
     sync::{oneshot, watch},
 };
 use tokio_stream::wrappers::WatchStream;
-use tracing::{error, trace, warn};
+use tracing::{error, info_span, trace, warn, Instrument, Span};
 
 #[derive(Error, Debug)]
 pub enum ControllerError {
@@ -22,6 +36,25 @@ pub enum ControllerError {
 
     #[error("Aborted by user")]
     Aborted,
+
+    #[error("Desk stalled before reaching the target")]
+    Stalled,
+
+    #[error("Desk is controlled by {holder} for another {remaining:.0?}")]
+    Leased { holder: String, remaining: Duration },
+}
+
+impl ControllerError {
+    /// Whether the controller can keep running after this error
+    fn is_recoverable(&self) -> bool {
+        matches!(
+            self,
+            ControllerError::Aborted
+                | ControllerError::Stalled
+                | ControllerError::Leased { .. }
+                | ControllerError::DeskError(DeskError::Disconnected)
+        )
+    }
 }
 
 type CompletePromise<T> = oneshot::Sender<Result<T, ControllerError>>;
@@ -29,6 +62,14 @@ pub type Complete<T> = oneshot::Receiver<Result<T, ControllerError>>;
 pub type CommandReceiver = watch::Receiver<Mutex<Option<Command>>>;
 pub type CommandSender = watch::Sender<Mutex<Option<Command>>>;
 pub type StateStream = Pin<Box<dyn Stream<Item = (Position, Velocity)> + Send>>;
+type OutcomePromise = oneshot::Sender<MoveOutcome>;
+
+/// Accepted move, its outcome is resolved when it finishes.
+/// Unlike the move events, the outcome is never dropped.
+pub struct MoveReport {
+    pub id: u64,
+    pub outcome: oneshot::Receiver<MoveOutcome>,
+}
 
 pub trait CommandSenderExt {
     fn send_command(&self, command: Command);
@@ -47,13 +88,56 @@ pub enum Command {
     SubscribeState {
         result: CompletePromise<StateStream>,
     },
+    SubscribeEvents {
+        result: CompletePromise<EventStream>,
+    },
     Stop {
         complete: CompletePromise<()>,
     },
     MoveTo {
-        target: Position,
+        info: MoveInfo,
+        lease: Option<String>,
         complete: CompletePromise<()>,
+        /// Resolved when the move finishes.
+        /// The desk is stopped if the receiver is dropped before that.
+        finished: Option<CompletePromise<()>>,
+        outcome: OutcomePromise,
     },
+    AcquireControl {
+        holder: String,
+        duration: Duration,
+        renew: Option<String>,
+        result: CompletePromise<Lease>,
+    },
+    ReleaseControl {
+        lease: String,
+        complete: CompletePromise<()>,
+    },
+}
+
+#[derive(Clone, Debug)]
+pub struct MoveInfo {
+    pub id: u64,
+    pub target: Position,
+    /// Originating client
+    pub client: String,
+    /// Span of the request, the move is traced as its child
+    pub span: Span,
+}
+
+impl MoveInfo {
+    fn new(target: Position, client: String) -> (Self, OutcomePromise, MoveReport) {
+        static NEXT_MOVE_ID: AtomicU64 = AtomicU64::new(1);
+        let id = NEXT_MOVE_ID.fetch_add(1, atomic::Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        let info = MoveInfo {
+            id,
+            target,
+            client,
+            span: Span::current(),
+        };
+        (info, tx, MoveReport { id, outcome: rx })
+    }
 }
 
 impl Command {
@@ -67,16 +151,75 @@ impl Command {
         (Command::SubscribeState { result: tx }, rx)
     }
 
+    pub fn subscribe_events() -> (Command, Complete<EventStream>) {
+        let (tx, rx) = oneshot::channel();
+        (Command::SubscribeEvents { result: tx }, rx)
+    }
+
     pub fn stop() -> (Command, Complete<()>) {
         let (tx, rx) = oneshot::channel();
         (Command::Stop { complete: tx }, rx)
     }
 
-    pub fn move_to(target: Position) -> (Command, Complete<()>) {
+    pub fn move_to(
+        target: Position,
+        client: String,
+        lease: Option<String>,
+    ) -> (Command, Complete<()>, MoveReport) {
+        let (tx, rx) = oneshot::channel();
+        let (info, outcome, report) = MoveInfo::new(target, client);
+        let command = Command::MoveTo {
+            info,
+            lease,
+            complete: tx,
+            finished: None,
+            outcome,
+        };
+        (command, rx, report)
+    }
+
+    /// Like `move_to`, but the move is bound to the returned `Complete<()>` for the
+    /// finished move, which must be kept alive until the move finishes.
+    pub fn move_to_bound(
+        target: Position,
+        client: String,
+        lease: Option<String>,
+    ) -> (Command, Complete<()>, Complete<()>, MoveReport) {
+        let (tx, rx) = oneshot::channel();
+        let (finished_tx, finished_rx) = oneshot::channel();
+        let (info, outcome, report) = MoveInfo::new(target, client);
+        let command = Command::MoveTo {
+            info,
+            lease,
+            complete: tx,
+            finished: Some(finished_tx),
+            outcome,
+        };
+        (command, rx, finished_rx, report)
+    }
+
+    pub fn acquire_control(
+        holder: String,
+        duration: Duration,
+        renew: Option<String>,
+    ) -> (Command, Complete<Lease>) {
+        let (tx, rx) = oneshot::channel();
+        (
+            Command::AcquireControl {
+                holder,
+                duration,
+                renew,
+                result: tx,
+            },
+            rx,
+        )
+    }
+
+    pub fn release_control(lease: String) -> (Command, Complete<()>) {
         let (tx, rx) = oneshot::channel();
         (
-            Command::MoveTo {
-                target,
+            Command::ReleaseControl {
+                lease,
                 complete: tx,
             },
             rx,
@@ -104,7 +247,7 @@ pub trait Controller: Send {
         };
         match &result {
             Ok(()) => trace!("Finish moving to {}", position),
-            Err(ControllerError::Aborted) => warn!("{}", ControllerError::Aborted),
+            Err(e) if e.is_recoverable() => warn!("{}", e),
             Err(e) => error!("Error moving to {}: {}", position, e),
         }
         result
@@ -122,41 +265,84 @@ pub trait Controller: Send {
 
     async fn update(&mut self) -> Result<(Position, Velocity), ControllerError> {
         self.desk().update().await.map_err(|e| {
-            let e = e.into();
-            error!("Error updateing: {}", e);
+            let e: ControllerError = e.into();
+            if !e.is_recoverable() {
+                error!("Error updateing: {}", e);
+            }
             e
         })
     }
 
     async fn drive(&mut self, mut inputs: CommandReceiver) -> Result<(), ControllerError> {
         let mut in_progress: InProgress<'_> = None;
+        let mut policy = Policy::default();
+        let mut watchdog = Watchdog::from_env();
+        // state notifications during a move show the bluetooth stack is alive
+        let mut states = self.desk().state.clone();
 
         loop {
             match &mut in_progress {
                 Some(task) => {
                     select! {
-                        result = &mut *task => {
-                            in_progress = None;
+                        result = &mut task.future => {
+                            // Future must be dropped before borrowing self
+                            let Task { info, finished, outcome: promise, .. } = in_progress.take().expect("No task");
+                            let outcome = match &result {
+                                Ok(()) => MoveOutcome::Reached,
+                                Err(ControllerError::Stalled) => MoveOutcome::Stalled,
+                                Err(_) => MoveOutcome::Aborted,
+                            };
+                            finish_move(self, info, promise, outcome);
+                            watchdog.progress();
                             match result {
-                                Ok(()) | Err(ControllerError::Aborted) => {}
-                                e => { return e; }
+                                Err(e) if !e.is_recoverable() => { return Err(e); }
+                                result => {
+                                    if let Some(finished) = finished {
+                                        finished.send(result).unwrap_or(());
+                                    }
+                                }
                             }
                         }
+                        _ = session_closed(&mut task.finished) => {
+                            // Future must be dropped before borrowing self
+                            let Task { info, outcome: promise, .. } = in_progress.take().expect("No task");
+                            warn!(client = %info.client, "Client went away during bound move, stopping");
+                            finish_move(self, info, promise, MoveOutcome::Aborted);
+                            self.stop().await.unwrap_or(());
+                        }
                         result = inputs.changed() => {
                             if result.is_err() {
-                                return task.await;
+                                return (&mut task.future).await;
                             } else {
                                 unsafe {
-                                    process_command(self, &mut inputs, &mut in_progress)
+                                    process_command(self, &mut inputs, &mut in_progress, &mut policy)
                                 }.await;
+                                watchdog.progress();
                             }
                         }
+                        Ok(()) = states.changed() => {
+                            watchdog.progress();
+                        }
+                        _ = watchdog.due() => {
+                            watchdog.ping_if_progressed();
+                        }
                     }
                 }
                 None => {
+                    let (_, last_velocity) = self.desk().state();
                     select! {
                         result = self.update() => {
-                            result?;
+                            match result {
+                                Ok((position, velocity)) => {
+                                    if last_velocity.is_zero() && !velocity.is_zero() {
+                                        trace!("Manual movement detected at {}", position);
+                                        self.desk().publish(Event::ManualMovement { position });
+                                    }
+                                    watchdog.progress();
+                                }
+                                Err(e) if e.is_recoverable() => {}
+                                Err(e) => { return Err(e); }
+                            }
                         }
                         result = inputs.changed() => {
                             if result.is_err() {
@@ -164,8 +350,26 @@ pub trait Controller: Send {
                                 return Ok(());
                             } else {
                                 unsafe {
-                                    process_command(self, &mut inputs, &mut in_progress)
+                                    process_command(self, &mut inputs, &mut in_progress, &mut policy)
                                 }.await;
+                                watchdog.progress();
+                            }
+                        }
+                        _ = watchdog.due() => {
+                            if !self.desk().is_connected() {
+                                // a hung connection attempt must get deskd restarted,
+                                // even while commands are still processed
+                                if self.desk().reconnect_alive() {
+                                    watchdog.ping();
+                                } else {
+                                    warn!("Reconnecting to the desk hangs, not pinging the watchdog");
+                                }
+                            } else if !watchdog.ping_if_progressed() {
+                                // an idle desk sends no notifications, so ask it for the state
+                                match self.desk().probe().await {
+                                    Ok(_) => watchdog.ping(),
+                                    Err(e) => warn!("Watchdog probe failed: {}", e),
+                                }
                             }
                         }
                     }
@@ -175,8 +379,54 @@ pub trait Controller: Send {
     }
 }
 
-type InProgress<'a> =
-    Option<Pin<Box<dyn Future<Output = Result<(), ControllerError>> + Send + 'a>>>;
+struct Task<'a> {
+    info: MoveInfo,
+    future: Pin<Box<dyn Future<Output = Result<(), ControllerError>> + Send + 'a>>,
+    finished: Option<CompletePromise<()>>,
+    outcome: OutcomePromise,
+}
+
+impl Task<'_> {
+    /// Notify the bound client, if any, that the move did not finish
+    fn abort(self) -> (MoveInfo, OutcomePromise) {
+        if let Some(finished) = self.finished {
+            finished.send(Err(ControllerError::Aborted)).unwrap_or(());
+        }
+        (self.info, self.outcome)
+    }
+}
+
+type InProgress<'a> = Option<Task<'a>>;
+
+/// Resolves when the client bound to a move goes away
+async fn session_closed(finished: &mut Option<CompletePromise<()>>) {
+    match finished {
+        Some(finished) => finished.closed().await,
+        None => future::pending().await,
+    }
+}
+
+/// Resolve the outcome of a finished move, and publish it
+fn finish_move<C: Controller + ?Sized>(
+    controller: &mut C,
+    info: MoveInfo,
+    promise: OutcomePromise,
+    outcome: MoveOutcome,
+) {
+    promise.send(outcome.clone()).unwrap_or(());
+    publish_move(controller, info, outcome);
+}
+
+fn publish_move<C: Controller + ?Sized>(controller: &mut C, info: MoveInfo, outcome: MoveOutcome) {
+    let position = controller.desk().state().0;
+    controller.desk().publish(Event::Move {
+        id: info.id,
+        outcome,
+        target: info.target,
+        position,
+        client: info.client,
+    });
+}
 
 // `in_progress` may contain a mut borrow of controller.
 // `in_progress` must be set to None or passed back to this function before controller is borrowed again
@@ -184,6 +434,7 @@ async unsafe fn process_command<'a, C: Controller + ?Sized + 'a>(
     controller: &mut C,
     inputs: &mut CommandReceiver,
     in_progress: &mut InProgress<'a>,
+    policy: &mut Policy,
 ) {
     let mut self_ptr = SendPtr::new(controller);
     let command = inputs
@@ -201,18 +452,59 @@ async unsafe fn process_command<'a, C: Controller + ?Sized + 'a>(
             let stream = Box::pin(WatchStream::new(controller.desk().state.clone()));
             result.send(Ok(stream)).unwrap_or(());
         }
+        Command::SubscribeEvents { result } => {
+            let stream = controller.desk().events();
+            result.send(Ok(stream)).unwrap_or(());
+        }
         Command::Stop { complete } => {
             // Future must be dropped before borrowing self
-            *in_progress = None;
+            if let Some(task) = in_progress.take() {
+                let (info, promise) = task.abort();
+                finish_move(controller, info, promise, MoveOutcome::Aborted);
+            }
             let result = controller.stop().await;
             complete.send(result).unwrap_or(());
         }
-        Command::MoveTo { target, complete } => {
+        Command::MoveTo {
+            info,
+            lease,
+            complete,
+            finished,
+            outcome,
+        } => {
+            if let Err(e) = policy.check_motion(lease.as_deref()) {
+                complete.send(Err(e)).unwrap_or(());
+                return;
+            }
             // Future must be dropped before borrowing self
-            *in_progress = None;
-            *in_progress = Some(self_ptr.as_mut().move_to(target));
+            if let Some(task) = in_progress.take() {
+                let by = info.client.clone();
+                let (aborted, promise) = task.abort();
+                finish_move(controller, aborted, promise, MoveOutcome::Preempted { by });
+            }
+            publish_move(controller, info.clone(), MoveOutcome::Started);
+            let span = info_span!(parent: &info.span, "move", id = info.id, target = %info.target);
+            *in_progress = Some(Task {
+                future: Box::pin(self_ptr.as_mut().move_to(info.target).instrument(span)),
+                info,
+                finished,
+                outcome,
+            });
             complete.send(Ok(())).unwrap_or(());
         }
+        Command::AcquireControl {
+            holder,
+            duration,
+            renew,
+            result,
+        } => {
+            result
+                .send(policy.acquire(holder, duration, renew))
+                .unwrap_or(());
+        }
+        Command::ReleaseControl { lease, complete } => {
+            complete.send(policy.release(&lease)).unwrap_or(());
+        }
     }
 }
 
@@ -5,7 +5,13 @@ use crate::{
 };
 use async_trait::async_trait;
 use std::time::Duration;
-use tokio::{select, time};
+use tokio::{
+    select,
+    time::{self, Instant},
+};
+
+/// Give up if the position does not change for this long while moving
+const STALL_TIMEOUT: Duration = Duration::from_secs(2);
 
 pub struct OvershootController {
     desk: Desk,
@@ -26,11 +32,21 @@ impl Controller for OvershootController {
     async fn move_up_to(&mut self, target: Position) -> Result<(), ControllerError> {
         let mut interval = time::interval(Duration::from_millis(500));
         let mut position = self.desk.state().0;
+        let mut last_progress = Instant::now();
         while position < target {
             select! {
-                _ = interval.tick() => self.desk.move_up().await?,
+                _ = interval.tick() => {
+                    if last_progress.elapsed() > STALL_TIMEOUT {
+                        self.desk.stop().await?;
+                        return Err(ControllerError::Stalled);
+                    }
+                    self.desk.move_up().await?;
+                }
                 result = self.desk.update() => {
                     let (_position, velocity) = result?;
+                    if _position != position {
+                        last_progress = Instant::now();
+                    }
                     position = _position;
                     if velocity.is_zero() {
                         return Err(ControllerError::Aborted);
@@ -45,11 +61,21 @@ impl Controller for OvershootController {
     async fn move_down_to(&mut self, target: Position) -> Result<(), ControllerError> {
         let mut interval = time::interval(Duration::from_millis(500));
         let mut position = self.desk.state().0;
+        let mut last_progress = Instant::now();
         while position > target {
             select! {
-                _ = interval.tick() => self.desk.move_down().await?,
+                _ = interval.tick() => {
+                    if last_progress.elapsed() > STALL_TIMEOUT {
+                        self.desk.stop().await?;
+                        return Err(ControllerError::Stalled);
+                    }
+                    self.desk.move_down().await?;
+                }
                 result = self.desk.update() => {
                     let (_position, velocity) = result?;
+                    if _position != position {
+                        last_progress = Instant::now();
+                    }
                     position = _position;
                     if velocity.is_zero() {
                         return Err(ControllerError::Aborted);
@@ -0,0 +1,89 @@
+use crate::controllers::ControllerError;
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::info;
+use uuid::Uuid;
+
+/// Leases longer than this are shortened
+pub const MAX_LEASE_DURATION: Duration = Duration::from_secs(60 * 60);
+pub const DEFAULT_LEASE_DURATION: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug)]
+pub struct Lease {
+    pub id: String,
+    pub holder: String,
+    pub expires: Instant,
+}
+
+impl Lease {
+    pub fn remaining(&self) -> Duration {
+        self.expires.saturating_duration_since(Instant::now())
+    }
+}
+
+/**
+ * Decides which commands the controller accepts.
+ * Owned by the controller loop, so every command source is subject to the same rules.
+ */
+#[derive(Default)]
+pub struct Policy {
+    lease: Option<Lease>,
+}
+
+impl Policy {
+    fn active_lease(&mut self) -> Option<&Lease> {
+        if let Some(lease) = &self.lease {
+            if lease.expires <= Instant::now() {
+                info!(holder = %lease.holder, "Control lease expired");
+                self.lease = None;
+            }
+        }
+        self.lease.as_ref()
+    }
+
+    fn held_by_other(&mut self, lease_id: Option<&str>) -> Result<(), ControllerError> {
+        match self.active_lease() {
+            Some(lease) if Some(lease.id.as_str()) != lease_id => Err(ControllerError::Leased {
+                holder: lease.holder.clone(),
+                remaining: lease.remaining(),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Grant a new lease, or renew `renew` if it is the active one
+    pub fn acquire(
+        &mut self,
+        holder: String,
+        duration: Duration,
+        renew: Option<String>,
+    ) -> Result<Lease, ControllerError> {
+        self.held_by_other(renew.as_deref())?;
+        let duration = Ord::min(duration, MAX_LEASE_DURATION);
+        let id = match (renew, &self.lease) {
+            (Some(id), Some(_)) => id,
+            _ => Uuid::new_v4().to_string(),
+        };
+        let lease = Lease {
+            id,
+            holder,
+            expires: Instant::now() + duration,
+        };
+        info!(holder = %lease.holder, ?duration, "Control lease granted");
+        self.lease = Some(lease.clone());
+        Ok(lease)
+    }
+
+    pub fn release(&mut self, lease_id: &str) -> Result<(), ControllerError> {
+        self.held_by_other(Some(lease_id))?;
+        if let Some(lease) = self.lease.take() {
+            info!(holder = %lease.holder, "Control lease released");
+        }
+        Ok(())
+    }
+
+    /// Check whether a motion command is allowed
+    pub fn check_motion(&mut self, lease_id: Option<&str>) -> Result<(), ControllerError> {
+        self.held_by_other(lease_id)
+    }
+}
@@ -0,0 +1,230 @@
+use crate::{
+    events::{Event, EventStream},
+    schedule::Target,
+    service::{Automatic, DeskService, Peer},
+    stats::{Posture, PostureThresholds},
+    utils::{Position, Velocity},
+};
+use desklink_common::rpc::{
+    desk_service_server::DeskService as DeskServiceTrait, StartMoveRequest,
+};
+use futures::StreamExt;
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    select,
+    sync::{watch, Notify},
+    time::{self, Instant},
+};
+use tonic::Request;
+use tracing::{info, warn};
+
+/// Shortest sitting or standing phase
+pub const MIN_PHASE: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug)]
+pub struct CycleSettings {
+    pub sit: Duration,
+    pub stand: Duration,
+    pub sit_target: Target,
+    pub stand_target: Target,
+}
+
+impl CycleSettings {
+    fn phase(&self, posture: Posture) -> (Duration, &Target) {
+        match posture {
+            Posture::Sitting => (self.sit, &self.sit_target),
+            Posture::Standing => (self.stand, &self.stand_target),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CycleStatus {
+    pub settings: CycleSettings,
+    pub posture: Posture,
+    /// Paused while the desk is moved by hand
+    pub paused: bool,
+    /// Time until the next move, the full phase while paused
+    pub remaining: Duration,
+}
+
+struct Running {
+    settings: CycleSettings,
+    posture: Posture,
+    phase_started: Instant,
+    paused: bool,
+}
+
+impl Running {
+    fn deadline(&self) -> Option<Instant> {
+        (!self.paused).then(|| self.phase_started + self.settings.phase(self.posture).0)
+    }
+}
+
+/**
+ * Timer alternating the desk between sitting and standing, through the RPC handlers.
+ * The cycle starts in the current posture. Moving the desk by hand pauses the timer,
+ * which restarts in the new posture once the desk is at rest.
+ */
+pub struct Cycle {
+    running: Mutex<Option<Running>>,
+    thresholds: PostureThresholds,
+    state: watch::Receiver<(Position, Velocity)>,
+    changed: Notify,
+}
+
+impl Cycle {
+    pub fn new(
+        thresholds: PostureThresholds,
+        state: watch::Receiver<(Position, Velocity)>,
+    ) -> Self {
+        Cycle {
+            running: Mutex::new(None),
+            thresholds,
+            state,
+            changed: Notify::new(),
+        }
+    }
+
+    /// Start a cycle, replacing the running one
+    pub fn start(&self, settings: CycleSettings) {
+        let (position, _) = *self.state.borrow();
+        let posture = self.thresholds.classify(position, None);
+        info!(?settings, ?posture, "Starting cycle");
+        *self.running.lock().unwrap() = Some(Running {
+            settings,
+            posture,
+            phase_started: Instant::now(),
+            paused: false,
+        });
+        self.changed.notify_one();
+    }
+
+    /// Whether a cycle was running
+    pub fn stop(&self) -> bool {
+        let stopped = self.running.lock().unwrap().take().is_some();
+        if stopped {
+            info!("Stopping cycle");
+            self.changed.notify_one();
+        }
+        stopped
+    }
+
+    pub fn status(&self) -> Option<CycleStatus> {
+        let running = self.running.lock().unwrap();
+        running.as_ref().map(|running| CycleStatus {
+            settings: running.settings.clone(),
+            posture: running.posture,
+            paused: running.paused,
+            remaining: match running.deadline() {
+                Some(deadline) => deadline.saturating_duration_since(Instant::now()),
+                None => running.settings.phase(running.posture).0,
+            },
+        })
+    }
+
+    /// Drive the running cycle, until the state stream ends
+    pub async fn run(self: Arc<Self>, service: Arc<DeskService>, mut events: EventStream) {
+        let mut states = self.state.clone();
+        loop {
+            let deadline = self
+                .running
+                .lock()
+                .unwrap()
+                .as_ref()
+                .and_then(Running::deadline);
+            select! {
+                _ = time::sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                    self.next_phase(&service).await;
+                }
+                _ = self.changed.notified() => {}
+                Some(event) = events.next() => {
+                    if let Event::ManualMovement { .. } = event {
+                        self.pause();
+                    }
+                }
+                result = states.changed() => {
+                    if result.is_err() {
+                        break;
+                    }
+                    self.resume_at_rest();
+                }
+            }
+        }
+    }
+
+    async fn next_phase(&self, service: &DeskService) {
+        let (posture, target, phase_started) = {
+            let running = self.running.lock().unwrap();
+            let running = match running.as_ref() {
+                Some(running) if !running.paused => running,
+                _ => return,
+            };
+            let posture = match running.posture {
+                Posture::Sitting => Posture::Standing,
+                Posture::Standing => Posture::Sitting,
+            };
+            let target = running.settings.phase(posture).1.clone();
+            (posture, target, running.phase_started)
+        };
+        info!(?posture, "Cycle phase");
+        let message = match target {
+            Target::Position(position) => StartMoveRequest {
+                target: position.to_cm(),
+                ..Default::default()
+            },
+            Target::Preset(preset) => StartMoveRequest {
+                preset,
+                ..Default::default()
+            },
+        };
+        let mut request = Request::new(message);
+        request.extensions_mut().insert(Peer("cycle".to_owned()));
+        request.extensions_mut().insert(Automatic::Cycle);
+        let result = service.start_move(request).await;
+        let (position, _) = *self.state.borrow();
+        let mut running = self.running.lock().unwrap();
+        match running.as_mut() {
+            Some(running) if running.phase_started == phase_started => {
+                running.posture = match result {
+                    Ok(_) => posture,
+                    // the cycle goes on from where the desk is, the next phase may succeed
+                    Err(status) => {
+                        warn!("Cycle move failed: {}", status.message());
+                        self.thresholds.classify(position, Some(running.posture))
+                    }
+                };
+                running.phase_started = Instant::now();
+            }
+            // the cycle was restarted or stopped during the move request
+            _ => {}
+        }
+    }
+
+    fn pause(&self) {
+        if let Some(running) = self.running.lock().unwrap().as_mut() {
+            info!("Cycle paused by manual movement");
+            running.paused = true;
+        }
+        // the desk may already be at rest again
+        self.resume_at_rest();
+    }
+
+    fn resume_at_rest(&self) {
+        let (position, velocity) = *self.state.borrow();
+        if !velocity.is_zero() {
+            return;
+        }
+        if let Some(running) = self.running.lock().unwrap().as_mut() {
+            if running.paused {
+                running.posture = self.thresholds.classify(position, Some(running.posture));
+                running.phase_started = Instant::now();
+                running.paused = false;
+                info!(posture = ?running.posture, "Cycle resumed");
+            }
+        }
+    }
+}
@@ -1,6 +1,9 @@
-use crate::utils::{
-    Position, PositionError, Velocity, COMMAND_DOWN, COMMAND_STOP, COMMAND_UP, UUID_COMMAND,
-    UUID_STATE,
+use crate::{
+    events::{self, ConnectionState, Event, EventSender, EventStream},
+    utils::{
+        Position, PositionError, Velocity, COMMAND_DOWN, COMMAND_STOP, COMMAND_UP, UUID_COMMAND,
+        UUID_STATE,
+    },
 };
 use btleplug::{
     api::{
@@ -9,11 +12,21 @@ use btleplug::{
     },
     platform::{Manager, Peripheral},
 };
-use futures::{Stream, StreamExt};
-use std::pin::Pin;
+use futures::{Future, Stream, StreamExt};
+use prometheus::IntCounter;
+use std::{pin::Pin, time::Duration};
 use thiserror::Error;
-use tokio::sync::watch;
-use tracing::{debug, trace};
+use tokio::{
+    select,
+    sync::watch,
+    time::{self, Instant, Timeout},
+};
+use tracing::{debug, info, info_span, trace, warn, Instrument};
+
+const RECONNECT_BACKOFF_MIN: Duration = Duration::from_secs(1);
+const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(30);
+/// Longest connection attempt, a longer one is taken for a hung bluetooth stack
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
 
 #[derive(Error, Debug)]
 pub enum DeskError {
@@ -31,20 +44,112 @@ pub enum DeskError {
 
     #[error(transparent)]
     InvalidPosition(#[from] PositionError),
+
+    #[error("Desk disconnected")]
+    Disconnected,
+
+    #[error("Timed out connecting to the desk")]
+    ConnectTimeout,
+}
+
+type Notifications = Pin<Box<dyn Stream<Item = ValueNotification> + Send>>;
+/// Notifications, command and state characteristics, and the initial state
+type Connection = (
+    Notifications,
+    Characteristic,
+    Characteristic,
+    (Position, Velocity),
+);
+type ConnectFuture<T> = Pin<Box<dyn Future<Output = Result<T, DeskError>> + Send>>;
+type Attempt<T> = Pin<Box<Timeout<ConnectFuture<T>>>>;
+
+/**
+ * Connection attempts with exponential backoff.
+ * The attempt in progress and the backoff outlive `next`, so cancelling it loses nothing.
+ */
+struct Reconnect<T> {
+    attempt: Option<Attempt<T>>,
+    retry_at: Instant,
+    backoff: Duration,
+    /// An attempt finished since the last `alive` check, successfully or not
+    finished: bool,
+    /// The last attempt timed out
+    stuck: bool,
+}
+
+impl<T> Reconnect<T> {
+    fn new() -> Self {
+        Reconnect {
+            attempt: None,
+            retry_at: Instant::now(),
+            backoff: RECONNECT_BACKOFF_MIN,
+            finished: false,
+            stuck: false,
+        }
+    }
+
+    /// Result of the next attempt, started with `connect` once the backoff elapsed
+    async fn next(&mut self, connect: impl FnOnce() -> ConnectFuture<T>) -> Result<T, DeskError> {
+        if self.attempt.is_none() {
+            time::sleep_until(self.retry_at).await;
+            self.attempt = Some(Box::pin(time::timeout(CONNECT_TIMEOUT, connect())));
+        }
+        let result = self.attempt.as_mut().expect("No attempt").await;
+        self.attempt = None;
+        self.retry_at = Instant::now() + self.backoff;
+        self.backoff = Ord::min(self.backoff * 2, RECONNECT_BACKOFF_MAX);
+        match result {
+            Ok(result) => {
+                self.finished = true;
+                self.stuck = false;
+                if result.is_ok() {
+                    self.retry_at = Instant::now();
+                    self.backoff = RECONNECT_BACKOFF_MIN;
+                }
+                result
+            }
+            Err(_) => {
+                self.stuck = true;
+                Err(DeskError::ConnectTimeout)
+            }
+        }
+    }
+
+    /// Time until the next attempt may start
+    fn retry_in(&self) -> Duration {
+        self.retry_at.saturating_duration_since(Instant::now())
+    }
+
+    /// Whether attempts are not hanging: one finished since the last check,
+    /// or none is running and the last one did not time out
+    fn alive(&mut self) -> bool {
+        std::mem::take(&mut self.finished) || (self.attempt.is_none() && !self.stuck)
+    }
 }
 
 pub struct Desk {
     // bluetooth
+    central_events: Pin<Box<dyn Stream<Item = CentralEvent> + Send>>,
     device: Peripheral,
-    events: Pin<Box<dyn Stream<Item = ValueNotification> + Send>>,
+    notifications: Notifications,
     command_characteristic: Characteristic,
+    state_characteristic: Characteristic,
+    connected: bool,
+    reconnect: Reconnect<Connection>,
+    notification_counter: IntCounter,
     // desk state
     pub state: watch::Receiver<(Position, Velocity)>,
     state_publisher: watch::Sender<(Position, Velocity)>,
+    event_publisher: EventSender,
 }
 
 impl Desk {
-    pub async fn find(address: BDAddr) -> Result<Desk, DeskError> {
+    /// `notification_counter` is incremented on every state notification from the desk
+    pub async fn find(
+        address: BDAddr,
+        event_publisher: EventSender,
+        notification_counter: IntCounter,
+    ) -> Result<Desk, DeskError> {
         // setup local central
         let manager = Manager::new().await?;
         let central = manager
@@ -56,9 +161,9 @@ impl Desk {
         central.start_scan(Default::default()).await?;
 
         // find target peripheral
-        let mut events = central.events().await?;
+        let mut central_events = central.events().await?;
         let id = loop {
-            let event = events.next().await.expect("No more events");
+            let event = central_events.next().await.expect("No more events");
             if let CentralEvent::DeviceDiscovered(id) = event {
                 trace!("Discovered device: {:?}", id);
                 if central.peripheral(&id).await?.address() == address {
@@ -69,6 +174,31 @@ impl Desk {
 
         // setup target connection
         let device = central.peripheral(&id).await?;
+        let (notifications, command_characteristic, state_characteristic, state) =
+            Self::connect(&device).await?;
+        debug!(position = %state.0, velocity = %state.1, "Initial state");
+        let (tx, rx) = watch::channel(state);
+        info!(%address, "Connected to desk");
+        event_publisher
+            .send(Event::Connection(ConnectionState::Connected))
+            .unwrap_or(0);
+
+        Ok(Desk {
+            central_events,
+            device,
+            notifications,
+            command_characteristic,
+            state_characteristic,
+            connected: true,
+            reconnect: Reconnect::new(),
+            notification_counter,
+            state: rx,
+            state_publisher: tx,
+            event_publisher,
+        })
+    }
+
+    async fn connect(device: &Peripheral) -> Result<Connection, DeskError> {
         device.connect().await?;
         device.discover_services().await?;
 
@@ -91,62 +221,118 @@ impl Desk {
 
         // event subscription
         device.subscribe(char_state).await?;
-        let events = device.notifications().await?;
+        let notifications = device.notifications().await?;
 
         // state notification
         let raw_state = device.read(char_state).await?;
-        let (position, velocity) = Self::parse_state(raw_state)?;
-        debug!(%position, %velocity, "Initial state");
-        let (tx, rx) = watch::channel((position, velocity));
+        let state = Self::parse_state(raw_state)?;
+        Ok((
+            notifications,
+            char_command.clone(),
+            char_state.clone(),
+            state,
+        ))
+    }
 
-        Ok(Desk {
-            device,
-            events,
-            command_characteristic: char_command.clone(),
-            state: rx,
-            state_publisher: tx,
-        })
+    /// Reconnect to the desk, retrying with exponential backoff until it succeeds.
+    /// If cancelled, the next call resumes the attempt in progress.
+    async fn reconnect(&mut self) {
+        loop {
+            let device = self.device.clone();
+            let event_publisher = self.event_publisher.clone();
+            let result = self
+                .reconnect
+                .next(move || {
+                    event_publisher
+                        .send(Event::Connection(ConnectionState::Reconnecting))
+                        .unwrap_or(0);
+                    Box::pin(async move { Self::connect(&device).await })
+                })
+                .await;
+            match result {
+                Ok((notifications, command_characteristic, state_characteristic, state)) => {
+                    debug!(position = %state.0, velocity = %state.1, "Reconnected state");
+                    self.notifications = notifications;
+                    self.command_characteristic = command_characteristic;
+                    self.state_characteristic = state_characteristic;
+                    self.connected = true;
+                    self.state_publisher.send_replace(state);
+                    info!("Reconnected to desk");
+                    self.publish(Event::Connection(ConnectionState::Connected));
+                    return;
+                }
+                Err(e) => {
+                    warn!(
+                        "Error reconnecting to desk, retrying in {:?}: {}",
+                        self.reconnect.retry_in(),
+                        e
+                    );
+                }
+            }
+        }
+    }
+
+    fn disconnected(&mut self) -> DeskError {
+        if self.connected {
+            warn!("Desk disconnected");
+            self.connected = false;
+            self.publish(Event::Connection(ConnectionState::Disconnected));
+        }
+        DeskError::Disconnected
     }
 
     pub async fn move_up(&mut self) -> Result<(), DeskError> {
-        trace!("Sending bluetooth command: up");
-        self.device
-            .write(
-                &self.command_characteristic,
-                &COMMAND_UP,
-                WriteType::WithoutResponse,
-            )
-            .await?;
-        Ok(())
+        self.write_command("up", &COMMAND_UP).await
     }
 
     pub async fn move_down(&mut self) -> Result<(), DeskError> {
-        trace!("Sending bluetooth command: down");
-        self.device
-            .write(
-                &self.command_characteristic,
-                &COMMAND_DOWN,
-                WriteType::WithoutResponse,
-            )
-            .await?;
-        Ok(())
+        self.write_command("down", &COMMAND_DOWN).await
     }
 
     pub async fn stop(&mut self) -> Result<(), DeskError> {
-        trace!("Sending bluetooth command: stop");
+        self.write_command("stop", &COMMAND_STOP).await
+    }
+
+    async fn write_command(&mut self, name: &str, command: &[u8]) -> Result<(), DeskError> {
+        trace!("Sending bluetooth command: {}", name);
+        if !self.connected {
+            return Err(DeskError::Disconnected);
+        }
         self.device
             .write(
                 &self.command_characteristic,
-                &COMMAND_STOP,
+                command,
                 WriteType::WithoutResponse,
             )
+            .instrument(info_span!("ble_write", command = name))
             .await?;
         Ok(())
     }
 
+    /// Wait for the next state update.
+    /// Returns `DeskError::Disconnected` once when the connection is lost,
+    /// and reconnects on the next call.
     pub async fn update(&mut self) -> Result<(Position, Velocity), DeskError> {
-        let event = self.events.next().await.expect("No more events");
+        if !self.connected {
+            self.reconnect().await;
+        }
+        let event = loop {
+            select! {
+                event = self.notifications.next() => match event {
+                    Some(event) => break event,
+                    None => return Err(self.disconnected()),
+                },
+                event = self.central_events.next() => match event {
+                    Some(CentralEvent::DeviceDisconnected(id)) if id == self.device.id() => {
+                        return Err(self.disconnected());
+                    }
+                    Some(_) => {}
+                    None => return Err(self.disconnected()),
+                },
+            }
+        };
         assert!(event.uuid.hyphenated().to_string() == UUID_STATE);
+        self.notification_counter.inc();
         let raw_state = event.value;
         let (position, velocity) = Self::parse_state(raw_state)?;
         debug!(%position, %velocity, "Updated state");
@@ -154,10 +340,42 @@ impl Desk {
         Ok((position, velocity))
     }
 
+    /// Read the state from the desk, to check that the bluetooth stack still responds
+    pub async fn probe(&mut self) -> Result<(Position, Velocity), DeskError> {
+        if !self.connected {
+            return Err(DeskError::Disconnected);
+        }
+        let raw_state = self
+            .device
+            .read(&self.state_characteristic)
+            .instrument(info_span!("ble_read"))
+            .await?;
+        Self::parse_state(raw_state)
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    /// Whether reconnecting makes progress, see `Reconnect::alive`
+    pub fn reconnect_alive(&mut self) -> bool {
+        self.reconnect.alive()
+    }
+
     pub fn state(&self) -> (Position, Velocity) {
         *self.state.borrow()
     }
 
+    pub fn publish(&self, event: Event) {
+        trace!(?event, "Publishing event");
+        // no subscribers is not an error
+        self.event_publisher.send(event).unwrap_or(0);
+    }
+
+    pub fn events(&self) -> EventStream {
+        events::subscribe(&self.event_publisher)
+    }
+
     fn parse_state(raw_state: Vec<u8>) -> Result<(Position, Velocity), DeskError> {
         assert!(raw_state.len() == 4);
         let raw_position: [u8; 2] = raw_state[0..2].try_into().unwrap();
@@ -167,3 +385,96 @@ impl Desk {
         Ok((position, velocity))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::systemd::{tests::FakeNotifySocket, Watchdog};
+    use futures::future;
+    use std::{
+        env,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    static HUNG_ATTEMPTS: AtomicUsize = AtomicUsize::new(0);
+
+    fn hang() -> ConnectFuture<()> {
+        HUNG_ATTEMPTS.fetch_add(1, Ordering::SeqCst);
+        Box::pin(future::pending())
+    }
+
+    fn fail() -> ConnectFuture<()> {
+        Box::pin(future::ready(Err(DeskError::Disconnected)))
+    }
+
+    /// Reconnect like the idle controller loop, which is cancelled when the watchdog is due.
+    /// Returns whether it pinged.
+    async fn tick(
+        reconnect: &mut Reconnect<()>,
+        watchdog: &mut Watchdog,
+        connect: fn() -> ConnectFuture<()>,
+    ) -> bool {
+        loop {
+            select! {
+                _ = reconnect.next(connect) => {}
+                _ = watchdog.due() => {
+                    let alive = reconnect.alive();
+                    if alive {
+                        watchdog.ping();
+                    }
+                    return alive;
+                }
+            }
+        }
+    }
+
+    fn watchdog(systemd: &FakeNotifySocket) -> Watchdog {
+        env::set_var("WATCHDOG_USEC", "200000");
+        env::set_var("WATCHDOG_PID", std::process::id().to_string());
+        let watchdog = Watchdog::from_env();
+        assert_eq!(systemd.recv(), None);
+        watchdog
+    }
+
+    #[tokio::test]
+    async fn watchdog_stops_on_hung_connect() {
+        let systemd = FakeNotifySocket::bind("hung-connect");
+        let mut watchdog = watchdog(&systemd);
+        // the first tick is immediate
+        watchdog.due().await;
+        let mut reconnect = Reconnect::new();
+        for _ in 0..3 {
+            assert!(!tick(&mut reconnect, &mut watchdog, hang).await);
+        }
+        assert_eq!(systemd.recv(), None);
+        // the attempt is resumed, not restarted, after each cancellation
+        assert_eq!(HUNG_ATTEMPTS.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn watchdog_pings_on_failing_connect() {
+        let systemd = FakeNotifySocket::bind("failing-connect");
+        let mut watchdog = watchdog(&systemd);
+        watchdog.due().await;
+        let mut reconnect = Reconnect::new();
+        // a failed attempt, then the backoff
+        for _ in 0..2 {
+            assert!(tick(&mut reconnect, &mut watchdog, fail).await);
+            assert_eq!(systemd.recv().as_deref(), Some("WATCHDOG=1\n"));
+        }
+    }
+
+    #[tokio::test]
+    async fn reconnect_backs_off() {
+        let mut reconnect = Reconnect::<()>::new();
+        assert!(reconnect.next(fail).await.is_err());
+        assert!(reconnect.retry_in() > Duration::ZERO);
+        assert_eq!(reconnect.backoff, RECONNECT_BACKOFF_MIN * 2);
+        assert!(reconnect
+            .next(|| Box::pin(future::ready(Ok(()))))
+            .await
+            .is_ok());
+        assert_eq!(reconnect.backoff, RECONNECT_BACKOFF_MIN);
+        assert_eq!(reconnect.retry_in(), Duration::ZERO);
+    }
+}
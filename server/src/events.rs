@@ -0,0 +1,74 @@
+use crate::utils::Position;
+use futures::{Stream, StreamExt};
+use std::{pin::Pin, time::Duration};
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
+use tracing::warn;
+
+pub type EventSender = broadcast::Sender<Event>;
+pub type EventStream = Pin<Box<dyn Stream<Item = Event> + Send>>;
+
+/// Number of events buffered for each subscriber before it starts lagging behind
+pub const EVENT_BUFFER: usize = 64;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    Connected,
+    Disconnected,
+    Reconnecting,
+}
+
+#[derive(Clone, Debug)]
+pub enum MoveOutcome {
+    Started,
+    Reached,
+    Aborted,
+    Stalled,
+    Preempted { by: String },
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum GoalStatus {
+    Reached,
+    /// The day is nearly over and the goal is still far off
+    Behind,
+}
+
+#[derive(Clone, Debug)]
+pub enum Event {
+    Connection(ConnectionState),
+    Move {
+        id: u64,
+        outcome: MoveOutcome,
+        target: Position,
+        position: Position,
+        client: String,
+    },
+    ManualMovement {
+        position: Position,
+    },
+    ConfigReloaded,
+    Goal {
+        status: GoalStatus,
+        /// Standing time today
+        standing: Duration,
+        goal: Duration,
+    },
+}
+
+pub fn channel() -> EventSender {
+    broadcast::channel(EVENT_BUFFER).0
+}
+
+pub fn subscribe(publisher: &EventSender) -> EventStream {
+    let stream = BroadcastStream::new(publisher.subscribe());
+    Box::pin(stream.filter_map(|event| async move {
+        match event {
+            Ok(event) => Some(event),
+            Err(BroadcastStreamRecvError::Lagged(n)) => {
+                warn!("Event subscriber lagged behind, {} events dropped", n);
+                None
+            }
+        }
+    }))
+}
@@ -0,0 +1,100 @@
+use crate::{
+    config::GoalConfig,
+    events::{Event, EventSender, GoalStatus},
+    history::{self, History, HistoryError},
+};
+use chrono::{Local, NaiveDateTime};
+use desklink_common::day_start_ms;
+use std::{sync::Arc, time::Duration};
+use tokio::time::{self, MissedTickBehavior};
+use tracing::{info, warn};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+/// Share of the goal below which the day is behind at the check time
+const BEHIND_RATIO: f64 = 0.5;
+
+#[derive(Copy, Clone, Debug)]
+pub struct GoalProgress {
+    pub goal: Duration,
+    /// Standing time today
+    pub standing: Duration,
+    /// Unix time in milliseconds of the local midnight
+    pub day_start_ms: i64,
+}
+
+/**
+ * Daily standing goal, with the progress computed from the history.
+ * Reaching the goal and still being far off at the check time are published as events.
+ */
+pub struct Goal {
+    config: GoalConfig,
+    history: Arc<History>,
+    event_publisher: EventSender,
+}
+
+impl Goal {
+    pub fn new(config: GoalConfig, history: Arc<History>, event_publisher: EventSender) -> Self {
+        Goal {
+            config,
+            history,
+            event_publisher,
+        }
+    }
+
+    pub async fn progress(&self) -> Result<GoalProgress, HistoryError> {
+        let day_start_ms = day_start_ms(Local::now().date_naive());
+        let stats = self.history.usage(day_start_ms..history::now_ms()).await?;
+        Ok(GoalProgress {
+            goal: self.config.standing,
+            standing: Duration::from_millis(stats.standing_ms),
+            day_start_ms,
+        })
+    }
+
+    /// Check the progress every minute and publish goal events
+    pub async fn run(self: Arc<Self>) {
+        let mut checks = time::interval(CHECK_INTERVAL);
+        checks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        // day of the last reached goal, one reached before a restart is not announced again
+        let mut reached_day = None;
+        let mut first_check = true;
+        let mut last_check = Local::now().naive_local();
+        loop {
+            checks.tick().await;
+            let progress = match self.progress().await {
+                Ok(progress) => progress,
+                Err(e) => {
+                    warn!("Error computing goal progress: {}", e);
+                    continue;
+                }
+            };
+            let now = Local::now().naive_local();
+            if progress.standing >= progress.goal && reached_day != Some(progress.day_start_ms) {
+                reached_day = Some(progress.day_start_ms);
+                if !first_check {
+                    self.publish(GoalStatus::Reached, &progress);
+                }
+            }
+            let behind_at = NaiveDateTime::new(now.date(), self.config.behind_at);
+            if last_check < behind_at
+                && behind_at <= now
+                && progress.standing.as_secs_f64() < progress.goal.as_secs_f64() * BEHIND_RATIO
+            {
+                self.publish(GoalStatus::Behind, &progress);
+            }
+            last_check = now;
+            first_check = false;
+        }
+    }
+
+    fn publish(&self, status: GoalStatus, progress: &GoalProgress) {
+        info!(?status, standing = ?progress.standing, goal = ?progress.goal, "Standing goal");
+        self.event_publisher
+            .send(Event::Goal {
+                status,
+                standing: progress.standing,
+                goal: progress.goal,
+            })
+            .unwrap_or(0);
+    }
+}
@@ -0,0 +1,455 @@
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::{ready, Future};
+use http::{
+    header::{self, HeaderName, HeaderValue},
+    HeaderMap, Method, Request, Response, StatusCode,
+};
+use http_body::{Body, LengthLimitError, Limited, SizeHint};
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tonic::Code;
+use tower::{Layer, Service};
+use tower_http::cors::{AllowOrigin, Any, Cors, CorsLayer};
+use tracing::debug;
+
+const GRPC: &str = "application/grpc";
+const GRPC_WEB: &str = "application/grpc-web";
+const GRPC_WEB_TEXT: &str = "application/grpc-web-text";
+
+/// Flag of the frame carrying the trailers at the end of a gRPC-Web response
+const TRAILERS_FLAG: u8 = 0x80;
+/// Largest base64 request, which is decoded in memory
+const MAX_TEXT_REQUEST_SIZE: usize = 4 * 1024 * 1024;
+const CORS_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Encoding {
+    Binary,
+    /// Base64, used by browsers that cannot read binary streams
+    Text,
+}
+
+impl Encoding {
+    fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let content_type = headers.get(header::CONTENT_TYPE)?.to_str().ok()?;
+        if content_type.starts_with(GRPC_WEB_TEXT) {
+            Some(Encoding::Text)
+        } else if content_type.starts_with(GRPC_WEB) {
+            Some(Encoding::Binary)
+        } else {
+            None
+        }
+    }
+
+    fn content_type(self) -> HeaderValue {
+        HeaderValue::from_static(match self {
+            Encoding::Binary => "application/grpc-web+proto",
+            Encoding::Text => "application/grpc-web-text+proto",
+        })
+    }
+
+    fn encode(self, data: Bytes) -> Bytes {
+        match self {
+            Encoding::Binary => data,
+            Encoding::Text => Bytes::from(base64::encode(data)),
+        }
+    }
+}
+
+/**
+ * Translates gRPC-Web requests to gRPC for the tonic routes, and answers CORS requests.
+ * Other requests pass through unchanged, as do all requests if gRPC-Web is disabled.
+ */
+#[derive(Clone)]
+pub struct GrpcWebLayer {
+    enabled: bool,
+    cors: CorsLayer,
+}
+
+impl GrpcWebLayer {
+    /// `*` in `cors_origins` allows any origin
+    pub fn new(enabled: bool, cors_origins: &[HeaderValue]) -> Self {
+        let cors = if !enabled || cors_origins.is_empty() {
+            CorsLayer::new()
+        } else {
+            let origins = if cors_origins.iter().any(|origin| origin == "*") {
+                AllowOrigin::any()
+            } else {
+                AllowOrigin::list(cors_origins.iter().cloned())
+            };
+            CorsLayer::new()
+                .allow_origin(origins)
+                .allow_methods([Method::POST])
+                .allow_headers(Any)
+                .expose_headers([
+                    HeaderName::from_static("grpc-status"),
+                    HeaderName::from_static("grpc-message"),
+                    HeaderName::from_static("grpc-status-details-bin"),
+                ])
+                .max_age(CORS_MAX_AGE)
+        };
+        GrpcWebLayer { enabled, cors }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+}
+
+impl<S> Layer<S> for GrpcWebLayer {
+    type Service = Cors<GrpcWeb<S>>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        self.cors.layer(GrpcWeb {
+            inner,
+            enabled: self.enabled,
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct GrpcWeb<S> {
+    inner: S,
+    enabled: bool,
+}
+
+impl<S, B> Service<Request<hyper::Body>> for GrpcWeb<S>
+where
+    S: Service<Request<hyper::Body>, Response = Response<B>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    B: Body<Data = Bytes> + Unpin,
+{
+    type Response = Response<GrpcWebBody<B>>;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<hyper::Body>) -> Self::Future {
+        // keep the service that was driven to readiness
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let encoding = if self.enabled {
+            Encoding::from_headers(request.headers())
+        } else {
+            None
+        };
+        Box::pin(async move {
+            let encoding = match encoding {
+                Some(encoding) => encoding,
+                None => {
+                    let response = inner.call(request).await?;
+                    return Ok(response.map(GrpcWebBody::passthrough));
+                }
+            };
+            debug!(?encoding, "gRPC-Web request");
+            let (mut parts, body) = request.into_parts();
+            let body = match encoding {
+                Encoding::Binary => body,
+                Encoding::Text => match decode_text(body).await {
+                    Ok(body) => body,
+                    Err(status) => {
+                        let mut response = Response::new(GrpcWebBody::default());
+                        *response.status_mut() = status;
+                        if status == StatusCode::PAYLOAD_TOO_LARGE {
+                            response.headers_mut().insert(
+                                "grpc-status",
+                                HeaderValue::from(Code::ResourceExhausted as i32),
+                            );
+                        }
+                        return Ok(response);
+                    }
+                },
+            };
+            parts
+                .headers
+                .insert(header::CONTENT_TYPE, HeaderValue::from_static(GRPC));
+            parts.headers.remove(header::CONTENT_LENGTH);
+
+            let response = inner.call(Request::from_parts(parts, body)).await?;
+            let (mut parts, body) = response.into_parts();
+            let is_grpc = parts
+                .headers
+                .get(header::CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok())
+                .is_some_and(|value| value.starts_with(GRPC));
+            if is_grpc {
+                parts
+                    .headers
+                    .insert(header::CONTENT_TYPE, encoding.content_type());
+            }
+            Ok(Response::from_parts(
+                parts,
+                GrpcWebBody::new(body, encoding),
+            ))
+        })
+    }
+}
+
+/// gRPC-Web has no client streaming, so the whole request can be decoded at once
+async fn decode_text(body: hyper::Body) -> Result<hyper::Body, StatusCode> {
+    let text = hyper::body::to_bytes(Limited::new(body, MAX_TEXT_REQUEST_SIZE))
+        .await
+        .map_err(|e| {
+            if e.is::<LengthLimitError>() {
+                StatusCode::PAYLOAD_TOO_LARGE
+            } else {
+                StatusCode::BAD_REQUEST
+            }
+        })?;
+    let text: Vec<u8> = text
+        .into_iter()
+        .filter(|c| !c.is_ascii_whitespace())
+        .collect();
+    base64::decode(text)
+        .map(hyper::Body::from)
+        .map_err(|_| StatusCode::BAD_REQUEST)
+}
+
+/// Response body with the trailers moved into the last frame
+pub struct GrpcWebBody<B> {
+    inner: Option<B>,
+    encoding: Option<Encoding>,
+    finished: bool,
+}
+
+impl<B> Default for GrpcWebBody<B> {
+    fn default() -> Self {
+        GrpcWebBody {
+            inner: None,
+            encoding: None,
+            finished: true,
+        }
+    }
+}
+
+impl<B> GrpcWebBody<B> {
+    fn new(inner: B, encoding: Encoding) -> Self {
+        GrpcWebBody {
+            inner: Some(inner),
+            encoding: Some(encoding),
+            finished: false,
+        }
+    }
+
+    fn passthrough(inner: B) -> Self {
+        GrpcWebBody {
+            inner: Some(inner),
+            encoding: None,
+            finished: false,
+        }
+    }
+}
+
+fn trailers_frame(trailers: &HeaderMap) -> Bytes {
+    let mut block = BytesMut::new();
+    for (name, value) in trailers {
+        block.put_slice(name.as_str().as_bytes());
+        block.put_slice(b": ");
+        block.put_slice(value.as_bytes());
+        block.put_slice(b"\r\n");
+    }
+    let mut frame = BytesMut::with_capacity(block.len() + 5);
+    frame.put_u8(TRAILERS_FLAG);
+    frame.put_u32(block.len() as u32);
+    frame.put(block);
+    frame.freeze()
+}
+
+impl<B> Body for GrpcWebBody<B>
+where
+    B: Body<Data = Bytes> + Unpin,
+{
+    type Data = Bytes;
+    type Error = B::Error;
+
+    fn poll_data(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        let this = self.get_mut();
+        let inner = match &mut this.inner {
+            Some(inner) if !this.finished => inner,
+            _ => return Poll::Ready(None),
+        };
+        let encoding = match this.encoding {
+            Some(encoding) => encoding,
+            None => return Pin::new(inner).poll_data(cx),
+        };
+        match ready!(Pin::new(&mut *inner).poll_data(cx)) {
+            Some(Ok(data)) => Poll::Ready(Some(Ok(encoding.encode(data)))),
+            Some(Err(e)) => Poll::Ready(Some(Err(e))),
+            None => {
+                let trailers = ready!(Pin::new(inner).poll_trailers(cx))?;
+                this.finished = true;
+                // trailers-only responses carry the status in the headers
+                match trailers {
+                    Some(trailers) if !trailers.is_empty() => {
+                        Poll::Ready(Some(Ok(encoding.encode(trailers_frame(&trailers)))))
+                    }
+                    _ => Poll::Ready(None),
+                }
+            }
+        }
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        let this = self.get_mut();
+        match (&mut this.inner, this.encoding) {
+            (Some(inner), None) => Pin::new(inner).poll_trailers(cx),
+            _ => Poll::Ready(Ok(None)),
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        match (&self.inner, self.encoding) {
+            (Some(inner), None) => inner.is_end_stream(),
+            (Some(_), Some(_)) => self.finished,
+            (None, _) => true,
+        }
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        match (&self.inner, self.encoding) {
+            (Some(inner), None) => inner.size_hint(),
+            (Some(_), Some(_)) => SizeHint::default(),
+            (None, _) => SizeHint::with_exact(0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{collections::VecDeque, convert::Infallible};
+
+    /// Body with the given frames and trailers
+    struct TestBody {
+        data: VecDeque<Bytes>,
+        trailers: Option<HeaderMap>,
+    }
+
+    impl TestBody {
+        fn new(data: &[&'static [u8]], trailers: Option<HeaderMap>) -> Self {
+            TestBody {
+                data: data.iter().copied().map(Bytes::from_static).collect(),
+                trailers,
+            }
+        }
+    }
+
+    impl Body for TestBody {
+        type Data = Bytes;
+        type Error = Infallible;
+
+        fn poll_data(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+            Poll::Ready(self.get_mut().data.pop_front().map(Ok))
+        }
+
+        fn poll_trailers(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+            Poll::Ready(Ok(self.get_mut().trailers.take()))
+        }
+
+        fn is_end_stream(&self) -> bool {
+            self.data.is_empty() && self.trailers.is_none()
+        }
+    }
+
+    fn trailers(status: &'static str) -> HeaderMap {
+        let mut trailers = HeaderMap::new();
+        trailers.insert("grpc-status", HeaderValue::from_static(status));
+        trailers
+    }
+
+    async fn collect<B: Body<Data = Bytes> + Unpin>(mut body: B) -> (Vec<u8>, Option<HeaderMap>)
+    where
+        B::Error: std::fmt::Debug,
+    {
+        let mut data = Vec::new();
+        while let Some(chunk) = body.data().await {
+            data.extend_from_slice(&chunk.unwrap());
+        }
+        let trailers = body.trailers().await.unwrap();
+        assert!(body.is_end_stream());
+        (data, trailers)
+    }
+
+    #[test]
+    fn trailers_frame_layout() {
+        let mut trailers = trailers("3");
+        trailers.insert("grpc-message", HeaderValue::from_static("bad"));
+        let frame = trailers_frame(&trailers);
+        let block = b"grpc-status: 3\r\ngrpc-message: bad\r\n";
+        assert_eq!(frame[0], TRAILERS_FLAG);
+        assert_eq!(frame[1..5], (block.len() as u32).to_be_bytes());
+        assert_eq!(&frame[5..], block);
+    }
+
+    #[tokio::test]
+    async fn trailers_in_last_frame() {
+        let body = TestBody::new(&[b"one", b"two"], Some(trailers("0")));
+        let (data, trailers) = collect(GrpcWebBody::new(body, Encoding::Binary)).await;
+        let mut expected = b"onetwo".to_vec();
+        expected.extend_from_slice(&trailers_frame(&self::trailers("0")));
+        assert_eq!(data, expected);
+        assert_eq!(trailers, None);
+    }
+
+    #[tokio::test]
+    async fn trailers_only_response() {
+        for trailers in [None, Some(HeaderMap::new())] {
+            let body = TestBody::new(&[], trailers);
+            let (data, trailers) = collect(GrpcWebBody::new(body, Encoding::Binary)).await;
+            assert!(data.is_empty());
+            assert_eq!(trailers, None);
+        }
+    }
+
+    #[tokio::test]
+    async fn text_frames_encoded_separately() {
+        let body = TestBody::new(&[b"message"], Some(trailers("0")));
+        let (data, _) = collect(GrpcWebBody::new(body, Encoding::Text)).await;
+        let mut expected = base64::encode(b"message");
+        expected.push_str(&base64::encode(trailers_frame(&trailers("0"))));
+        assert_eq!(String::from_utf8(data).unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn passthrough_keeps_trailers() {
+        let body = TestBody::new(&[b"one", b"two"], Some(trailers("0")));
+        let (data, trailers) = collect(GrpcWebBody::passthrough(body)).await;
+        assert_eq!(data, b"onetwo");
+        assert_eq!(trailers, Some(self::trailers("0")));
+    }
+
+    #[tokio::test]
+    async fn text_request_decoding() {
+        let body = decode_text(hyper::Body::from("bWVz\r\nc2FnZQ=="))
+            .await
+            .unwrap();
+        assert_eq!(hyper::body::to_bytes(body).await.unwrap(), "message");
+        assert_eq!(
+            decode_text(hyper::Body::from("not base64!")).await.err(),
+            Some(StatusCode::BAD_REQUEST)
+        );
+        let large = vec![b'A'; MAX_TEXT_REQUEST_SIZE + 1];
+        assert_eq!(
+            decode_text(hyper::Body::from(large)).await.err(),
+            Some(StatusCode::PAYLOAD_TOO_LARGE)
+        );
+    }
+}
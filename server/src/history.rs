@@ -0,0 +1,477 @@
+use crate::{
+    config::HistoryConfig,
+    events::{ConnectionState, Event, EventStream},
+    stats::{PostureThresholds, UsageAccumulator, UsageStats},
+    utils::{Position, Velocity},
+};
+use futures::{Stream, StreamExt};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::{
+    io,
+    ops::Range,
+    path::PathBuf,
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use thiserror::Error;
+use tokio::{
+    select, task,
+    time::{self, MissedTickBehavior},
+};
+use tracing::{debug, warn};
+
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// Rest samples missed before the desk state is considered unknown
+const MISSED_REST_SAMPLES: u32 = 3;
+
+const SCHEMA: &str = "
+    PRAGMA journal_mode = WAL;
+    PRAGMA synchronous = NORMAL;
+    CREATE TABLE IF NOT EXISTS samples (
+        timestamp_ms INTEGER NOT NULL,
+        position REAL NOT NULL,
+        velocity REAL NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS samples_timestamp ON samples (timestamp_ms);
+    CREATE TABLE IF NOT EXISTS moves (
+        start_ms INTEGER NOT NULL,
+        end_ms INTEGER NOT NULL,
+        start_position REAL NOT NULL,
+        end_position REAL NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS moves_start ON moves (start_ms);
+";
+
+#[derive(Error, Debug)]
+pub enum HistoryError {
+    #[error("IO error: `{path}`")]
+    IoError {
+        path: PathBuf,
+        #[source]
+        error: io::Error,
+    },
+
+    #[error("History database error")]
+    DatabaseError(#[from] rusqlite::Error),
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Sample {
+    /// Unix time in milliseconds
+    pub timestamp_ms: i64,
+    pub position: f32,
+    pub velocity: f32,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct MoveSegment {
+    /// Unix time in milliseconds
+    pub start_ms: i64,
+    pub end_ms: i64,
+    pub start_position: f32,
+    pub end_position: f32,
+}
+
+/// Key of the last entry of a page, the next page starts after it
+#[derive(Copy, Clone, Debug)]
+pub struct PageKey {
+    timestamp_ms: i64,
+    rowid: i64,
+}
+
+impl PageKey {
+    /// Key before the first entry at `timestamp_ms`
+    pub fn before(timestamp_ms: i64) -> Self {
+        PageKey {
+            timestamp_ms,
+            rowid: i64::MIN,
+        }
+    }
+}
+
+/// Write applied in order by the writer thread
+enum Write {
+    Sample {
+        timestamp_ms: i64,
+        position: Position,
+        velocity: Velocity,
+    },
+    Move(MoveSegment),
+    Prune {
+        before_ms: i64,
+    },
+}
+
+impl Write {
+    fn apply(self, db: &Connection) -> Result<(), HistoryError> {
+        match self {
+            Write::Sample {
+                timestamp_ms,
+                position,
+                velocity,
+            } => {
+                db.prepare_cached(
+                    "INSERT INTO samples (timestamp_ms, position, velocity) VALUES (?1, ?2, ?3)",
+                )?
+                .execute(params![
+                    timestamp_ms,
+                    position.to_cm(),
+                    velocity.to_cm_per_s()
+                ])?;
+            }
+            Write::Move(segment) => {
+                db.prepare_cached(
+                    "INSERT INTO moves (start_ms, end_ms, start_position, end_position)
+                     VALUES (?1, ?2, ?3, ?4)",
+                )?
+                .execute(params![
+                    segment.start_ms,
+                    segment.end_ms,
+                    segment.start_position,
+                    segment.end_position
+                ])?;
+            }
+            Write::Prune { before_ms } => {
+                let samples =
+                    db.execute("DELETE FROM samples WHERE timestamp_ms < ?1", [before_ms])?;
+                let moves = db.execute("DELETE FROM moves WHERE end_ms < ?1", [before_ms])?;
+                debug!(samples, moves, "Pruned history");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Current Unix time in milliseconds
+pub fn now_ms() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as i64)
+        .unwrap_or(0)
+}
+
+/**
+ * Desk state over time, stored in SQLite.
+ * Every update is stored while the desk is moving, at rest only one sample per interval.
+ * Move segments are derived from the transitions between moving and resting.
+ * SQLite blocks, so queries run on blocking threads and writes on a writer thread.
+ */
+pub struct History {
+    db: Arc<Mutex<Connection>>,
+    config: HistoryConfig,
+    thresholds: PostureThresholds,
+}
+
+impl History {
+    pub fn open(
+        path: PathBuf,
+        config: HistoryConfig,
+        thresholds: PostureThresholds,
+    ) -> Result<Self, HistoryError> {
+        if let Some(directory) = path.parent() {
+            std::fs::create_dir_all(directory).map_err(|error| HistoryError::IoError {
+                path: path.clone(),
+                error,
+            })?;
+        }
+        let db = Connection::open(&path)?;
+        db.execute_batch(SCHEMA)?;
+        Ok(History {
+            db: Arc::new(Mutex::new(db)),
+            config,
+            thresholds,
+        })
+    }
+
+    /// Samples in the range, oldest first, and whether there are more than `limit`
+    pub async fn samples(
+        &self,
+        range: Range<i64>,
+        limit: usize,
+    ) -> Result<(Vec<Sample>, bool), HistoryError> {
+        self.query(move |db| {
+            let mut statement = db.prepare_cached(
+                "SELECT timestamp_ms, position, velocity FROM samples
+                 WHERE timestamp_ms >= ?1 AND timestamp_ms < ?2
+                 ORDER BY timestamp_ms LIMIT ?3",
+            )?;
+            let samples = statement
+                .query_map(params![range.start, range.end, limit as i64 + 1], |row| {
+                    Ok(Sample {
+                        timestamp_ms: row.get(0)?,
+                        position: row.get(1)?,
+                        velocity: row.get(2)?,
+                    })
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(truncate(samples, limit))
+        })
+        .await
+    }
+
+    /// Moves started in the range, oldest first, and whether there are more than `limit`
+    pub async fn moves(
+        &self,
+        range: Range<i64>,
+        limit: usize,
+    ) -> Result<(Vec<MoveSegment>, bool), HistoryError> {
+        self.query(move |db| {
+            let mut statement = db.prepare_cached(
+                "SELECT start_ms, end_ms, start_position, end_position FROM moves
+                 WHERE start_ms >= ?1 AND start_ms < ?2
+                 ORDER BY start_ms LIMIT ?3",
+            )?;
+            let moves = statement
+                .query_map(params![range.start, range.end, limit as i64 + 1], |row| {
+                    Ok(MoveSegment {
+                        start_ms: row.get(0)?,
+                        end_ms: row.get(1)?,
+                        start_position: row.get(2)?,
+                        end_position: row.get(3)?,
+                    })
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(truncate(moves, limit))
+        })
+        .await
+    }
+
+    /// Samples after `after` and before `end_ms`, oldest first,
+    /// and the key of the last one if there are more
+    pub async fn sample_page(
+        &self,
+        after: PageKey,
+        end_ms: i64,
+        limit: usize,
+    ) -> Result<(Vec<Sample>, Option<PageKey>), HistoryError> {
+        self.query(move |db| {
+            let mut statement = db.prepare_cached(
+                "SELECT timestamp_ms, position, velocity, rowid FROM samples
+                 WHERE (timestamp_ms, rowid) > (?1, ?2) AND timestamp_ms < ?3
+                 ORDER BY timestamp_ms, rowid LIMIT ?4",
+            )?;
+            let samples = statement
+                .query_map(
+                    params![after.timestamp_ms, after.rowid, end_ms, limit as i64 + 1],
+                    |row| {
+                        let sample = Sample {
+                            timestamp_ms: row.get(0)?,
+                            position: row.get(1)?,
+                            velocity: row.get(2)?,
+                        };
+                        let key = PageKey {
+                            timestamp_ms: sample.timestamp_ms,
+                            rowid: row.get(3)?,
+                        };
+                        Ok((sample, key))
+                    },
+                )?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(page(samples, limit))
+        })
+        .await
+    }
+
+    /// Moves started after `after` and before `end_ms`, oldest first,
+    /// and the key of the last one if there are more
+    pub async fn move_page(
+        &self,
+        after: PageKey,
+        end_ms: i64,
+        limit: usize,
+    ) -> Result<(Vec<MoveSegment>, Option<PageKey>), HistoryError> {
+        self.query(move |db| {
+            let mut statement = db.prepare_cached(
+                "SELECT start_ms, end_ms, start_position, end_position, rowid FROM moves
+                 WHERE (start_ms, rowid) > (?1, ?2) AND start_ms < ?3
+                 ORDER BY start_ms, rowid LIMIT ?4",
+            )?;
+            let moves = statement
+                .query_map(
+                    params![after.timestamp_ms, after.rowid, end_ms, limit as i64 + 1],
+                    |row| {
+                        let segment = MoveSegment {
+                            start_ms: row.get(0)?,
+                            end_ms: row.get(1)?,
+                            start_position: row.get(2)?,
+                            end_position: row.get(3)?,
+                        };
+                        let key = PageKey {
+                            timestamp_ms: segment.start_ms,
+                            rowid: row.get(4)?,
+                        };
+                        Ok((segment, key))
+                    },
+                )?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(page(moves, limit))
+        })
+        .await
+    }
+
+    pub fn thresholds(&self) -> PostureThresholds {
+        self.thresholds
+    }
+
+    /// Time sitting and standing in the range
+    pub async fn usage(&self, range: Range<i64>) -> Result<UsageStats, HistoryError> {
+        let thresholds = self.thresholds;
+        let max_gap = self.max_gap();
+        self.query(move |db| usage(db, range, thresholds, max_gap))
+            .await
+    }
+
+    /// Longest gap between samples before the desk state is unknown
+    fn max_gap(&self) -> Duration {
+        self.config.rest_interval * MISSED_REST_SAMPLES
+    }
+
+    /// Run a query on a blocking thread
+    async fn query<T, F>(&self, query: F) -> Result<T, HistoryError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> Result<T, HistoryError> + Send + 'static,
+    {
+        let db = self.db.clone();
+        task::spawn_blocking(move || query(&db.lock().unwrap()))
+            .await
+            .expect("History query panicked")
+    }
+
+    /// Store the desk state until the state stream ends
+    pub async fn record(
+        self: Arc<Self>,
+        mut states: impl Stream<Item = (Position, Velocity)> + Unpin,
+        mut events: EventStream,
+    ) {
+        let mut rest_samples = time::interval(self.config.rest_interval);
+        rest_samples.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut prune = time::interval(PRUNE_INTERVAL);
+        prune.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut last: Option<(Position, Velocity)> = None;
+        // start of the move in progress
+        let mut segment: Option<(i64, Position)> = None;
+        let mut connected = true;
+        let (writes, queue) = mpsc::channel::<Write>();
+        let db = self.db.clone();
+        // ends when `writes` is dropped, once the pending writes are applied
+        thread::Builder::new()
+            .name("history-writer".to_owned())
+            .spawn(move || {
+                for write in queue {
+                    log_error(write.apply(&db.lock().unwrap()));
+                }
+            })
+            .expect("Error spawning the history writer");
+        let write = |write| writes.send(write).unwrap_or(());
+        loop {
+            select! {
+                state = states.next() => {
+                    let (position, velocity) = match state {
+                        Some(state) => state,
+                        None => break,
+                    };
+                    let now = now_ms();
+                    let moving = !velocity.is_zero();
+                    match segment {
+                        None if moving => {
+                            let start_position = last.map_or(position, |(position, _)| position);
+                            segment = Some((now, start_position));
+                        }
+                        Some((start_ms, start_position)) if !moving => {
+                            write(Write::Move(MoveSegment {
+                                start_ms,
+                                end_ms: now,
+                                start_position: start_position.to_cm(),
+                                end_position: position.to_cm(),
+                            }));
+                            segment = None;
+                        }
+                        _ => {}
+                    }
+                    // at rest, only changes are stored outside of the interval
+                    let changed = last.is_none_or(|(last_position, last_velocity)| {
+                        last_position != position || !last_velocity.is_zero()
+                    });
+                    if moving || changed {
+                        write(Write::Sample { timestamp_ms: now, position, velocity });
+                        rest_samples.reset();
+                    }
+                    last = Some((position, velocity));
+                }
+                _ = rest_samples.tick() => {
+                    // the last state is stale while the desk is disconnected
+                    if let (Some((position, velocity)), true) = (last, connected) {
+                        write(Write::Sample { timestamp_ms: now_ms(), position, velocity });
+                    }
+                }
+                Some(event) = events.next() => {
+                    if let Event::Connection(state) = event {
+                        connected = state == ConnectionState::Connected;
+                    }
+                }
+                _ = prune.tick() => {
+                    if let Some(retention) = self.config.retention {
+                        let before = now_ms() - retention.as_millis() as i64;
+                        write(Write::Prune { before_ms: before });
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn usage(
+    db: &Connection,
+    range: Range<i64>,
+    thresholds: PostureThresholds,
+    max_gap: Duration,
+) -> Result<UsageStats, HistoryError> {
+    let mut usage = UsageAccumulator::new(range.clone(), thresholds, max_gap.as_millis() as i64);
+    let before = db
+        .prepare_cached(
+            "SELECT timestamp_ms, position FROM samples
+             WHERE timestamp_ms < ?1
+             ORDER BY timestamp_ms DESC LIMIT 1",
+        )?
+        .query_row([range.start], |row| Ok((row.get(0)?, row.get(1)?)))
+        .optional()?;
+    let mut statement = db.prepare_cached(
+        "SELECT timestamp_ms, position FROM samples
+         WHERE timestamp_ms >= ?1 AND timestamp_ms < ?2
+         ORDER BY timestamp_ms",
+    )?;
+    let samples = statement.query_map([range.start, range.end], |row| {
+        Ok((row.get(0)?, row.get(1)?))
+    })?;
+    for sample in before.into_iter().map(Ok).chain(samples) {
+        let (timestamp_ms, position): (i64, f32) = sample?;
+        // only valid positions are stored
+        if let Ok(position) = Position::from_cm(position) {
+            usage.add(timestamp_ms, position);
+        }
+    }
+    Ok(usage.finish())
+}
+
+/// Recording goes on if a write fails
+fn log_error(result: Result<(), HistoryError>) {
+    if let Err(e) = result {
+        warn!("Error recording history: {}", e);
+    }
+}
+
+/// Entries of a page, and the key of the last one if more were fetched than the limit
+fn page<T>(entries: Vec<(T, PageKey)>, limit: usize) -> (Vec<T>, Option<PageKey>) {
+    let (entries, more) = truncate(entries, limit);
+    let last = entries.last().map(|(_, key)| *key);
+    let entries = entries.into_iter().map(|(entry, _)| entry).collect();
+    (entries, last.filter(|_| more))
+}
+
+fn truncate<T>(mut entries: Vec<T>, limit: usize) -> (Vec<T>, bool) {
+    let truncated = entries.len() > limit;
+    entries.truncate(limit);
+    (entries, truncated)
+}
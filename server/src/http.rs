@@ -0,0 +1,548 @@
+use crate::{
+    auth::{AuthError, Authenticator, Identity, Role},
+    metrics::Metrics,
+    service::{DeskService, Peer},
+    utils::Position,
+};
+use axum::{
+    extract::{connect_info::Connected, ConnectInfo, Extension, Path, Query},
+    http::{
+        header::{AUTHORIZATION, CONTENT_TYPE},
+        HeaderMap, StatusCode,
+    },
+    response::{
+        sse::{self, KeepAlive, Sse},
+        Html, IntoResponse, Response,
+    },
+    routing::{delete, get, post, put},
+    Json, Router, Server,
+};
+use desklink_common::rpc::{
+    desk_service_server::DeskService as DeskServiceTrait, error_detail, AcquireControlRequest,
+    AcquireControlResponse, DeletePresetRequest, ErrorDetail, GetStateRequest, GetStateResponse,
+    ListPresetsRequest, ReleaseControlRequest, ReloadConfigRequest, SetPresetRequest,
+    StartMoveRequest, StopRequest, SubscribeStateRequest, SubscribeStateResponse,
+};
+use futures::{future, stream, Future, Stream, StreamExt};
+use hyper::server::{
+    accept::{self, Accept},
+    conn::{AddrIncoming, AddrStream},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time,
+};
+use tokio_rustls::{rustls::ServerConfig, server::TlsStream, TlsAcceptor};
+use tonic::{Code, Request, Status};
+use tracing::{debug, info, warn};
+
+// Web control panel, compiled into the binary
+const PANEL_HTML: &str = include_str!("../assets/panel/index.html");
+const PANEL_JS: &str = include_str!("../assets/panel/panel.js");
+const PANEL_CSS: &str = include_str!("../assets/panel/panel.css");
+
+/**
+ * JSON endpoints mirroring `DeskService`.
+ * Requests are forwarded to the RPC handlers, so both frontends share
+ * the controller commands, permission checks and logging.
+ */
+#[derive(Clone)]
+struct Gateway {
+    service: Arc<DeskService>,
+    authenticator: Authenticator,
+    metrics: Arc<Metrics>,
+}
+
+impl Gateway {
+    fn authenticate(
+        &self,
+        headers: &HeaderMap,
+        peer: SocketAddr,
+    ) -> Result<Option<Identity>, AuthError> {
+        let authorization = headers
+            .get(AUTHORIZATION)
+            .map(|value| value.to_str().map_err(|_| AuthError::MalformedHeader))
+            .transpose()?;
+        self.authenticator
+            .authenticate(authorization)
+            .inspect_err(|e| info!(%peer, "Rejected HTTP request: {}", e))
+    }
+
+    /// Authenticate the HTTP request and wrap the message for the RPC handler
+    #[allow(clippy::result_large_err)]
+    fn request<T>(
+        &self,
+        message: T,
+        headers: &HeaderMap,
+        peer: SocketAddr,
+    ) -> Result<Request<T>, ApiError> {
+        let identity = self.authenticate(headers, peer)?;
+        let mut request = Request::new(message);
+        request
+            .extensions_mut()
+            .insert(Peer(format!("http {}", peer)));
+        if let Some(identity) = identity {
+            request.extensions_mut().insert(identity);
+        }
+        Ok(request)
+    }
+}
+
+pub fn router(
+    service: Arc<DeskService>,
+    authenticator: Authenticator,
+    metrics: Arc<Metrics>,
+) -> Router {
+    Router::new()
+        .route("/", get(|| async { Html(PANEL_HTML) }))
+        .route(
+            "/panel.js",
+            get(|| async { ([(CONTENT_TYPE, "text/javascript")], PANEL_JS) }),
+        )
+        .route(
+            "/panel.css",
+            get(|| async { ([(CONTENT_TYPE, "text/css")], PANEL_CSS) }),
+        )
+        .route("/api/limits", get(get_limits))
+        .route("/api/state", get(get_state))
+        .route("/api/state/stream", get(stream_state))
+        .route("/api/move", post(start_move))
+        .route("/api/stop", post(stop))
+        .route("/api/presets", get(list_presets))
+        .route("/api/presets/:name", put(set_preset).delete(delete_preset))
+        .route("/api/control", post(acquire_control))
+        .route("/api/control/:lease_id", delete(release_control))
+        .route("/api/reload", post(reload_config))
+        .route("/metrics", get(render_metrics))
+        .layer(Extension(Gateway {
+            service,
+            authenticator,
+            metrics,
+        }))
+}
+
+/// Serve over TLS if configured, with the certificate of the gRPC server
+pub async fn serve(
+    address: SocketAddr,
+    tls: Option<Arc<ServerConfig>>,
+    router: Router,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), hyper::Error> {
+    let tls = match tls {
+        Some(tls) => TlsAcceptor::from(tls),
+        None => {
+            return Server::try_bind(&address)?
+                .serve(router.into_make_service_with_connect_info::<SocketAddr>())
+                .with_graceful_shutdown(shutdown)
+                .await
+        }
+    };
+    let mut incoming = AddrIncoming::bind(&address)?;
+    let connections = stream::poll_fn(move |cx| Pin::new(&mut incoming).poll_accept(cx))
+        .filter_map(|connection| {
+            future::ready(
+                connection
+                    .inspect_err(|e| warn!("Error accepting HTTP connection: {}", e))
+                    .ok(),
+            )
+        })
+        .map(move |connection| {
+            let peer = connection.remote_addr();
+            let handshake = time::timeout(TLS_HANDSHAKE_TIMEOUT, tls.accept(connection));
+            async move {
+                match handshake.await {
+                    Ok(Ok(stream)) => Some(TlsConnection { stream, peer }),
+                    Ok(Err(e)) => {
+                        debug!(%peer, "TLS handshake failed: {}", e);
+                        None
+                    }
+                    Err(_) => {
+                        debug!(%peer, "TLS handshake timed out");
+                        None
+                    }
+                }
+            }
+        })
+        // a slow client only holds up its own handshake
+        .buffer_unordered(MAX_TLS_HANDSHAKES)
+        .filter_map(future::ready)
+        .map(Ok::<_, io::Error>);
+    Server::builder(accept::from_stream(connections))
+        .serve(router.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(shutdown)
+        .await
+}
+
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_TLS_HANDSHAKES: usize = 16;
+
+/// HTTPS connection, keeping the address of the client for `ConnectInfo`
+struct TlsConnection {
+    stream: TlsStream<AddrStream>,
+    peer: SocketAddr,
+}
+
+impl Connected<&TlsConnection> for SocketAddr {
+    fn connect_info(connection: &TlsConnection) -> Self {
+        connection.peer
+    }
+}
+
+impl AsyncRead for TlsConnection {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for TlsConnection {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+/// Error status of the RPC handlers, rendered as JSON
+pub struct ApiError(Status);
+
+impl From<Status> for ApiError {
+    fn from(status: Status) -> Self {
+        ApiError(status)
+    }
+}
+
+impl From<AuthError> for ApiError {
+    fn from(e: AuthError) -> Self {
+        ApiError(e.into())
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    code: &'static str,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    allowed_range: Option<Range>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lease_holder: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lease_remaining_ms: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct Range {
+    min: f32,
+    max: f32,
+}
+
+impl From<&Status> for ErrorBody {
+    fn from(status: &Status) -> Self {
+        let detail = ErrorDetail::from_status(status).unwrap_or_default();
+        let code = error_detail::Code::from_i32(detail.code).unwrap_or(error_detail::Code::Unknown);
+        ErrorBody {
+            code: code.as_str_name(),
+            message: status.message().to_owned(),
+            allowed_range: detail.allowed_range.map(|range| Range {
+                min: range.min,
+                max: range.max,
+            }),
+            lease_holder: Some(detail.lease_holder).filter(|holder| !holder.is_empty()),
+            lease_remaining_ms: Some(detail.lease_remaining_ms).filter(|ms| *ms > 0),
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let status = match self.0.code() {
+            Code::Ok => StatusCode::OK,
+            Code::InvalidArgument | Code::OutOfRange => StatusCode::BAD_REQUEST,
+            Code::Unauthenticated => StatusCode::UNAUTHORIZED,
+            Code::PermissionDenied => StatusCode::FORBIDDEN,
+            Code::NotFound => StatusCode::NOT_FOUND,
+            Code::AlreadyExists | Code::FailedPrecondition | Code::Aborted | Code::Cancelled => {
+                StatusCode::CONFLICT
+            }
+            Code::ResourceExhausted => StatusCode::TOO_MANY_REQUESTS,
+            Code::Unimplemented => StatusCode::NOT_IMPLEMENTED,
+            Code::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+            Code::DeadlineExceeded => StatusCode::GATEWAY_TIMEOUT,
+            Code::Unknown | Code::Internal | Code::DataLoss => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        (status, Json(ErrorBody::from(&self.0))).into_response()
+    }
+}
+
+#[derive(Serialize)]
+struct State {
+    position: f32,
+    velocity: f32,
+}
+
+impl From<GetStateResponse> for State {
+    fn from(GetStateResponse { position, velocity }: GetStateResponse) -> Self {
+        State { position, velocity }
+    }
+}
+
+impl From<SubscribeStateResponse> for State {
+    fn from(SubscribeStateResponse { position, velocity }: SubscribeStateResponse) -> Self {
+        State { position, velocity }
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct StateStreamQuery {
+    min_interval_ms: u32,
+    min_position_change: f32,
+    moving_only: bool,
+}
+
+#[derive(Deserialize)]
+struct MoveBody {
+    target: Option<f32>,
+    preset: Option<String>,
+    lease_id: Option<String>,
+    #[serde(default)]
+    stop_on_disconnect: bool,
+    #[serde(default)]
+    override_lock: bool,
+}
+
+#[derive(Serialize)]
+struct Preset {
+    name: String,
+    position: f32,
+    from_config: bool,
+}
+
+#[derive(Deserialize)]
+struct PresetBody {
+    position: f32,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct AcquireControlBody {
+    holder: String,
+    duration_ms: u32,
+    lease_id: String,
+}
+
+#[derive(Serialize)]
+struct Lease {
+    lease_id: String,
+    duration_ms: u32,
+}
+
+async fn get_state(
+    Extension(gateway): Extension<Gateway>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> Result<Json<State>, ApiError> {
+    let request = gateway.request(GetStateRequest {}, &headers, peer)?;
+    let response = gateway.service.get_state(request).await?;
+    Ok(Json(response.into_inner().into()))
+}
+
+/// Server-Sent Events with a `state` event per update
+async fn stream_state(
+    Extension(gateway): Extension<Gateway>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Query(query): Query<StateStreamQuery>,
+) -> Result<Sse<impl Stream<Item = Result<sse::Event, serde_json::Error>>>, ApiError> {
+    let message = SubscribeStateRequest {
+        min_interval_ms: query.min_interval_ms,
+        min_position_change: query.min_position_change,
+        moving_only: query.moving_only,
+    };
+    let request = gateway.request(message, &headers, peer)?;
+    let states = gateway.service.subscribe_state(request).await?.into_inner();
+    let events = states.map(|state| match state {
+        Ok(state) => sse::Event::default()
+            .event("state")
+            .json_data(State::from(state)),
+        Err(status) => sse::Event::default()
+            .event("error")
+            .json_data(ErrorBody::from(&status)),
+    });
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+/// Heights the token may move the desk between, the whole range without authentication
+async fn get_limits(
+    Extension(gateway): Extension<Gateway>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> Result<Json<Range>, ApiError> {
+    let (min, max) = match gateway.authenticate(&headers, peer)? {
+        Some(identity) => identity.window(),
+        None => (Position::MIN_CM, Position::MAX_CM),
+    };
+    Ok(Json(Range { min, max }))
+}
+
+async fn start_move(
+    Extension(gateway): Extension<Gateway>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(body): Json<MoveBody>,
+) -> Result<StatusCode, ApiError> {
+    if body.target.is_none() && body.preset.is_none() {
+        return Err(ApiError(
+            ErrorDetail::new(error_detail::Code::InvalidArgument)
+                .into_status(Code::InvalidArgument, "Either target or preset is required"),
+        ));
+    }
+    let message = StartMoveRequest {
+        target: body.target.unwrap_or_default(),
+        preset: body.preset.unwrap_or_default(),
+        lease_id: body.lease_id.unwrap_or_default(),
+        stop_on_disconnect: body.stop_on_disconnect,
+        override_lock: body.override_lock,
+    };
+    let request = gateway.request(message, &headers, peer)?;
+    gateway.service.start_move(request).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn stop(
+    Extension(gateway): Extension<Gateway>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> Result<StatusCode, ApiError> {
+    let request = gateway.request(StopRequest {}, &headers, peer)?;
+    gateway.service.stop(request).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn list_presets(
+    Extension(gateway): Extension<Gateway>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<Preset>>, ApiError> {
+    let request = gateway.request(ListPresetsRequest {}, &headers, peer)?;
+    let presets = gateway.service.list_presets(request).await?.into_inner();
+    Ok(Json(
+        presets
+            .presets
+            .into_iter()
+            .map(|preset| Preset {
+                name: preset.name,
+                position: preset.position,
+                from_config: preset.from_config,
+            })
+            .collect(),
+    ))
+}
+
+async fn set_preset(
+    Extension(gateway): Extension<Gateway>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(name): Path<String>,
+    Json(body): Json<PresetBody>,
+) -> Result<StatusCode, ApiError> {
+    let message = SetPresetRequest {
+        name,
+        position: body.position,
+    };
+    let request = gateway.request(message, &headers, peer)?;
+    gateway.service.set_preset(request).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn delete_preset(
+    Extension(gateway): Extension<Gateway>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(name): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    let request = gateway.request(DeletePresetRequest { name }, &headers, peer)?;
+    gateway.service.delete_preset(request).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn acquire_control(
+    Extension(gateway): Extension<Gateway>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    body: Option<Json<AcquireControlBody>>,
+) -> Result<Json<Lease>, ApiError> {
+    let Json(body) = body.unwrap_or_default();
+    let message = AcquireControlRequest {
+        holder: body.holder,
+        duration_ms: body.duration_ms,
+        lease_id: body.lease_id,
+    };
+    let request = gateway.request(message, &headers, peer)?;
+    let AcquireControlResponse {
+        lease_id,
+        duration_ms,
+    } = gateway.service.acquire_control(request).await?.into_inner();
+    Ok(Json(Lease {
+        lease_id,
+        duration_ms,
+    }))
+}
+
+async fn release_control(
+    Extension(gateway): Extension<Gateway>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(lease_id): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    let request = gateway.request(ReleaseControlRequest { lease_id }, &headers, peer)?;
+    gateway.service.release_control(request).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn reload_config(
+    Extension(gateway): Extension<Gateway>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> Result<StatusCode, ApiError> {
+    let request = gateway.request(ReloadConfigRequest {}, &headers, peer)?;
+    gateway.service.reload_config(request).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Prometheus scrape endpoint, scrapers authenticate with a bearer token like other clients
+async fn render_metrics(
+    Extension(gateway): Extension<Gateway>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, ApiError> {
+    if let Some(identity) = gateway.authenticate(&headers, peer)? {
+        identity.require(Role::Observe)?;
+    }
+    Ok((
+        [(CONTENT_TYPE, prometheus::TEXT_FORMAT)],
+        gateway.metrics.render(),
+    ))
+}
@@ -1,5 +1,22 @@
+pub mod audit;
+pub mod auth;
+pub mod calendar;
 pub mod config;
 pub mod controllers;
+pub mod cycle;
 pub mod desk;
+pub mod events;
+pub mod goal;
+pub mod grpc_web;
+pub mod history;
+pub mod http;
+pub mod lock;
+pub mod metrics;
+pub mod mqtt;
+pub mod presets;
+pub mod reload;
+pub mod schedule;
 pub mod service;
+pub mod stats;
+pub mod systemd;
 pub mod utils;
@@ -0,0 +1,484 @@
+use crate::{config::LockConfig, history::now_ms, schedule::Days, service::Automatic};
+use chrono::{Datelike, Duration as ChronoDuration, Local, NaiveDateTime, NaiveTime, TimeZone};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::{self, Display, Formatter},
+    io,
+    ops::Range,
+    path::PathBuf,
+    str::FromStr,
+    sync::Mutex,
+};
+use thiserror::Error;
+use tracing::info;
+
+#[derive(Error, Debug)]
+pub enum LockError {
+    #[error(
+        "Invalid lock window `{0}`, expected `daily|weekdays|weekends|mon,tue,.. HH:MM-HH:MM`"
+    )]
+    InvalidWindow(String),
+
+    #[error("The desk is locked against {} moves, {}", .0.scope, .0)]
+    Locked(ActiveLock),
+
+    #[error("IO error: `{path}`")]
+    IoError {
+        path: PathBuf,
+        #[source]
+        error: io::Error,
+    },
+
+    #[error("TOML parsing error")]
+    TomlError(#[from] toml::de::Error),
+
+    #[error("TOML serialization error")]
+    TomlSerializeError(#[from] toml::ser::Error),
+}
+
+/// Moves refused by a lock, `Stop` is always allowed
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum LockScope {
+    /// Moves started by deskd itself, like scheduled ones
+    Automatic,
+    All,
+}
+
+impl LockScope {
+    fn refuses(self, automatic: Option<Automatic>) -> bool {
+        automatic.is_some() || self == LockScope::All
+    }
+}
+
+impl Display for LockScope {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            LockScope::Automatic => write!(f, "automatic"),
+            LockScope::All => write!(f, "all"),
+        }
+    }
+}
+
+/// Local time span on some days of the week, ending the next day if it ends before it starts,
+/// lasting a whole day if it ends when it starts
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Window {
+    pub days: Days,
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl Window {
+    /// End of the span containing `at`, spans start on one of the days
+    pub fn end_of_span(&self, at: NaiveDateTime) -> Option<NaiveDateTime> {
+        let today = at.date();
+        [today - ChronoDuration::days(1), today]
+            .into_iter()
+            .filter(|date| self.days.contains(date.weekday()))
+            .find_map(|date| {
+                let start = date.and_time(self.start);
+                let end = if self.end > self.start {
+                    date.and_time(self.end)
+                } else {
+                    (date + ChronoDuration::days(1)).and_time(self.end)
+                };
+                (start <= at && at < end).then_some(end)
+            })
+    }
+}
+
+impl FromStr for Window {
+    type Err = LockError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || LockError::InvalidWindow(s.to_owned());
+        let (days, span) = s.trim().rsplit_once(' ').ok_or_else(invalid)?;
+        let (start, end) = span.split_once('-').ok_or_else(invalid)?;
+        let time =
+            |time: &str| NaiveTime::parse_from_str(time.trim(), "%H:%M").map_err(|_| invalid());
+        Ok(Window {
+            days: Days::parse(days).ok_or_else(invalid)?,
+            start: time(start)?,
+            end: time(end)?,
+        })
+    }
+}
+
+impl Display for Window {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {}-{}",
+            self.days,
+            self.start.format("%H:%M"),
+            self.end.format("%H:%M")
+        )
+    }
+}
+
+/// Recurring lock from the config file
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LockWindow {
+    pub window: Window,
+    pub scope: LockScope,
+}
+
+/// Lock window as written in the config file
+#[derive(Deserialize)]
+pub struct LockWindowSpec {
+    pub when: String,
+    /// Defaults to automatic moves
+    pub scope: Option<LockScope>,
+}
+
+impl TryFrom<LockWindowSpec> for LockWindow {
+    type Error = LockError;
+
+    fn try_from(spec: LockWindowSpec) -> Result<Self, Self::Error> {
+        Ok(LockWindow {
+            window: spec.when.parse()?,
+            scope: spec.scope.unwrap_or(LockScope::Automatic),
+        })
+    }
+}
+
+/// Lock set through RPCs, kept across restarts
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RuntimeLock {
+    pub scope: LockScope,
+    pub reason: String,
+    /// Client that set the lock
+    pub client: String,
+    /// Unix time in milliseconds
+    pub since_ms: i64,
+}
+
+impl RuntimeLock {
+    pub fn new(scope: LockScope, reason: String, client: String) -> Self {
+        RuntimeLock {
+            scope,
+            reason,
+            client,
+            since_ms: now_ms(),
+        }
+    }
+}
+
+/// Lock during a calendar event
+#[derive(Clone, Debug)]
+pub struct CalendarLock {
+    pub rule: String,
+    /// Summary of the event
+    pub event: String,
+    pub scope: LockScope,
+    /// Unix times in milliseconds
+    pub range: Range<i64>,
+}
+
+#[derive(Clone, Debug)]
+pub enum LockSource {
+    Window {
+        name: String,
+        /// Unix time in milliseconds
+        until_ms: i64,
+    },
+    Calendar {
+        rule: String,
+        event: String,
+        /// Unix time in milliseconds
+        until_ms: i64,
+    },
+    Runtime(RuntimeLock),
+}
+
+#[derive(Clone, Debug)]
+pub struct ActiveLock {
+    pub scope: LockScope,
+    pub source: LockSource,
+}
+
+impl Display for ActiveLock {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match &self.source {
+            LockSource::Window { name, until_ms } => {
+                write!(f, "window `{}`", name)?;
+                if let Some(until) = Local.timestamp_millis_opt(*until_ms).single() {
+                    write!(f, " until {}", until.format("%H:%M"))?;
+                }
+                Ok(())
+            }
+            LockSource::Calendar {
+                rule,
+                event,
+                until_ms,
+            } => {
+                write!(f, "event `{}` of calendar rule `{}`", event, rule)?;
+                if let Some(until) = Local.timestamp_millis_opt(*until_ms).single() {
+                    write!(f, " until {}", until.format("%H:%M"))?;
+                }
+                Ok(())
+            }
+            LockSource::Runtime(lock) if lock.reason.is_empty() => {
+                write!(f, "set by {}", lock.client)
+            }
+            LockSource::Runtime(lock) => write!(f, "`{}` set by {}", lock.reason, lock.client),
+        }
+    }
+}
+
+/**
+ * Locks refusing automatic moves, or all moves.
+ * Windows from the config file lock the desk at set times, calendar rules during events,
+ * the runtime lock is stored in the storage directory.
+ */
+pub struct Locks {
+    config: Mutex<LockConfig>,
+    calendar: Mutex<Vec<CalendarLock>>,
+    runtime: Mutex<Option<RuntimeLock>>,
+    path: PathBuf,
+}
+
+impl Locks {
+    pub fn load(config: LockConfig, path: PathBuf) -> Result<Self, LockError> {
+        let runtime = match std::fs::read_to_string(&path) {
+            Ok(content) => Some(toml::from_str(&content)?),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => None,
+            Err(error) => return Err(LockError::IoError { path, error }),
+        };
+        Ok(Locks {
+            config: Mutex::new(config),
+            calendar: Mutex::new(Vec::new()),
+            runtime: Mutex::new(runtime),
+            path,
+        })
+    }
+
+    /// Replace the windows from the config file
+    pub fn set_config(&self, config: LockConfig) {
+        *self.config.lock().unwrap() = config;
+    }
+
+    /// Replace the locks of upcoming and current calendar events
+    pub fn set_calendar(&self, locks: Vec<CalendarLock>) {
+        *self.calendar.lock().unwrap() = locks;
+    }
+
+    /// Locks in effect, the runtime lock first
+    pub fn active(&self) -> Vec<ActiveLock> {
+        let now = Local::now().naive_local();
+        let runtime = self.runtime.lock().unwrap().clone();
+        let config = self.config.lock().unwrap();
+        let windows = config.windows.iter().filter_map(|(name, lock)| {
+            let end = lock.window.end_of_span(now)?;
+            Some(ActiveLock {
+                scope: lock.scope,
+                source: LockSource::Window {
+                    name: name.clone(),
+                    until_ms: Local
+                        .from_local_datetime(&end)
+                        .earliest()
+                        .map_or(0, |end| end.timestamp_millis()),
+                },
+            })
+        });
+        let calendar = self.calendar.lock().unwrap();
+        let now_ms = now_ms();
+        let events = calendar
+            .iter()
+            .filter(|lock| lock.range.contains(&now_ms))
+            .map(|lock| ActiveLock {
+                scope: lock.scope,
+                source: LockSource::Calendar {
+                    rule: lock.rule.clone(),
+                    event: lock.event.clone(),
+                    until_ms: lock.range.end,
+                },
+            });
+        runtime
+            .map(|lock| ActiveLock {
+                scope: lock.scope,
+                source: LockSource::Runtime(lock),
+            })
+            .into_iter()
+            .chain(windows)
+            .chain(events)
+            .collect()
+    }
+
+    /// Fails with the strongest lock refusing the move, calendar locks allow calendar moves
+    pub fn check(&self, automatic: Option<Automatic>) -> Result<(), LockError> {
+        match self
+            .active()
+            .into_iter()
+            .filter(|lock| {
+                !(matches!(lock.source, LockSource::Calendar { .. })
+                    && automatic == Some(Automatic::Calendar))
+            })
+            .filter(|lock| lock.scope.refuses(automatic))
+            .max_by_key(|lock| lock.scope)
+        {
+            Some(lock) => Err(LockError::Locked(lock)),
+            None => Ok(()),
+        }
+    }
+
+    /// Replace the runtime lock, or clear it
+    pub fn set(&self, lock: Option<RuntimeLock>) -> Result<(), LockError> {
+        let mut runtime = self.runtime.lock().unwrap();
+        info!(?lock, "Setting lock");
+        let io_error = |error| LockError::IoError {
+            path: self.path.clone(),
+            error,
+        };
+        match &lock {
+            Some(lock) => {
+                let content = toml::to_string(lock)?;
+                if let Some(directory) = self.path.parent() {
+                    std::fs::create_dir_all(directory).map_err(io_error)?;
+                }
+                std::fs::write(&self.path, content).map_err(io_error)?;
+            }
+            None => match std::fs::remove_file(&self.path) {
+                Err(error) if error.kind() != io::ErrorKind::NotFound => {
+                    return Err(io_error(error))
+                }
+                _ => {}
+            },
+        }
+        *runtime = lock;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(time: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M").unwrap()
+    }
+
+    fn end_of_span(window: &str, time: &str) -> Option<NaiveDateTime> {
+        window.parse::<Window>().unwrap().end_of_span(at(time))
+    }
+
+    fn locks_with(windows: &[(&str, LockScope)], calendar: Option<LockScope>) -> Locks {
+        let windows = windows
+            .iter()
+            .map(|(when, scope)| {
+                let lock = LockWindow {
+                    window: when.parse().unwrap(),
+                    scope: *scope,
+                };
+                (when.to_string(), lock)
+            })
+            .collect();
+        let now = now_ms();
+        let calendar = calendar.map(|scope| CalendarLock {
+            rule: "meetings".to_owned(),
+            event: "Standup".to_owned(),
+            scope,
+            range: now - 60_000..now + 60_000,
+        });
+        Locks {
+            config: Mutex::new(LockConfig { windows }),
+            calendar: Mutex::new(calendar.into_iter().collect()),
+            runtime: Mutex::new(None),
+            path: PathBuf::new(),
+        }
+    }
+
+    #[test]
+    fn window_span() {
+        // 2024-01-05 is a Friday
+        assert_eq!(
+            end_of_span("weekdays 09:00-17:00", "2024-01-05 09:00"),
+            Some(at("2024-01-05 17:00"))
+        );
+        assert_eq!(
+            end_of_span("weekdays 09:00-17:00", "2024-01-05 17:00"),
+            None
+        );
+        assert_eq!(
+            end_of_span("weekdays 09:00-17:00", "2024-01-06 10:00"),
+            None
+        );
+    }
+
+    #[test]
+    fn overnight_window_span() {
+        let window = "fri 22:00-07:00";
+        assert_eq!(end_of_span(window, "2024-01-05 21:59"), None);
+        assert_eq!(
+            end_of_span(window, "2024-01-05 23:00"),
+            Some(at("2024-01-06 07:00"))
+        );
+        // seen from the next morning, which is not one of the days
+        assert_eq!(
+            end_of_span(window, "2024-01-06 06:59"),
+            Some(at("2024-01-06 07:00"))
+        );
+        assert_eq!(end_of_span(window, "2024-01-06 07:00"), None);
+        // Friday morning belongs to the span starting on Thursday
+        assert_eq!(end_of_span(window, "2024-01-05 06:00"), None);
+    }
+
+    #[test]
+    fn whole_day_window_span() {
+        let window = "mon 08:00-08:00";
+        // 2024-01-08 is a Monday
+        assert_eq!(end_of_span(window, "2024-01-08 07:59"), None);
+        assert_eq!(
+            end_of_span(window, "2024-01-08 08:00"),
+            Some(at("2024-01-09 08:00"))
+        );
+        assert_eq!(
+            end_of_span(window, "2024-01-09 07:59"),
+            Some(at("2024-01-09 08:00"))
+        );
+        assert_eq!(end_of_span(window, "2024-01-09 08:00"), None);
+    }
+
+    #[test]
+    fn calendar_locks_allow_calendar_moves() {
+        let locks = locks_with(&[], Some(LockScope::All));
+        assert!(locks.check(Some(Automatic::Calendar)).is_ok());
+        assert!(locks.check(Some(Automatic::Schedule)).is_err());
+        assert!(locks.check(None).is_err());
+
+        let locks = locks_with(&[], Some(LockScope::Automatic));
+        assert!(locks.check(Some(Automatic::Calendar)).is_ok());
+        assert!(locks.check(Some(Automatic::Cycle)).is_err());
+        assert!(locks.check(None).is_ok());
+    }
+
+    #[test]
+    fn other_locks_refuse_calendar_moves() {
+        let always = ("daily 00:00-00:00", LockScope::Automatic);
+        let locks = locks_with(&[always], Some(LockScope::Automatic));
+        match locks.check(Some(Automatic::Calendar)) {
+            Err(LockError::Locked(lock)) => {
+                assert!(matches!(lock.source, LockSource::Window { .. }))
+            }
+            result => panic!("Calendar move not refused by the window: {:?}", result),
+        }
+        assert!(locks.check(None).is_ok());
+
+        *locks.runtime.lock().unwrap() = Some(RuntimeLock::new(
+            LockScope::All,
+            String::new(),
+            "test".to_owned(),
+        ));
+        match locks.check(Some(Automatic::Calendar)) {
+            Err(LockError::Locked(lock)) => {
+                assert!(matches!(lock.source, LockSource::Runtime(_)))
+            }
+            result => panic!(
+                "Calendar move not refused by the runtime lock: {:?}",
+                result
+            ),
+        }
+        assert!(locks.check(None).is_err());
+    }
+}
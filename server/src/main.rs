@@ -1,16 +1,56 @@
 use anyhow::Result;
+use desklink_common::telemetry;
 use desklink_server::{
-    config::Config,
+    audit::AuditLog,
+    auth::Authenticator,
+    calendar::Calendar,
+    config::{Config, ConfigError, TlsConfig, UnixSocketConfig},
     controllers,
+    cycle::Cycle,
     desk::Desk,
+    events,
+    goal::Goal,
+    grpc_web::GrpcWebLayer,
+    history::History,
+    http,
+    lock::Locks,
+    metrics::{Metrics, RpcMetricsLayer},
+    mqtt::MqttBridge,
+    presets::Presets,
+    reload::ConfigReloader,
+    schedule::Scheduler,
     service::{DeskService, DeskServiceServer},
+    systemd::{self, ActivatedListeners},
 };
-use futures::{FutureExt, StreamExt};
+use futures::{future, FutureExt, StreamExt};
 use signal_hook::consts::signal;
 use signal_hook_tokio::Signals;
-use tokio::sync::watch;
-use tonic::transport::Server;
-use tracing::info;
+use std::{
+    fs::{self, DirBuilder, Permissions},
+    io,
+    os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    net::{TcpListener, UnixListener},
+    sync::{oneshot, watch},
+};
+use tokio_stream::wrappers::{TcpListenerStream, UnixListenerStream, WatchStream};
+use tonic::{codegen::InterceptedService, transport::Server};
+use tower::layer::util::{Identity, Stack};
+use tracing::{error, info, info_span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{filter::LevelFilter, fmt, prelude::*};
+
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(10);
+const KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(10);
+const PRESETS_FILE: &str = "presets.toml";
+const AUDIT_FILE: &str = "audit.jsonl";
+const LOCK_FILE: &str = "lock.toml";
+const HISTORY_FILE: &str = "history.sqlite3";
+const SCHEDULE_FILE: &str = "schedule.toml";
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<()> {
@@ -19,25 +59,142 @@ async fn main() -> Result<()> {
     #[cfg(debug_assertions)]
     println!("{:#?}", config);
 
-    // Logger
-    let subscriber_builder = tracing_subscriber::fmt().with_max_level(config.log.level);
-    let _log_guard = if let Some((directory, file_name)) = config.log.file {
+    // Logger, spans are also exported if tracing is configured
+    let otel = config
+        .tracing
+        .as_ref()
+        .map(|tracing| telemetry::tracer(&tracing.otlp_endpoint, &tracing.service_name))
+        .transpose()?
+        .map(|tracer| tracing_opentelemetry::layer().with_tracer(tracer));
+    let (log, _log_guard) = if let Some((directory, file_name)) = config.log.file.clone() {
         let file_appender = tracing_appender::rolling::never(directory, file_name);
         let (non_blocking, guard) = tracing_appender::non_blocking(file_appender);
-        subscriber_builder.with_writer(non_blocking).json().init();
-        guard
+        (fmt::layer().with_writer(non_blocking).json().boxed(), guard)
     } else {
         let (non_blocking, guard) = tracing_appender::non_blocking(std::io::stdout());
-        subscriber_builder
-            .with_writer(non_blocking)
-            .pretty()
-            .compact()
-            .init();
-        guard
+        (
+            fmt::layer()
+                .with_writer(non_blocking)
+                .pretty()
+                .compact()
+                .boxed(),
+            guard,
+        )
     };
+    tracing_subscriber::registry()
+        .with(log)
+        .with(otel)
+        .with(LevelFilter::from_level(config.log.level))
+        .init();
+
+    // Listeners, sockets passed by systemd are served in addition to the configured ones
+    let activated = ActivatedListeners::from_env()?;
+    let mut tcp_listeners = activated
+        .tcp
+        .into_iter()
+        .map(|listener| {
+            listener.set_nonblocking(true)?;
+            TcpListener::from_std(listener)
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    if let Some(address) = config.server.address {
+        tcp_listeners.push(TcpListener::bind(address).await?);
+    }
+    let mut unix_listeners = activated
+        .unix
+        .into_iter()
+        .map(|listener| {
+            listener.set_nonblocking(true)?;
+            Ok((UnixListener::from_std(listener)?, None))
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    if let Some(unix_socket) = &config.server.unix_socket {
+        let listener = bind_unix_socket(unix_socket)?;
+        unix_listeners.push((listener, Some(unix_socket.path.clone())));
+    }
+    if tcp_listeners.is_empty() && unix_listeners.is_empty() {
+        return Err(ConfigError::MissingConfigField("server bind address or unix socket").into());
+    }
+    let http_address = config.http.address;
+    let mqtt = config.mqtt.clone();
+    let device_id = format!(
+        "desklink_{}",
+        config
+            .desk
+            .address
+            .to_string()
+            .replace(':', "")
+            .to_lowercase()
+    );
+    let grpc_web = GrpcWebLayer::new(
+        config.server.grpc_web.enabled,
+        &config.server.grpc_web.cors_origins,
+    );
+    let tls = config
+        .server
+        .tls
+        .as_ref()
+        .map(TlsConfig::load)
+        .transpose()?;
+    let http_tls = match config.http.address {
+        Some(_) => config
+            .server
+            .tls
+            .as_ref()
+            .map(TlsConfig::load_http)
+            .transpose()?,
+        None => None,
+    };
+    let authenticator = Authenticator::new(config.auth.tokens.clone());
+    let presets = Presets::load(
+        config.presets.clone(),
+        config.storage.directory.join(PRESETS_FILE),
+    )?
+    .shared();
 
     // Desk controller driver
-    let desk = Desk::find(config.desk.address).await?;
+    let metrics = Metrics::new(config.desk.posture);
+    let event_publisher = events::channel();
+    let desk = Desk::find(
+        config.desk.address,
+        event_publisher.clone(),
+        metrics.notifications(),
+    )
+    .await?;
+    tokio::spawn(metrics.clone().record(
+        WatchStream::new(desk.state.clone()),
+        events::subscribe(&event_publisher),
+    ));
+    let audit = Arc::new(AuditLog::open(
+        config.storage.directory.join(AUDIT_FILE),
+        desk.state.clone(),
+    )?);
+    let history = match config.history.clone() {
+        Some(history) => {
+            let history = Arc::new(History::open(
+                config.storage.directory.join(HISTORY_FILE),
+                history,
+                config.desk.posture,
+            )?);
+            tokio::spawn(history.clone().record(
+                WatchStream::new(desk.state.clone()),
+                events::subscribe(&event_publisher),
+            ));
+            Some(history)
+        }
+        None => None,
+    };
+    // the config requires the history for the goal
+    let goal = config
+        .goal
+        .clone()
+        .zip(history.clone())
+        .map(|(goal, history)| {
+            let goal = Arc::new(Goal::new(goal, history, event_publisher.clone()));
+            tokio::spawn(goal.clone().run());
+            goal
+        });
+    let desk_state = desk.state.clone();
     let mut controller = controllers::create_controller(desk);
     let (tx, rx) = watch::channel(Default::default());
     let join_controller = tokio::spawn(async move {
@@ -46,29 +203,213 @@ async fn main() -> Result<()> {
             .await
             .unwrap_or_else(|e| panic!("{}", e))
     });
+    systemd::notify_ready();
+    tokio::spawn(systemd::report_status(events::subscribe(&event_publisher)));
+    let scheduler = Arc::new(Scheduler::load(
+        config.scheduler.clone(),
+        config.storage.directory.join(SCHEDULE_FILE),
+        presets.clone(),
+        audit.clone(),
+    )?);
+    let locks = Arc::new(Locks::load(
+        config.lock.clone(),
+        config.storage.directory.join(LOCK_FILE),
+    )?);
+    let calendar = Arc::new(Calendar::new(config.calendar.clone(), locks.clone()));
+    let cycle = Arc::new(Cycle::new(config.desk.posture, desk_state.clone()));
+    let reloader = Arc::new(ConfigReloader::new(
+        config,
+        authenticator.clone(),
+        presets.clone(),
+        scheduler.clone(),
+        locks.clone(),
+        calendar.clone(),
+        event_publisher.clone(),
+    ));
 
-    // Shutdown signal
-    let mut signals = Signals::new([signal::SIGINT, signal::SIGTERM])?;
-    let shutdown = signals.next().map(|sig| {
-        info!(
-            "{} received, finishing existing client connections",
-            match sig.unwrap() {
-                signal::SIGINT => "SIGINT",
-                signal::SIGTERM => "SIGTERM",
+    // Signals
+    let mut signals = Signals::new([signal::SIGINT, signal::SIGTERM, signal::SIGHUP])?;
+    let (shutdown_tx, shutdown_rx) = oneshot::channel();
+    let signal_reloader = reloader.clone();
+    tokio::spawn(async move {
+        while let Some(sig) = signals.next().await {
+            match sig {
+                signal::SIGHUP => {
+                    if let Err(e) = signal_reloader.reload() {
+                        error!("Error reloading config: {}", e);
+                    }
+                }
+                signal::SIGINT | signal::SIGTERM => {
+                    info!(
+                        "{} received, finishing existing client connections",
+                        if sig == signal::SIGINT {
+                            "SIGINT"
+                        } else {
+                            "SIGTERM"
+                        }
+                    );
+                    systemd::notify_stopping();
+                    shutdown_tx.send(()).unwrap_or(());
+                    break;
+                }
                 _ => unreachable!(),
             }
-        );
+        }
     });
+    let shutdown = shutdown_rx.map(|_| ()).shared();
 
     // RPC server
     info!("Starting server...");
-    let svc = DeskServiceServer::new(DeskService::new(tx));
-    Server::builder()
-        .add_service(svc)
-        .serve_with_shutdown(config.server.address, shutdown)
-        .await?;
+    let service = Arc::new(DeskService::new(
+        tx,
+        presets,
+        reloader,
+        audit,
+        history,
+        goal,
+        scheduler.clone(),
+        cycle.clone(),
+        locks,
+    ));
+    tokio::spawn(calendar.run(service.clone()));
+    tokio::spawn(cycle.run(service.clone(), events::subscribe(&event_publisher)));
+    tokio::spawn(scheduler.run(
+        service.clone(),
+        desk_state,
+        events::subscribe(&event_publisher),
+    ));
+    if let Some(mqtt) = mqtt {
+        tokio::spawn(
+            MqttBridge::new(
+                mqtt,
+                device_id,
+                service.clone(),
+                authenticator.clone(),
+                event_publisher,
+            )
+            .run(),
+        );
+    }
+    let svc = InterceptedService::new(
+        DeskServiceServer::from_arc(service.clone()),
+        authenticator.clone(),
+    );
+    let rpc_metrics = RpcMetricsLayer::new(metrics.clone());
+    let mut servers = Vec::new();
+    for listener in tcp_listeners {
+        let mut server = server_builder(&grpc_web, &rpc_metrics);
+        if let Some(tls) = tls.clone() {
+            server = server.tls_config(tls)?;
+        }
+        info!(address = %listener.local_addr()?, tls = tls.is_some(), "Listening on TCP");
+        let incoming = TcpListenerStream::new(listener).map(|stream| {
+            let stream = stream?;
+            stream.set_nodelay(true)?;
+            Ok::<_, io::Error>(stream)
+        });
+        let serve = server
+            .add_service(svc.clone())
+            .serve_with_incoming_shutdown(incoming, shutdown.clone());
+        servers.push(
+            async move {
+                serve.await?;
+                Ok(())
+            }
+            .boxed(),
+        );
+    }
+    for (listener, path) in unix_listeners {
+        match &path {
+            Some(path) => info!(path = %path.display(), "Listening on Unix socket"),
+            None => info!("Listening on activated Unix socket"),
+        }
+        let serve = server_builder(&grpc_web, &rpc_metrics)
+            .add_service(svc.clone())
+            .serve_with_incoming_shutdown(UnixListenerStream::new(listener), shutdown.clone());
+        servers.push(
+            async move {
+                serve.await?;
+                // sockets passed by systemd are cleaned up by systemd
+                if let Some(path) = path {
+                    fs::remove_file(path)?;
+                }
+                Ok::<_, anyhow::Error>(())
+            }
+            .boxed(),
+        );
+    }
+    if let Some(address) = http_address {
+        info!(%address, tls = http_tls.is_some(), "Listening on HTTP");
+        let router = http::router(service, authenticator, metrics);
+        servers.push(
+            http::serve(address, http_tls, router, shutdown.clone())
+                .map(|result| Ok(result?))
+                .boxed(),
+        );
+    }
+    future::try_join_all(servers).await?;
     info!("Shutting down server...");
 
     join_controller.await?;
+    telemetry::shutdown().await;
     Ok(())
 }
+
+fn server_builder(
+    grpc_web: &GrpcWebLayer,
+    rpc_metrics: &RpcMetricsLayer,
+) -> Server<Stack<RpcMetricsLayer, Stack<GrpcWebLayer, Identity>>> {
+    Server::builder()
+        // detect vanished clients, so moves bound to them are stopped
+        .http2_keepalive_interval(Some(KEEPALIVE_INTERVAL))
+        .http2_keepalive_timeout(Some(KEEPALIVE_TIMEOUT))
+        // browsers speak gRPC-Web over HTTP/1.1 to plaintext servers
+        .accept_http1(grpc_web.is_enabled())
+        // continue the trace of the client, if any
+        .trace_fn(|request| {
+            let span = info_span!(
+                "rpc",
+                otel.name = %request.uri().path(),
+                otel.kind = "server",
+            );
+            span.set_parent(telemetry::extract(request.headers()));
+            span
+        })
+        .layer(grpc_web.clone())
+        // inside gRPC-Web, so the translated requests are measured too
+        .layer(rpc_metrics.clone())
+}
+
+/// Bind the socket, replacing the file left behind by a previous instance
+/// Replace a stale socket at the path, anything else there is an error.
+/// The socket is bound in a private directory and moved into place once its mode is set,
+/// so it is never reachable with the permissions of the umask.
+fn bind_unix_socket(config: &UnixSocketConfig) -> io::Result<UnixListener> {
+    match fs::symlink_metadata(&config.path) {
+        Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(&config.path)?,
+        Ok(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("`{}` exists and is not a socket", config.path.display()),
+            ))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    let directory = match config.path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let private = directory.join(format!(".deskd-{}", std::process::id()));
+    DirBuilder::new().mode(0o700).create(&private)?;
+    let bind = || {
+        let path = private.join("socket");
+        let listener = UnixListener::bind(&path)?;
+        fs::set_permissions(&path, Permissions::from_mode(config.mode))?;
+        fs::rename(&path, &config.path)?;
+        Ok(listener)
+    };
+    let result = bind();
+    fs::remove_dir_all(&private).unwrap_or(());
+    result
+}
@@ -0,0 +1,408 @@
+use crate::{
+    events::{ConnectionState, Event, EventStream, MoveOutcome},
+    service::{DeskService, DeskServiceServer},
+    stats::{Posture, PostureThresholds},
+    utils::{Position, Velocity},
+};
+use desklink_common::PROJECT_NAME;
+use futures::{future::BoxFuture, Stream, StreamExt};
+use http::{Request, Response};
+use prometheus::{
+    CounterVec, Encoder, Gauge, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec,
+    IntGauge, Opts, Registry, TextEncoder,
+};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Instant,
+};
+use tokio::select;
+use tonic::{transport::NamedService, Code};
+use tower::{Layer, Service};
+
+const TARGET_ERROR_BUCKETS_CM: &[f64] = &[0.05, 0.1, 0.2, 0.5, 1.0, 2.0, 5.0];
+const MOVE_DURATION_BUCKETS_S: &[f64] = &[0.5, 1.0, 2.0, 5.0, 10.0, 20.0, 30.0, 60.0];
+
+/// Time spent in the current posture, flushed on every state change and scrape
+struct PostureClock {
+    posture: Posture,
+    since: Instant,
+}
+
+/**
+ * Prometheus metrics of the desk, the controller and the RPC server.
+ * Desk and move metrics are fed from the state and event streams by `record`,
+ * RPC metrics by `RpcMetricsLayer`.
+ */
+pub struct Metrics {
+    registry: Registry,
+    thresholds: PostureThresholds,
+    height: Gauge,
+    desk_connected: IntGauge,
+    moves: IntCounterVec,
+    target_error: Histogram,
+    move_duration: Histogram,
+    reconnects: IntCounter,
+    notifications: IntCounter,
+    rpc_requests: IntCounterVec,
+    rpc_duration: HistogramVec,
+    posture_seconds: CounterVec,
+    posture: Mutex<Option<PostureClock>>,
+}
+
+impl Metrics {
+    pub fn new(thresholds: PostureThresholds) -> Arc<Self> {
+        let registry = Registry::new_custom(Some(PROJECT_NAME.to_owned()), None)
+            .expect("Invalid metric namespace");
+        fn register<M: prometheus::core::Collector + Clone + 'static>(
+            registry: &Registry,
+            metric: prometheus::Result<M>,
+        ) -> M {
+            let metric = metric.expect("Invalid metric");
+            registry
+                .register(Box::new(metric.clone()))
+                .expect("Duplicate metric");
+            metric
+        }
+        let histogram = |name: &str, help: &str, buckets: &[f64]| {
+            HistogramOpts::new(name, help).buckets(buckets.to_vec())
+        };
+        Arc::new(Metrics {
+            thresholds,
+            height: register(
+                &registry,
+                Gauge::new("height_cm", "Current height of the desk"),
+            ),
+            desk_connected: register(
+                &registry,
+                IntGauge::new("desk_connected", "Whether the desk is connected"),
+            ),
+            moves: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new("moves_total", "Finished moves by outcome"),
+                    &["outcome"],
+                ),
+            ),
+            target_error: register(
+                &registry,
+                Histogram::with_opts(histogram(
+                    "move_target_error_cm",
+                    "Distance between the target and the final height of reached moves",
+                    TARGET_ERROR_BUCKETS_CM,
+                )),
+            ),
+            move_duration: register(
+                &registry,
+                Histogram::with_opts(histogram(
+                    "move_duration_seconds",
+                    "Duration of finished moves",
+                    MOVE_DURATION_BUCKETS_S,
+                )),
+            ),
+            reconnects: register(
+                &registry,
+                IntCounter::new("ble_reconnects_total", "Reconnections to the desk"),
+            ),
+            notifications: register(
+                &registry,
+                IntCounter::new(
+                    "ble_notifications_total",
+                    "State notifications received from the desk",
+                ),
+            ),
+            rpc_requests: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new("rpc_requests_total", "RPC requests by method and status"),
+                    &["method", "code"],
+                ),
+            ),
+            rpc_duration: register(
+                &registry,
+                HistogramVec::new(
+                    HistogramOpts::new(
+                        "rpc_duration_seconds",
+                        "RPC latency until the response headers, by method",
+                    ),
+                    &["method"],
+                ),
+            ),
+            posture_seconds: register(
+                &registry,
+                CounterVec::new(
+                    Opts::new(
+                        "posture_seconds_total",
+                        "Time spent sitting and standing, by the posture thresholds",
+                    )
+                    .const_label(
+                        "sitting_threshold_cm",
+                        format!("{:.1}", thresholds.sitting.to_cm()),
+                    )
+                    .const_label(
+                        "standing_threshold_cm",
+                        format!("{:.1}", thresholds.standing.to_cm()),
+                    ),
+                    &["posture"],
+                ),
+            ),
+            posture: Mutex::new(None),
+            registry,
+        })
+    }
+
+    /// Counter for the desk to increment on every BLE notification
+    pub fn notifications(&self) -> IntCounter {
+        self.notifications.clone()
+    }
+
+    /// Metrics in the Prometheus text format
+    pub fn render(&self) -> String {
+        self.update_posture(None);
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .expect("Error encoding metrics");
+        String::from_utf8(buffer).expect("Metrics are not UTF-8")
+    }
+
+    /// Feed the desk and move metrics until both streams end
+    pub async fn record(
+        self: Arc<Self>,
+        mut states: impl Stream<Item = (Position, Velocity)> + Unpin,
+        mut events: EventStream,
+    ) {
+        self.desk_connected.set(1);
+        // start times of moves in progress
+        let mut moves = HashMap::new();
+        loop {
+            select! {
+                Some((position, _)) = states.next() => {
+                    self.height.set(position.to_cm().into());
+                    self.update_posture(Some(position));
+                }
+                Some(event) = events.next() => match event {
+                    Event::Connection(state) => {
+                        let connected = state == ConnectionState::Connected;
+                        if connected {
+                            // the initial connection happens before metrics are recorded
+                            self.reconnects.inc();
+                        }
+                        self.desk_connected.set(connected.into());
+                    }
+                    Event::Move { id, outcome, target, position, .. } => {
+                        self.record_move(&mut moves, id, outcome, target, position);
+                    }
+                    Event::ManualMovement { .. } | Event::ConfigReloaded | Event::Goal { .. } => {}
+                },
+                else => break,
+            }
+        }
+    }
+
+    fn record_move(
+        &self,
+        moves: &mut HashMap<u64, Instant>,
+        id: u64,
+        outcome: MoveOutcome,
+        target: Position,
+        position: Position,
+    ) {
+        let outcome = match outcome {
+            MoveOutcome::Started => {
+                moves.insert(id, Instant::now());
+                return;
+            }
+            MoveOutcome::Reached => {
+                let error = (position.to_cm() - target.to_cm()).abs();
+                self.target_error.observe(error.into());
+                "reached"
+            }
+            MoveOutcome::Aborted => "aborted",
+            MoveOutcome::Stalled => "stalled",
+            MoveOutcome::Preempted { .. } => "preempted",
+        };
+        self.moves.with_label_values(&[outcome]).inc();
+        if let Some(started) = moves.remove(&id) {
+            self.move_duration.observe(started.elapsed().as_secs_f64());
+        }
+    }
+
+    /// Account the time since the last update, switching posture if `position` is given
+    fn update_posture(&self, position: Option<Position>) {
+        let now = Instant::now();
+        let mut posture = self.posture.lock().unwrap();
+        if let Some(clock) = posture.as_mut() {
+            self.posture_seconds
+                .with_label_values(&[clock.posture.name()])
+                .inc_by((now - clock.since).as_secs_f64());
+            clock.since = now;
+        }
+        if let Some(position) = position {
+            let previous = posture.as_ref().map(|clock| clock.posture);
+            *posture = Some(PostureClock {
+                posture: self.thresholds.classify(position, previous),
+                since: now,
+            });
+        }
+    }
+
+    fn observe_rpc(&self, method: &str, code: Code, started: Instant) {
+        self.rpc_requests
+            .with_label_values(&[method, &format!("{:?}", code)])
+            .inc();
+        self.rpc_duration
+            .with_label_values(&[method])
+            .observe(started.elapsed().as_secs_f64());
+    }
+}
+
+/// Counts gRPC requests and measures their latency by method
+#[derive(Clone)]
+pub struct RpcMetricsLayer {
+    metrics: Arc<Metrics>,
+}
+
+impl RpcMetricsLayer {
+    pub fn new(metrics: Arc<Metrics>) -> Self {
+        RpcMetricsLayer { metrics }
+    }
+}
+
+impl<S> Layer<S> for RpcMetricsLayer {
+    type Service = RpcMetrics<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RpcMetrics {
+            inner,
+            metrics: self.metrics.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RpcMetrics<S> {
+    inner: S,
+    metrics: Arc<Metrics>,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for RpcMetrics<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<ReqBody>) -> Self::Future {
+        let method = method_label(request.uri().path());
+        let started = Instant::now();
+        let metrics = self.metrics.clone();
+        let response = self.inner.call(request);
+        Box::pin(async move {
+            let response = response.await?;
+            // errors are sent as trailers-only responses, success in the trailers after the body
+            let code = response
+                .headers()
+                .get("grpc-status")
+                .map(|value| Code::from_bytes(value.as_bytes()))
+                .unwrap_or(Code::Ok);
+            metrics.observe_rpc(method, code, started);
+            Ok(response)
+        })
+    }
+}
+
+/// Methods of `DeskService`, the only values of the method label besides `unknown`
+const RPC_METHODS: &[&str] = &[
+    "GetState",
+    "SubscribeState",
+    "SubscribeEvents",
+    "Stop",
+    "StartMove",
+    "AcquireControl",
+    "ReleaseControl",
+    "ListPresets",
+    "SetPreset",
+    "DeletePreset",
+    "ReloadConfig",
+    "QueryHistory",
+    "GetUsageStats",
+    "Export",
+    "GetGoalProgress",
+    "ListSchedule",
+    "SetScheduleEntry",
+    "DeleteScheduleEntry",
+    "PauseScheduleEntry",
+    "StartCycle",
+    "StopCycle",
+    "GetCycle",
+    "GetLock",
+    "SetLock",
+];
+
+/// Method of a `/package.Service/Method` path.
+/// Requests are counted before authentication, so any other path is `unknown`
+/// to bound the label cardinality.
+fn method_label(path: &str) -> &'static str {
+    path.strip_prefix('/')
+        .and_then(|path| path.strip_prefix(<DeskServiceServer<DeskService> as NamedService>::NAME))
+        .and_then(|path| path.strip_prefix('/'))
+        .and_then(|method| RPC_METHODS.iter().find(|known| **known == method))
+        .copied()
+        .unwrap_or("unknown")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn method_label_of_rpc_path() {
+        assert_eq!(
+            method_label("/desk_service.DeskService/GetState"),
+            "GetState"
+        );
+        assert_eq!(
+            method_label("/desk_service.DeskService/StartMove"),
+            "StartMove"
+        );
+    }
+
+    #[test]
+    fn method_label_of_unknown_path() {
+        assert_eq!(method_label("/desk_service.DeskService/"), "unknown");
+        assert_eq!(method_label("desk_service.DeskService/GetState"), "unknown");
+        assert_eq!(method_label("/other.Service/GetState"), "unknown");
+        assert_eq!(method_label("/grpc.health.v1.Health/Check"), "unknown");
+    }
+
+    #[test]
+    fn method_label_of_unknown_method() {
+        assert_eq!(
+            method_label("/desk_service.DeskService/Xq7vRandomMethod"),
+            "unknown"
+        );
+        assert_eq!(
+            method_label("/desk_service.DeskService/GetState/extra"),
+            "unknown"
+        );
+    }
+
+    #[test]
+    fn rpc_methods_match_the_proto() {
+        let proto = include_str!("../../proto/desk_service.proto");
+        let declared: Vec<_> = proto
+            .lines()
+            .filter_map(|line| line.trim().strip_prefix("rpc "))
+            .filter_map(|line| line.split('(').next())
+            .collect();
+        assert_eq!(declared, RPC_METHODS);
+    }
+}
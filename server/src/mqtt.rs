@@ -0,0 +1,402 @@
+use crate::{
+    auth::{AuthError, Authenticator},
+    config::{HomeAssistantEntity, MqttConfig},
+    events::{self, ConnectionState, Event, EventSender},
+    service::{DeskService, Peer},
+    utils::Position,
+};
+use desklink_common::rpc::{
+    desk_service_server::DeskService as DeskServiceTrait, StartMoveRequest, StopRequest,
+    SubscribeStateRequest, SubscribeStateResponse,
+};
+use futures::{future, stream, StreamExt};
+use rumqttc::{
+    AsyncClient, ClientError, Event as MqttEvent, LastWill, MqttOptions, Packet, Publish, QoS,
+};
+use serde_json::json;
+use std::{sync::Arc, time::Duration};
+use tokio::{
+    select,
+    time::{self, Instant},
+};
+use tonic::{Request, Status};
+use tracing::{debug, info, warn};
+
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+const SUBSCRIBE_BACKOFF_MIN: Duration = Duration::from_secs(1);
+const SUBSCRIBE_BACKOFF_MAX: Duration = Duration::from_secs(30);
+/// Number of outgoing messages queued for the event loop
+const REQUEST_CAPACITY: usize = 64;
+/// Publication errors are logged at most once per interval
+const PUBLISH_WARNING_INTERVAL: Duration = Duration::from_secs(60);
+const MIN_STATE_INTERVAL_MS: u32 = 250;
+const MIN_POSITION_CHANGE: f32 = 0.1;
+
+const ONLINE: &str = "online";
+const OFFLINE: &str = "offline";
+const COMMAND_OPEN: &str = "OPEN";
+const COMMAND_CLOSE: &str = "CLOSE";
+const COMMAND_STOP: &str = "STOP";
+
+/**
+ * Publishes the desk state to MQTT and forwards commands from MQTT to the RPC handlers.
+ *
+ * State topics under the prefix: `position` (cm), `velocity` (cm/s), `moving` (`true`/`false`),
+ * `state` (`opening`/`closing`/`stopped`) and `availability` (`online`/`offline`).
+ * Command topics: `height/set` (cm), `preset/set` (preset name) and `command` (`OPEN`/`CLOSE`/`STOP`).
+ * With authentication enabled, requests carry the token of the bridge unless it is trusted.
+ */
+pub struct MqttBridge {
+    config: MqttConfig,
+    device_id: String,
+    service: Arc<DeskService>,
+    authenticator: Authenticator,
+    event_publisher: EventSender,
+}
+
+type StateStream = stream::BoxStream<'static, SubscribeStateResponse>;
+
+/// Latest values not published yet, replacing older ones instead of queueing them
+#[derive(Default)]
+struct Pending {
+    availability: Option<&'static str>,
+    state: Option<SubscribeStateResponse>,
+}
+
+impl MqttBridge {
+    /// `device_id` identifies the desk in Home Assistant
+    pub fn new(
+        config: MqttConfig,
+        device_id: String,
+        service: Arc<DeskService>,
+        authenticator: Authenticator,
+        event_publisher: EventSender,
+    ) -> Self {
+        MqttBridge {
+            config,
+            device_id,
+            service,
+            authenticator,
+            event_publisher,
+        }
+    }
+
+    fn topic(&self, name: &str) -> String {
+        format!("{}/{}", self.config.topic_prefix, name)
+    }
+
+    pub async fn run(self) {
+        let mut options =
+            MqttOptions::new(&self.config.client_id, &self.config.host, self.config.port);
+        options.set_keep_alive(KEEP_ALIVE);
+        options.set_last_will(LastWill::new(
+            self.topic("availability"),
+            OFFLINE,
+            QoS::AtLeastOnce,
+            true,
+        ));
+        if let Some((username, password)) = &self.config.credentials {
+            options.set_credentials(username, password);
+        }
+        let (client, mut eventloop) = AsyncClient::new(options, REQUEST_CAPACITY);
+
+        let mut events = events::subscribe(&self.event_publisher);
+        let mut states = None;
+        let mut subscribe_at = Instant::now();
+        let mut backoff = SUBSCRIBE_BACKOFF_MIN;
+        let mut desk_connected = true;
+        let mut broker_connected = false;
+        let mut reconnect_at: Option<Instant> = None;
+        let mut pending = Pending::default();
+        let mut last_warning: Option<Instant> = None;
+        loop {
+            select! {
+                event = eventloop.poll(), if reconnect_at.is_none() => match event {
+                    Ok(MqttEvent::Incoming(Packet::ConnAck(_))) => {
+                        info!(host = %self.config.host, "Connected to MQTT broker");
+                        broker_connected = true;
+                        self.announce(&client);
+                        pending.availability = Some(availability(desk_connected));
+                    }
+                    Ok(MqttEvent::Incoming(Packet::Publish(publish))) => self.handle(publish),
+                    Ok(_) => {}
+                    Err(e) => {
+                        warn!("MQTT connection error, retrying in {:?}: {}", RECONNECT_DELAY, e);
+                        broker_connected = false;
+                        reconnect_at = Some(Instant::now() + RECONNECT_DELAY);
+                    }
+                },
+                _ = deadline(reconnect_at) => reconnect_at = None,
+                _ = time::sleep_until(subscribe_at), if states.is_none() => {
+                    match self.subscribe_state().await {
+                        Ok(stream) => {
+                            states = Some(stream);
+                            backoff = SUBSCRIBE_BACKOFF_MIN;
+                        }
+                        Err(status) => {
+                            warn!(
+                                "Cannot subscribe to the desk state, retrying in {:?}: {}",
+                                backoff,
+                                status.message(),
+                            );
+                            subscribe_at = Instant::now() + backoff;
+                            backoff = Ord::min(backoff * 2, SUBSCRIBE_BACKOFF_MAX);
+                        }
+                    }
+                }
+                state = next_state(&mut states) => match state {
+                    Some(state) => pending.state = Some(state),
+                    None => {
+                        states = None;
+                        subscribe_at = Instant::now();
+                    }
+                },
+                Some(event) = events.next() => {
+                    if let Event::Connection(state) = event {
+                        desk_connected = state == ConnectionState::Connected;
+                        pending.availability = Some(availability(desk_connected));
+                    }
+                }
+            }
+
+            // retried after the next event of the event loop, which frees queue space
+            if broker_connected {
+                if let Err(e) = self.flush(&client, &mut pending) {
+                    if last_warning.is_none_or(|at| at.elapsed() >= PUBLISH_WARNING_INTERVAL) {
+                        warn!(
+                            "Error publishing to MQTT, the latest state is retried: {}",
+                            e
+                        );
+                        last_warning = Some(Instant::now());
+                    }
+                }
+            }
+        }
+    }
+
+    async fn subscribe_state(&self) -> Result<StateStream, Status> {
+        let request = self.request(SubscribeStateRequest {
+            min_interval_ms: MIN_STATE_INTERVAL_MS,
+            min_position_change: MIN_POSITION_CHANGE,
+            moving_only: false,
+        })?;
+        let response = self.service.subscribe_state(request).await?;
+        Ok(response
+            .into_inner()
+            .filter_map(|state| async move { state.ok() })
+            .boxed())
+    }
+
+    /// Requests from MQTT carry the token of the bridge, or no identity if it is trusted
+    fn request<T>(&self, message: T) -> Result<Request<T>, AuthError> {
+        let mut request = Request::new(message);
+        request.extensions_mut().insert(Peer("mqtt".to_owned()));
+        if !self.config.trusted {
+            let authorization = self
+                .config
+                .token
+                .as_ref()
+                .map(|token| format!("Bearer {}", token));
+            if let Some(identity) = self.authenticator.authenticate(authorization.as_deref())? {
+                request.extensions_mut().insert(identity);
+            }
+        }
+        Ok(request)
+    }
+
+    /// Publish discovery payloads and subscribe to commands, after every (re)connection
+    fn announce(&self, client: &AsyncClient) {
+        for name in ["height/set", "preset/set", "command"] {
+            if let Err(e) = client.try_subscribe(self.topic(name), QoS::AtLeastOnce) {
+                warn!("Error subscribing to MQTT topic: {}", e);
+            }
+        }
+        if let Some(discovery_prefix) = &self.config.discovery_prefix {
+            let (component, config) = self.discovery_config();
+            let topic = format!(
+                "{}/{}/{}/config",
+                discovery_prefix, component, self.device_id
+            );
+            if let Err(e) = client.try_publish(topic, QoS::AtLeastOnce, true, config.to_string()) {
+                warn!("Error publishing Home Assistant discovery: {}", e);
+            }
+        }
+    }
+
+    fn discovery_config(&self) -> (&'static str, serde_json::Value) {
+        let device = json!({
+            "identifiers": [self.device_id],
+            "name": "Desk",
+            "model": "desklink",
+            "sw_version": env!("CARGO_PKG_VERSION"),
+        });
+        let range = Position::MAX_CM - Position::MIN_CM;
+        match self.config.entity {
+            HomeAssistantEntity::Number => (
+                "number",
+                json!({
+                    "name": "Desk height",
+                    "unique_id": format!("{}_height", self.device_id),
+                    "state_topic": self.topic("position"),
+                    "command_topic": self.topic("height/set"),
+                    "availability_topic": self.topic("availability"),
+                    "min": Position::MIN_CM,
+                    "max": Position::MAX_CM,
+                    "step": 0.5,
+                    "unit_of_measurement": "cm",
+                    "mode": "slider",
+                    "device": device,
+                }),
+            ),
+            // Home Assistant cover positions are percentages
+            HomeAssistantEntity::Cover => (
+                "cover",
+                json!({
+                    "name": "Desk",
+                    "unique_id": format!("{}_cover", self.device_id),
+                    "command_topic": self.topic("command"),
+                    "payload_open": COMMAND_OPEN,
+                    "payload_close": COMMAND_CLOSE,
+                    "payload_stop": COMMAND_STOP,
+                    "state_topic": self.topic("state"),
+                    "position_topic": self.topic("position"),
+                    "position_template": format!(
+                        "{{{{ ((value | float - {}) * 100 / {}) | round(0) }}}}",
+                        Position::MIN_CM,
+                        range,
+                    ),
+                    "set_position_topic": self.topic("height/set"),
+                    "set_position_template": format!(
+                        "{{{{ ({} + position * {} / 100) | round(1) }}}}",
+                        Position::MIN_CM,
+                        range,
+                    ),
+                    "availability_topic": self.topic("availability"),
+                    "device": device,
+                }),
+            ),
+        }
+    }
+
+    /// Publish the pending values, those not queued stay pending
+    fn flush(&self, client: &AsyncClient, pending: &mut Pending) -> Result<(), ClientError> {
+        if let Some(availability) = pending.availability {
+            self.publish(client, "availability", availability)?;
+            pending.availability = None;
+        }
+        if let Some(state) = &pending.state {
+            self.publish_state(client, state)?;
+            pending.state = None;
+        }
+        Ok(())
+    }
+
+    fn publish(
+        &self,
+        client: &AsyncClient,
+        name: &str,
+        payload: impl Into<Vec<u8>>,
+    ) -> Result<(), ClientError> {
+        // the event loop runs in this task, so waiting for queue space would deadlock
+        client.try_publish(self.topic(name), QoS::AtLeastOnce, true, payload)
+    }
+
+    fn publish_state(
+        &self,
+        client: &AsyncClient,
+        state: &SubscribeStateResponse,
+    ) -> Result<(), ClientError> {
+        let moving = state.velocity != 0.0;
+        let direction = if state.velocity > 0.0 {
+            "opening"
+        } else if state.velocity < 0.0 {
+            "closing"
+        } else {
+            "stopped"
+        };
+        self.publish(client, "position", format!("{:.1}", state.position))?;
+        self.publish(client, "velocity", format!("{:.2}", state.velocity))?;
+        self.publish(client, "moving", moving.to_string())?;
+        self.publish(client, "state", direction)
+    }
+
+    fn handle(&self, publish: Publish) {
+        let payload = String::from_utf8_lossy(&publish.payload).trim().to_owned();
+        debug!(topic = %publish.topic, %payload, "MQTT command");
+        let name = publish
+            .topic
+            .strip_prefix(&self.config.topic_prefix)
+            .and_then(|name| name.strip_prefix('/'));
+        let start_move = |target: f32, preset: String| StartMoveRequest {
+            target,
+            preset,
+            ..Default::default()
+        };
+        match (name, payload.as_str()) {
+            (Some("height/set"), payload) => match payload.parse::<f32>() {
+                Ok(target) => self.start_move(start_move(target, String::new())),
+                Err(_) => warn!(%payload, "Invalid height from MQTT"),
+            },
+            (Some("preset/set"), preset) => self.start_move(start_move(0.0, preset.to_owned())),
+            (Some("command"), COMMAND_OPEN) => {
+                self.start_move(start_move(Position::MAX_CM, String::new()))
+            }
+            (Some("command"), COMMAND_CLOSE) => {
+                self.start_move(start_move(Position::MIN_CM, String::new()))
+            }
+            (Some("command"), COMMAND_STOP) => self.stop(),
+            _ => warn!(topic = %publish.topic, %payload, "Unknown MQTT command"),
+        }
+    }
+
+    fn start_move(&self, message: StartMoveRequest) {
+        let request = match self.request(message) {
+            Ok(request) => request,
+            Err(e) => return warn!("Move from MQTT rejected: {}", e),
+        };
+        let service = self.service.clone();
+        tokio::spawn(async move {
+            if let Err(status) = service.start_move(request).await {
+                warn!("Move from MQTT failed: {}", status.message());
+            }
+        });
+    }
+
+    fn stop(&self) {
+        let request = match self.request(StopRequest {}) {
+            Ok(request) => request,
+            Err(e) => return warn!("Stop from MQTT rejected: {}", e),
+        };
+        let service = self.service.clone();
+        tokio::spawn(async move {
+            if let Err(status) = service.stop(request).await {
+                warn!("Stop from MQTT failed: {}", status.message());
+            }
+        });
+    }
+}
+
+/// Pending while not subscribed, `None` once the stream ends
+async fn next_state(states: &mut Option<StateStream>) -> Option<SubscribeStateResponse> {
+    match states {
+        Some(states) => states.next().await,
+        None => future::pending().await,
+    }
+}
+
+/// Pending without a deadline
+async fn deadline(at: Option<Instant>) {
+    match at {
+        Some(at) => time::sleep_until(at).await,
+        None => future::pending().await,
+    }
+}
+
+fn availability(desk_connected: bool) -> &'static str {
+    if desk_connected {
+        ONLINE
+    } else {
+        OFFLINE
+    }
+}
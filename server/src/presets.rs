@@ -0,0 +1,153 @@
+use crate::utils::{Position, PositionError};
+use std::{
+    collections::BTreeMap,
+    io,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+};
+use thiserror::Error;
+use tracing::info;
+
+#[derive(Error, Debug)]
+pub enum PresetError {
+    #[error("Preset `{0}` not found")]
+    NotFound(String),
+
+    #[error("Preset `{0}` is defined in the config file")]
+    FromConfig(String),
+
+    #[error(transparent)]
+    InvalidPosition(#[from] PositionError),
+
+    #[error("IO error: `{path}`")]
+    IoError {
+        path: PathBuf,
+        #[source]
+        error: io::Error,
+    },
+
+    #[error("TOML parsing error")]
+    TomlError(#[from] toml::de::Error),
+
+    #[error("TOML serialization error")]
+    TomlSerializeError(#[from] toml::ser::Error),
+}
+
+pub type SharedPresets = Arc<RwLock<Presets>>;
+
+#[derive(Clone, Debug)]
+pub struct Preset {
+    pub name: String,
+    pub position: Position,
+    pub from_config: bool,
+}
+
+/**
+ * Named positions.
+ * Presets from the config file are read-only at runtime,
+ * other presets are stored in the storage directory.
+ */
+pub struct Presets {
+    config: BTreeMap<String, Position>,
+    stored: BTreeMap<String, Position>,
+    path: PathBuf,
+}
+
+impl Presets {
+    pub fn load(config: BTreeMap<String, Position>, path: PathBuf) -> Result<Self, PresetError> {
+        let stored: BTreeMap<String, f32> = match std::fs::read_to_string(&path) {
+            Ok(content) => toml::from_str(&content)?,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(error) => return Err(PresetError::IoError { path, error }),
+        };
+        let stored = stored
+            .into_iter()
+            .map(|(name, cm)| Ok((name, Position::from_cm(cm)?)))
+            .collect::<Result<_, PresetError>>()?;
+        Ok(Presets {
+            config,
+            stored,
+            path,
+        })
+    }
+
+    pub fn shared(self) -> SharedPresets {
+        Arc::new(RwLock::new(self))
+    }
+
+    /// Replace the presets from the config file
+    pub fn set_config(&mut self, config: BTreeMap<String, Position>) {
+        self.config = config;
+    }
+
+    pub fn get(&self, name: &str) -> Result<Position, PresetError> {
+        self.config
+            .get(name)
+            .or_else(|| self.stored.get(name))
+            .copied()
+            .ok_or_else(|| PresetError::NotFound(name.to_owned()))
+    }
+
+    pub fn list(&self) -> Vec<Preset> {
+        let config = self.config.iter().map(|(name, position)| Preset {
+            name: name.clone(),
+            position: *position,
+            from_config: true,
+        });
+        let stored = self
+            .stored
+            .iter()
+            .filter(|(name, _)| !self.config.contains_key(*name))
+            .map(|(name, position)| Preset {
+                name: name.clone(),
+                position: *position,
+                from_config: false,
+            });
+        config.chain(stored).collect()
+    }
+
+    pub fn set(&mut self, name: String, position: Position) -> Result<(), PresetError> {
+        if self.config.contains_key(&name) {
+            return Err(PresetError::FromConfig(name));
+        }
+        info!(%name, %position, "Setting preset");
+        let previous = self.stored.insert(name.clone(), position);
+        self.save().inspect_err(|_| {
+            match previous {
+                Some(previous) => self.stored.insert(name, previous),
+                None => self.stored.remove(&name),
+            };
+        })
+    }
+
+    pub fn remove(&mut self, name: &str) -> Result<(), PresetError> {
+        if self.config.contains_key(name) {
+            return Err(PresetError::FromConfig(name.to_owned()));
+        }
+        info!(%name, "Removing preset");
+        let previous = self
+            .stored
+            .remove(name)
+            .ok_or_else(|| PresetError::NotFound(name.to_owned()))?;
+        self.save().inspect_err(|_| {
+            self.stored.insert(name.to_owned(), previous);
+        })
+    }
+
+    fn save(&self) -> Result<(), PresetError> {
+        let stored: BTreeMap<&str, f32> = self
+            .stored
+            .iter()
+            .map(|(name, position)| (name.as_str(), position.to_cm()))
+            .collect();
+        let content = toml::to_string(&stored)?;
+        let io_error = |error| PresetError::IoError {
+            path: self.path.clone(),
+            error,
+        };
+        if let Some(directory) = self.path.parent() {
+            std::fs::create_dir_all(directory).map_err(io_error)?;
+        }
+        std::fs::write(&self.path, content).map_err(io_error)
+    }
+}
@@ -0,0 +1,85 @@
+use crate::{
+    auth::Authenticator,
+    calendar::Calendar,
+    config::{Config, ConfigError},
+    events::{Event, EventSender},
+    lock::Locks,
+    presets::SharedPresets,
+    schedule::Scheduler,
+};
+use std::sync::{Arc, Mutex};
+use tracing::{info, warn};
+
+/// Re-reads the config file and applies the settings that can change at runtime
+pub struct ConfigReloader {
+    config: Mutex<Config>,
+    authenticator: Authenticator,
+    presets: SharedPresets,
+    scheduler: Arc<Scheduler>,
+    locks: Arc<Locks>,
+    calendar: Arc<Calendar>,
+    event_publisher: EventSender,
+}
+
+impl ConfigReloader {
+    pub fn new(
+        config: Config,
+        authenticator: Authenticator,
+        presets: SharedPresets,
+        scheduler: Arc<Scheduler>,
+        locks: Arc<Locks>,
+        calendar: Arc<Calendar>,
+        event_publisher: EventSender,
+    ) -> Self {
+        ConfigReloader {
+            config: Mutex::new(config),
+            authenticator,
+            presets,
+            scheduler,
+            locks,
+            calendar,
+            event_publisher,
+        }
+    }
+
+    pub fn reload(&self) -> Result<(), ConfigError> {
+        let new_config = Config::get()?;
+        let mut config = self.config.lock().unwrap();
+        // sections only read at startup, the others are applied below
+        let restart: Vec<_> = [
+            ("log", new_config.log != config.log),
+            ("desk", new_config.desk != config.desk),
+            ("server", new_config.server != config.server),
+            ("http", new_config.http != config.http),
+            ("mqtt", new_config.mqtt != config.mqtt),
+            ("tracing", new_config.tracing != config.tracing),
+            ("history", new_config.history != config.history),
+            ("goal", new_config.goal != config.goal),
+            ("storage", new_config.storage != config.storage),
+        ]
+        .into_iter()
+        .filter_map(|(section, changed)| changed.then_some(section))
+        .collect();
+        if !restart.is_empty() {
+            warn!(
+                "Changes to the {} sections require a restart",
+                restart.join(", ")
+            );
+        }
+        self.authenticator
+            .set_tokens(new_config.auth.tokens.clone());
+        self.presets
+            .write()
+            .unwrap()
+            .set_config(new_config.presets.clone());
+        self.scheduler.set_config(new_config.scheduler.clone());
+        self.locks.set_config(new_config.lock.clone());
+        self.calendar.set_config(new_config.calendar.clone());
+        *config = new_config;
+        info!("Config reloaded");
+        self.event_publisher
+            .send(Event::ConfigReloaded)
+            .unwrap_or(0);
+        Ok(())
+    }
+}
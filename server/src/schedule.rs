@@ -0,0 +1,785 @@
+use crate::{
+    audit::{Action, AuditLog},
+    config::SchedulerConfig,
+    events::{Event, EventStream},
+    history::now_ms,
+    presets::{Presets, SharedPresets},
+    service::{Automatic, DeskService, Peer},
+    utils::{Position, PositionError, Velocity},
+};
+use chrono::{Datelike, Duration as ChronoDuration, Local, NaiveTime, TimeZone, Weekday};
+use desklink_common::rpc::{
+    desk_service_server::DeskService as DeskServiceTrait, StartMoveRequest,
+};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt::{self, Display, Formatter},
+    io,
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use thiserror::Error;
+use tokio::{
+    select,
+    sync::{watch, Notify},
+    time::{self, Instant},
+};
+use tonic::Request;
+use tracing::{info, warn};
+
+/// Shortest interval of repeating entries
+const MIN_INTERVAL: Duration = Duration::from_secs(60);
+/// Longest sleep between checks, so that wall clock changes are noticed
+const MAX_SLEEP: Duration = Duration::from_secs(60);
+
+const WEEKDAYS: [(Weekday, &str); 7] = [
+    (Weekday::Mon, "mon"),
+    (Weekday::Tue, "tue"),
+    (Weekday::Wed, "wed"),
+    (Weekday::Thu, "thu"),
+    (Weekday::Fri, "fri"),
+    (Weekday::Sat, "sat"),
+    (Weekday::Sun, "sun"),
+];
+
+#[derive(Error, Debug)]
+pub enum ScheduleError {
+    #[error("Schedule entry `{0}` not found")]
+    NotFound(String),
+
+    #[error("Schedule entry `{0}` is defined in the config file")]
+    FromConfig(String),
+
+    #[error("Invalid schedule time `{0}`, expected `daily|weekdays|weekends|mon,tue,.. HH:MM` or `every <duration>` of at least a minute")]
+    InvalidWhen(String),
+
+    #[error("Invalid schedule target `{0}`")]
+    InvalidTarget(String, #[source] PositionError),
+
+    #[error("Schedule entry has no target")]
+    NoTarget,
+
+    #[error("IO error: `{path}`")]
+    IoError {
+        path: PathBuf,
+        #[source]
+        error: io::Error,
+    },
+
+    #[error("TOML parsing error")]
+    TomlError(#[from] toml::de::Error),
+
+    #[error("TOML serialization error")]
+    TomlSerializeError(#[from] toml::ser::Error),
+}
+
+/// Days of the week, as a bit per day from Monday
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Days(u8);
+
+impl Days {
+    const DAILY: Days = Days(0b111_1111);
+    const WEEKDAYS: Days = Days(0b001_1111);
+    const WEEKENDS: Days = Days(0b110_0000);
+
+    pub fn contains(self, day: Weekday) -> bool {
+        self.0 & (1 << day.num_days_from_monday()) != 0
+    }
+
+    /// `daily`, `weekdays`, `weekends` or a list of days like `mon,tue`
+    pub fn parse(s: &str) -> Option<Days> {
+        match s.trim() {
+            "daily" => Some(Days::DAILY),
+            "weekdays" => Some(Days::WEEKDAYS),
+            "weekends" => Some(Days::WEEKENDS),
+            list => list.split(',').try_fold(Days(0), |days, day| {
+                let day = day.trim().parse::<Weekday>().ok()?;
+                Some(Days(days.0 | 1 << day.num_days_from_monday()))
+            }),
+        }
+    }
+}
+
+impl Display for Days {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match *self {
+            Days::DAILY => write!(f, "daily"),
+            Days::WEEKDAYS => write!(f, "weekdays"),
+            Days::WEEKENDS => write!(f, "weekends"),
+            days => {
+                let names: Vec<_> = WEEKDAYS
+                    .iter()
+                    .filter(|(day, _)| days.contains(*day))
+                    .map(|(_, name)| *name)
+                    .collect();
+                write!(f, "{}", names.join(","))
+            }
+        }
+    }
+}
+
+/// When a schedule entry runs
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Trigger {
+    /// At a local time on some days of the week
+    At { days: Days, time: NaiveTime },
+    /// Repeatedly, from when the scheduler starts or the entry is added or resumed
+    Every(Duration),
+}
+
+impl Trigger {
+    /// Unix time in milliseconds of the first run after `after_ms`
+    pub fn next_after(&self, after_ms: i64) -> Option<i64> {
+        self.next_after_in(&Local, after_ms)
+    }
+
+    fn next_after_in<Z: TimeZone>(&self, zone: &Z, after_ms: i64) -> Option<i64> {
+        match self {
+            Trigger::Every(interval) => Some(after_ms + interval.as_millis() as i64),
+            Trigger::At { days, time } => {
+                let after = zone.timestamp_millis_opt(after_ms).single()?;
+                // two weeks, in case a single day a week is skipped by DST
+                (0..=14)
+                    .map(|offset| after.date_naive() + ChronoDuration::days(offset))
+                    .filter(|date| days.contains(date.weekday()))
+                    // local times skipped by DST do not run
+                    .filter_map(|date| zone.from_local_datetime(&date.and_time(*time)).earliest())
+                    .map(|run| run.timestamp_millis())
+                    .find(|&run| run > after_ms)
+            }
+        }
+    }
+}
+
+impl FromStr for Trigger {
+    type Err = ScheduleError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ScheduleError::InvalidWhen(s.to_owned());
+        if let Some(interval) = s.trim().strip_prefix("every ") {
+            let interval = humantime::parse_duration(interval.trim()).map_err(|_| invalid())?;
+            if interval < MIN_INTERVAL {
+                return Err(invalid());
+            }
+            return Ok(Trigger::Every(interval));
+        }
+        let (days, time) = s.trim().rsplit_once(' ').ok_or_else(invalid)?;
+        let time = NaiveTime::parse_from_str(time, "%H:%M").map_err(|_| invalid())?;
+        let days = Days::parse(days).ok_or_else(invalid)?;
+        Ok(Trigger::At { days, time })
+    }
+}
+
+impl Display for Trigger {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Trigger::Every(interval) => {
+                write!(f, "every {}", humantime::format_duration(*interval))
+            }
+            Trigger::At { days, time } => write!(f, "{} {}", days, time.format("%H:%M")),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Target {
+    Position(Position),
+    /// Resolved when the entry runs
+    Preset(String),
+}
+
+impl FromStr for Target {
+    type Err = ScheduleError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().parse::<f32>() {
+            Ok(cm) => Position::from_cm(cm)
+                .map(Target::Position)
+                .map_err(|e| ScheduleError::InvalidTarget(s.to_owned(), e)),
+            Err(_) if s.trim().is_empty() => Err(ScheduleError::NoTarget),
+            Err(_) => Ok(Target::Preset(s.trim().to_owned())),
+        }
+    }
+}
+
+impl Display for Target {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::Position(position) => write!(f, "{}", position.to_cm()),
+            Target::Preset(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+/// Moves the desk to a target, or alternates between several
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+    pub when: Trigger,
+    pub targets: Vec<Target>,
+}
+
+impl Entry {
+    pub fn parse(when: &str, targets: &[String]) -> Result<Self, ScheduleError> {
+        let targets = targets
+            .iter()
+            .map(|target| target.parse())
+            .collect::<Result<Vec<_>, _>>()?;
+        if targets.is_empty() {
+            return Err(ScheduleError::NoTarget);
+        }
+        Ok(Entry {
+            when: when.parse()?,
+            targets,
+        })
+    }
+}
+
+/// Textual form of an entry, in the config and storage files
+#[derive(Serialize, Deserialize)]
+pub struct EntrySpec {
+    pub when: String,
+    pub to: Targets,
+}
+
+/// A single target, or several to alternate between
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Targets {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl TryFrom<EntrySpec> for Entry {
+    type Error = ScheduleError;
+
+    fn try_from(spec: EntrySpec) -> Result<Self, Self::Error> {
+        let targets = match spec.to {
+            Targets::One(target) => vec![target],
+            Targets::Many(targets) => targets,
+        };
+        Entry::parse(&spec.when, &targets)
+    }
+}
+
+impl From<&Entry> for EntrySpec {
+    fn from(entry: &Entry) -> Self {
+        let mut targets: Vec<_> = entry.targets.iter().map(ToString::to_string).collect();
+        EntrySpec {
+            when: entry.when.to_string(),
+            to: if targets.len() == 1 {
+                Targets::One(targets.remove(0))
+            } else {
+                Targets::Many(targets)
+            },
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ScheduleEntry {
+    pub name: String,
+    pub entry: Entry,
+    pub paused: bool,
+    pub from_config: bool,
+    /// Unix time in milliseconds, unset if paused
+    pub next_run_ms: Option<i64>,
+}
+
+/// Runtime entries and paused entries, in the storage directory
+#[derive(Serialize, Deserialize, Default)]
+struct StoredSchedule {
+    #[serde(default)]
+    paused: BTreeSet<String>,
+    #[serde(default)]
+    entries: BTreeMap<String, EntrySpec>,
+}
+
+struct ScheduleState {
+    config: SchedulerConfig,
+    stored: BTreeMap<String, Entry>,
+    paused: BTreeSet<String>,
+    /// Last run of each entry, or when it was added or resumed, in Unix milliseconds
+    since: HashMap<String, i64>,
+}
+
+impl ScheduleState {
+    /// Entries from the config file shadow stored ones
+    fn get(&self, name: &str) -> Option<(&Entry, bool)> {
+        match self.config.entries.get(name) {
+            Some(entry) => Some((entry, true)),
+            None => self.stored.get(name).map(|entry| (entry, false)),
+        }
+    }
+
+    fn entries(&self) -> impl Iterator<Item = (&String, &Entry, bool)> {
+        let config = self
+            .config
+            .entries
+            .iter()
+            .map(|(name, entry)| (name, entry, true));
+        let stored = self
+            .stored
+            .iter()
+            .filter(|(name, _)| !self.config.entries.contains_key(*name))
+            .map(|(name, entry)| (name, entry, false));
+        config.chain(stored)
+    }
+
+    fn next_run(&mut self, name: &str, entry: &Entry) -> Option<i64> {
+        if self.paused.contains(name) {
+            return None;
+        }
+        let since = *self.since.entry(name.to_owned()).or_insert_with(now_ms);
+        entry.when.next_after(since)
+    }
+}
+
+/**
+ * Moves the desk at scheduled times, through the RPC handlers.
+ * Entries from the config file are read-only at runtime, except for pausing them,
+ * other entries and the paused state are stored in the storage directory.
+ * Entries are skipped while the desk is moving, or if it was recently moved by hand.
+ */
+pub struct Scheduler {
+    state: Mutex<ScheduleState>,
+    path: PathBuf,
+    presets: SharedPresets,
+    audit: Arc<AuditLog>,
+    changed: Notify,
+}
+
+impl Scheduler {
+    pub fn load(
+        config: SchedulerConfig,
+        path: PathBuf,
+        presets: SharedPresets,
+        audit: Arc<AuditLog>,
+    ) -> Result<Self, ScheduleError> {
+        let stored: StoredSchedule = match std::fs::read_to_string(&path) {
+            Ok(content) => toml::from_str(&content)?,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => StoredSchedule::default(),
+            Err(error) => return Err(ScheduleError::IoError { path, error }),
+        };
+        let entries = stored
+            .entries
+            .into_iter()
+            .map(|(name, spec)| Ok((name, spec.try_into()?)))
+            .collect::<Result<_, ScheduleError>>()?;
+        Ok(Scheduler {
+            state: Mutex::new(ScheduleState {
+                config,
+                stored: entries,
+                paused: stored.paused,
+                since: HashMap::new(),
+            }),
+            path,
+            presets,
+            audit,
+            changed: Notify::new(),
+        })
+    }
+
+    /// Replace the settings and entries from the config file
+    pub fn set_config(&self, config: SchedulerConfig) {
+        self.state.lock().unwrap().config = config;
+        self.changed.notify_one();
+    }
+
+    pub fn list(&self) -> Vec<ScheduleEntry> {
+        let mut state = self.state.lock().unwrap();
+        let entries: Vec<_> = state
+            .entries()
+            .map(|(name, entry, from_config)| (name.clone(), entry.clone(), from_config))
+            .collect();
+        entries
+            .into_iter()
+            .map(|(name, entry, from_config)| ScheduleEntry {
+                next_run_ms: state.next_run(&name, &entry),
+                paused: state.paused.contains(&name),
+                name,
+                entry,
+                from_config,
+            })
+            .collect()
+    }
+
+    pub fn set(&self, name: String, entry: Entry) -> Result<(), ScheduleError> {
+        let mut state = self.state.lock().unwrap();
+        if state.config.entries.contains_key(&name) {
+            return Err(ScheduleError::FromConfig(name));
+        }
+        info!(%name, when = %entry.when, "Setting schedule entry");
+        let previous = state.stored.insert(name.clone(), entry);
+        if let Err(e) = self.save(&state) {
+            match previous {
+                Some(previous) => state.stored.insert(name, previous),
+                None => state.stored.remove(&name),
+            };
+            return Err(e);
+        }
+        state.since.insert(name, now_ms());
+        self.changed.notify_one();
+        Ok(())
+    }
+
+    pub fn remove(&self, name: &str) -> Result<(), ScheduleError> {
+        let mut state = self.state.lock().unwrap();
+        if state.config.entries.contains_key(name) {
+            return Err(ScheduleError::FromConfig(name.to_owned()));
+        }
+        info!(%name, "Removing schedule entry");
+        let previous = state
+            .stored
+            .remove(name)
+            .ok_or_else(|| ScheduleError::NotFound(name.to_owned()))?;
+        let was_paused = state.paused.remove(name);
+        if let Err(e) = self.save(&state) {
+            state.stored.insert(name.to_owned(), previous);
+            if was_paused {
+                state.paused.insert(name.to_owned());
+            }
+            return Err(e);
+        }
+        state.since.remove(name);
+        self.changed.notify_one();
+        Ok(())
+    }
+
+    pub fn pause(&self, name: &str, paused: bool) -> Result<(), ScheduleError> {
+        let mut state = self.state.lock().unwrap();
+        if state.get(name).is_none() {
+            return Err(ScheduleError::NotFound(name.to_owned()));
+        }
+        info!(%name, paused, "Pausing schedule entry");
+        let changed = if paused {
+            state.paused.insert(name.to_owned())
+        } else {
+            state.paused.remove(name)
+        };
+        if !changed {
+            return Ok(());
+        }
+        if let Err(e) = self.save(&state) {
+            if paused {
+                state.paused.remove(name);
+            } else {
+                state.paused.insert(name.to_owned());
+            }
+            return Err(e);
+        }
+        // repeating entries start over when resumed
+        state.since.insert(name.to_owned(), now_ms());
+        self.changed.notify_one();
+        Ok(())
+    }
+
+    /// Run entries as they become due, forever
+    pub async fn run(
+        self: Arc<Self>,
+        service: Arc<DeskService>,
+        state: watch::Receiver<(Position, Velocity)>,
+        mut events: EventStream,
+    ) {
+        let mut last_manual: Option<Instant> = None;
+        loop {
+            let now = now_ms();
+            let (due, next_run, manual_grace) = {
+                let mut state = self.state.lock().unwrap();
+                let entries: Vec<_> = state
+                    .entries()
+                    .map(|(name, entry, _)| (name.clone(), entry.clone()))
+                    .collect();
+                let mut due = Vec::new();
+                let mut next_run: Option<i64> = None;
+                for (name, entry) in entries {
+                    match state.next_run(&name, &entry) {
+                        Some(run) if run <= now => {
+                            state.since.insert(name.clone(), now);
+                            next_run = entry.when.next_after(now).into_iter().chain(next_run).min();
+                            due.push((name, entry.targets));
+                        }
+                        Some(run) => next_run = Some(next_run.map_or(run, |next| next.min(run))),
+                        None => {}
+                    }
+                }
+                (due, next_run, state.config.manual_grace)
+            };
+            for (name, targets) in due {
+                let moved_by_hand = last_manual.is_some_and(|at| at.elapsed() < manual_grace);
+                self.run_entry(&service, &state, moved_by_hand, &name, &targets)
+                    .await;
+            }
+
+            let sleep = next_run
+                .map(|run| Duration::from_millis((run - now_ms()).max(0) as u64))
+                .map_or(MAX_SLEEP, |sleep| sleep.min(MAX_SLEEP));
+            select! {
+                _ = time::sleep(sleep) => {}
+                _ = self.changed.notified() => {}
+                Some(event) = events.next() => {
+                    if let Event::ManualMovement { .. } = event {
+                        last_manual = Some(Instant::now());
+                    }
+                }
+            }
+        }
+    }
+
+    async fn run_entry(
+        &self,
+        service: &DeskService,
+        state: &watch::Receiver<(Position, Velocity)>,
+        moved_by_hand: bool,
+        name: &str,
+        targets: &[Target],
+    ) {
+        let client = format!("schedule:{}", name);
+        let (position, velocity) = *state.borrow();
+        let target = choose(&self.presets.read().unwrap(), targets, position);
+        let skip = if moved_by_hand {
+            Some("moved by hand recently")
+        } else if !velocity.is_zero() {
+            Some("desk is moving")
+        } else {
+            None
+        };
+        if let Some(reason) = skip {
+            info!(%name, %target, reason, "Skipping scheduled move");
+            let action = match target {
+                Target::Position(position) => Action::Move {
+                    target: Some(position.to_cm()),
+                    preset: None,
+                },
+                Target::Preset(preset) => Action::Move {
+                    target: None,
+                    preset: Some(preset.clone()),
+                },
+            };
+            self.audit
+                .record(&client, action, &format!("skipped: {}", reason));
+            return;
+        }
+
+        info!(%name, %target, "Running scheduled move");
+        let message = match target {
+            Target::Position(position) => StartMoveRequest {
+                target: position.to_cm(),
+                ..Default::default()
+            },
+            Target::Preset(preset) => StartMoveRequest {
+                preset: preset.clone(),
+                ..Default::default()
+            },
+        };
+        let mut request = Request::new(message);
+        request.extensions_mut().insert(Peer(client));
+        request.extensions_mut().insert(Automatic::Schedule);
+        if let Err(status) = service.start_move(request).await {
+            warn!(%name, "Scheduled move failed: {}", status.message());
+        }
+    }
+
+    fn save(&self, state: &ScheduleState) -> Result<(), ScheduleError> {
+        let stored = StoredSchedule {
+            paused: state.paused.clone(),
+            entries: state
+                .stored
+                .iter()
+                .map(|(name, entry)| (name.clone(), entry.into()))
+                .collect(),
+        };
+        let content = toml::to_string(&stored)?;
+        let io_error = |error| ScheduleError::IoError {
+            path: self.path.clone(),
+            error,
+        };
+        if let Some(directory) = self.path.parent() {
+            std::fs::create_dir_all(directory).map_err(io_error)?;
+        }
+        std::fs::write(&self.path, content).map_err(io_error)
+    }
+}
+
+/// The target after the one closest to the desk, so alternating continues after manual moves
+fn choose<'a>(presets: &Presets, targets: &'a [Target], position: Position) -> &'a Target {
+    let closest = targets
+        .iter()
+        .enumerate()
+        .filter_map(|(index, target)| {
+            let target = match target {
+                Target::Position(position) => *position,
+                Target::Preset(name) => presets.get(name).ok()?,
+            };
+            Some((index, (target.to_cm() - position.to_cm()).abs()))
+        })
+        .min_by(|(_, a), (_, b)| a.total_cmp(b));
+    match closest {
+        Some((index, _)) => &targets[(index + 1) % targets.len()],
+        None => &targets[0],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDateTime;
+    use chrono_tz::{Europe::Paris, Tz};
+
+    fn paris_ms(time: &str) -> i64 {
+        let time = NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M").unwrap();
+        Paris
+            .from_local_datetime(&time)
+            .single()
+            .unwrap()
+            .timestamp_millis()
+    }
+
+    fn next_in_paris(when: &str, after: &str) -> Option<i64> {
+        when.parse::<Trigger>()
+            .unwrap()
+            .next_after_in::<Tz>(&Paris, paris_ms(after))
+    }
+
+    fn position(cm: f32) -> Position {
+        Position::from_cm(cm).unwrap()
+    }
+
+    #[test]
+    fn days() {
+        let weekdays = Days::parse("weekdays").unwrap();
+        assert!(weekdays.contains(Weekday::Mon) && weekdays.contains(Weekday::Fri));
+        assert!(!weekdays.contains(Weekday::Sat) && !weekdays.contains(Weekday::Sun));
+        let weekends = Days::parse("weekends").unwrap();
+        assert!(weekends.contains(Weekday::Sun) && !weekends.contains(Weekday::Fri));
+        let list = Days::parse(" mon, wed ").unwrap();
+        assert!(list.contains(Weekday::Mon) && list.contains(Weekday::Wed));
+        assert!(!list.contains(Weekday::Tue));
+        assert_eq!(Days::parse("mon,tue,wed,thu,fri"), Some(Days::WEEKDAYS));
+        assert_eq!(Days::parse(""), None);
+        assert_eq!(Days::parse("mon,"), None);
+        assert_eq!(Days::parse("mon,funday"), None);
+    }
+
+    #[test]
+    fn days_display_round_trip() {
+        for bits in 1..=0b111_1111 {
+            let days = Days(bits);
+            assert_eq!(Days::parse(&days.to_string()), Some(days), "{}", days);
+        }
+        assert_eq!(Days(0b000_0101).to_string(), "mon,wed");
+    }
+
+    #[test]
+    fn trigger_from_str() {
+        assert_eq!(
+            "weekdays 07:30".parse::<Trigger>().unwrap(),
+            Trigger::At {
+                days: Days::WEEKDAYS,
+                time: NaiveTime::from_hms_opt(7, 30, 0).unwrap(),
+            }
+        );
+        assert_eq!(
+            " every 1h 30m ".parse::<Trigger>().unwrap(),
+            Trigger::Every(Duration::from_secs(90 * 60))
+        );
+        for invalid in [
+            "every 30s",
+            "every often",
+            "daily 25:00",
+            "07:30",
+            "sometimes 07:30",
+            "",
+        ] {
+            assert!(matches!(
+                invalid.parse::<Trigger>(),
+                Err(ScheduleError::InvalidWhen(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn trigger_display_round_trip() {
+        for when in [
+            "daily 00:00",
+            "weekends 09:05",
+            "tue,sat 23:59",
+            "every 1h 30m",
+            "every 2days",
+        ] {
+            let trigger: Trigger = when.parse().unwrap();
+            assert_eq!(trigger.to_string(), when);
+            assert_eq!(trigger.to_string().parse::<Trigger>().unwrap(), trigger);
+        }
+    }
+
+    #[test]
+    fn next_after_across_week_end() {
+        // 2024-01-05 is a Friday
+        assert_eq!(
+            next_in_paris("weekdays 09:00", "2024-01-05 10:00"),
+            Some(paris_ms("2024-01-08 09:00"))
+        );
+        assert_eq!(
+            next_in_paris("weekends 09:00", "2024-01-07 10:00"),
+            Some(paris_ms("2024-01-13 09:00"))
+        );
+        // strictly after
+        assert_eq!(
+            next_in_paris("fri 09:00", "2024-01-05 09:00"),
+            Some(paris_ms("2024-01-12 09:00"))
+        );
+        assert_eq!(
+            next_in_paris("every 1h", "2024-01-05 09:00"),
+            Some(paris_ms("2024-01-05 10:00"))
+        );
+    }
+
+    #[test]
+    fn next_after_across_dst_gap() {
+        // 02:30 does not exist in Paris on Sunday 2024-03-31
+        assert_eq!(
+            next_in_paris("daily 02:30", "2024-03-30 03:00"),
+            Some(paris_ms("2024-04-01 02:30"))
+        );
+        assert_eq!(
+            next_in_paris("sun 02:30", "2024-03-24 02:30"),
+            Some(paris_ms("2024-04-07 02:30"))
+        );
+        // local time on both sides of the change
+        assert_eq!(
+            next_in_paris("daily 09:00", "2024-03-30 10:00"),
+            Some(paris_ms("2024-03-30 10:00") + 22 * 60 * 60 * 1000)
+        );
+    }
+
+    #[test]
+    fn choose_alternates_after_manual_move() {
+        let path = std::env::temp_dir().join("deskd-schedule-tests-no-presets.toml");
+        let presets = Presets::load(
+            BTreeMap::from([("stand".to_owned(), position(110.0))]),
+            path,
+        )
+        .unwrap();
+        let targets = [
+            Target::Position(position(70.0)),
+            Target::Preset("stand".to_owned()),
+        ];
+        assert_eq!(choose(&presets, &targets, position(70.0)), &targets[1]);
+        assert_eq!(choose(&presets, &targets, position(110.0)), &targets[0]);
+        // moved by hand close to the standing preset
+        assert_eq!(choose(&presets, &targets, position(105.0)), &targets[0]);
+        assert_eq!(choose(&presets, &targets, position(80.0)), &targets[1]);
+
+        let unknown = [
+            Target::Preset("missing".to_owned()),
+            Target::Position(position(70.0)),
+        ];
+        assert_eq!(choose(&presets, &unknown, position(110.0)), &unknown[0]);
+        let missing = [Target::Preset("missing".to_owned())];
+        assert_eq!(choose(&presets, &missing, position(110.0)), &missing[0]);
+    }
+}
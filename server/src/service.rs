@@ -1,51 +1,639 @@
 use crate::{
-    controllers::{Command, CommandSender, CommandSenderExt, ControllerError},
-    utils::{Position, PositionError},
+    audit::{Action, AuditLog},
+    auth::{Identity, Role},
+    config::ConfigError,
+    controllers::{
+        policy::DEFAULT_LEASE_DURATION, Command, CommandSender, CommandSenderExt, ControllerError,
+        StateStream,
+    },
+    cycle::{self, Cycle, CycleSettings},
+    desk::DeskError,
+    events::{ConnectionState, Event, GoalStatus, MoveOutcome},
+    goal::Goal,
+    history::{self, History, HistoryError, PageKey},
+    lock::{ActiveLock, LockError, LockScope, LockSource, Locks, RuntimeLock},
+    presets::{PresetError, SharedPresets},
+    reload::ConfigReloader,
+    schedule::{self, Entry, ScheduleError, Scheduler, Target},
+    stats::{self, Posture},
+    utils::{Position, PositionError, Velocity},
 };
 use async_trait::async_trait;
 pub use desklink_common::rpc::desk_service_server::DeskServiceServer;
 use desklink_common::rpc::{
-    desk_service_server::DeskService as DeskServiceTrait, GetStateRequest, GetStateResponse,
-    StartMoveRequest, StartMoveResponse, StopRequest, StopResponse, SubscribeStateRequest,
-    SubscribeStateResponse,
+    connection_event, desk_service_server::DeskService as DeskServiceTrait, error_detail,
+    get_cycle_response, goal_event, lock, move_event, query_history_request,
+    subscribe_events_response, AcquireControlRequest, AcquireControlResponse, ConfigReloadedEvent,
+    ConnectionEvent, DeletePresetRequest, DeletePresetResponse, DeleteScheduleEntryRequest,
+    DeleteScheduleEntryResponse, ErrorDetail, ExportRequest, ExportResponse, GetCycleRequest,
+    GetCycleResponse, GetGoalProgressRequest, GetGoalProgressResponse, GetLockRequest,
+    GetLockResponse, GetStateRequest, GetStateResponse, GetUsageStatsRequest,
+    GetUsageStatsResponse, GoalEvent, HistorySample, ListPresetsRequest, ListPresetsResponse,
+    ListScheduleRequest, ListScheduleResponse, Lock, ManualMovementEvent, MoveEvent, MoveSegment,
+    PauseScheduleEntryRequest, PauseScheduleEntryResponse, Preset, QueryHistoryRequest,
+    QueryHistoryResponse, Range, ReleaseControlRequest, ReleaseControlResponse,
+    ReloadConfigRequest, ReloadConfigResponse, ScheduleEntry, SetLockRequest, SetLockResponse,
+    SetPresetRequest, SetPresetResponse, SetScheduleEntryRequest, SetScheduleEntryResponse,
+    StartCycleRequest, StartCycleResponse, StartMoveRequest, StartMoveResponse, StopCycleRequest,
+    StopCycleResponse, StopRequest, StopResponse, SubscribeEventsRequest, SubscribeEventsResponse,
+    SubscribeStateRequest, SubscribeStateResponse, UsageStats,
 };
-use futures::{Stream, StreamExt};
-use std::pin::Pin;
-use tonic::{Request, Response, Status};
+use futures::{stream, Stream, StreamExt};
+use std::{error::Error, ops, pin::Pin, sync::Arc, time::Duration};
+use tokio::time::{self, Instant};
+use tonic::{transport::server::UdsConnectInfo, Code, Request, Response, Status};
 use tracing::info;
 
 pub struct DeskService {
     controller: CommandSender,
+    presets: SharedPresets,
+    reloader: Arc<ConfigReloader>,
+    audit: Arc<AuditLog>,
+    history: Option<Arc<History>>,
+    goal: Option<Arc<Goal>>,
+    scheduler: Arc<Scheduler>,
+    cycle: Arc<Cycle>,
+    locks: Arc<Locks>,
 }
 
 impl DeskService {
-    pub fn new(controller: CommandSender) -> Self {
-        DeskService { controller }
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        controller: CommandSender,
+        presets: SharedPresets,
+        reloader: Arc<ConfigReloader>,
+        audit: Arc<AuditLog>,
+        history: Option<Arc<History>>,
+        goal: Option<Arc<Goal>>,
+        scheduler: Arc<Scheduler>,
+        cycle: Arc<Cycle>,
+        locks: Arc<Locks>,
+    ) -> Self {
+        DeskService {
+            controller,
+            presets,
+            reloader,
+            audit,
+            history,
+            goal,
+            scheduler,
+            cycle,
+            locks,
+        }
+    }
+
+    /// Check the phases and targets of a cycle, the presets must exist when it starts
+    #[allow(clippy::result_large_err)]
+    fn cycle_settings(
+        &self,
+        sit_ms: u64,
+        stand_ms: u64,
+        sit_target: &str,
+        stand_target: &str,
+    ) -> Result<CycleSettings, Status> {
+        let sit = Duration::from_millis(sit_ms);
+        let stand = Duration::from_millis(stand_ms);
+        if sit < cycle::MIN_PHASE || stand < cycle::MIN_PHASE {
+            return Err(invalid_argument(format!(
+                "Phases must last at least {}s",
+                cycle::MIN_PHASE.as_secs()
+            )));
+        }
+        let sit_target: Target = sit_target.parse()?;
+        let stand_target: Target = stand_target.parse()?;
+        for target in [&sit_target, &stand_target] {
+            if let Target::Preset(name) = target {
+                self.presets.read().unwrap().get(name)?;
+            }
+        }
+        Ok(CycleSettings {
+            sit,
+            stand,
+            sit_target,
+            stand_target,
+        })
+    }
+
+    /// Like `authorize`, also recording denied commands in the audit log
+    #[allow(clippy::result_large_err)]
+    fn authorize_audited<'a, T>(
+        &self,
+        request: &'a Request<T>,
+        role: Role,
+        action: &Action,
+    ) -> Result<Option<&'a Identity>, Status> {
+        authorize(request, role).inspect_err(|status| {
+            self.audit
+                .record(&client_of(request), action.clone(), &rejected(status))
+        })
+    }
+}
+
+/**
+ * Server-side filtering of the state stream, as requested by `SubscribeStateRequest`.
+ * Transitions between moving and resting are always sent so clients see the desk stop.
+ */
+#[derive(Copy, Clone, Debug)]
+struct StateFilter {
+    min_interval: Duration,
+    min_position_change: f32,
+    moving_only: bool,
+}
+
+impl StateFilter {
+    fn accepts(&self, last: Option<(Position, Velocity)>, state: (Position, Velocity)) -> bool {
+        let (position, velocity) = state;
+        let moving = !velocity.is_zero();
+        match last {
+            None => moving || !self.moving_only,
+            Some((last_position, last_velocity)) => {
+                if moving == last_velocity.is_zero() {
+                    true
+                } else if !moving && self.moving_only {
+                    false
+                } else {
+                    f32::abs(position.to_cm() - last_position.to_cm()) >= self.min_position_change
+                }
+            }
+        }
+    }
+
+    fn apply(self, states: StateStream) -> StateStream {
+        let init = (states, None, None);
+        Box::pin(stream::unfold(
+            init,
+            move |(mut states, mut last, mut last_sent): (_, _, Option<Instant>)| async move {
+                loop {
+                    if let Some(last_sent) = last_sent {
+                        // the watch stream only keeps the latest state, so sleeping drops
+                        // intermediate updates
+                        time::sleep_until(last_sent + self.min_interval).await;
+                    }
+                    let state = states.next().await?;
+                    if self.accepts(last, state) {
+                        last = Some(state);
+                        last_sent = Some(Instant::now());
+                        return Some((state, (states, last, last_sent)));
+                    }
+                }
+            },
+        ))
+    }
+}
+
+impl TryFrom<&SubscribeStateRequest> for StateFilter {
+    type Error = Status;
+
+    fn try_from(request: &SubscribeStateRequest) -> Result<Self, Self::Error> {
+        let min_position_change = request.min_position_change;
+        if !min_position_change.is_finite() || min_position_change < 0.0 {
+            return Err(invalid_argument(format!(
+                "Invalid minimum position change: {}",
+                min_position_change
+            )));
+        }
+        Ok(StateFilter {
+            min_interval: Duration::from_millis(request.min_interval_ms.into()),
+            min_position_change,
+            moving_only: request.moving_only,
+        })
+    }
+}
+
+impl From<Event> for SubscribeEventsResponse {
+    fn from(event: Event) -> Self {
+        use subscribe_events_response::Event as E;
+        let event = match event {
+            Event::Connection(state) => E::Connection(ConnectionEvent {
+                state: match state {
+                    ConnectionState::Connected => connection_event::State::Connected,
+                    ConnectionState::Disconnected => connection_event::State::Disconnected,
+                    ConnectionState::Reconnecting => connection_event::State::Reconnecting,
+                } as i32,
+            }),
+            Event::Move {
+                id,
+                outcome,
+                target,
+                position,
+                client,
+            } => {
+                let (outcome, preempted_by) = match outcome {
+                    MoveOutcome::Started => (move_event::Outcome::Started, String::new()),
+                    MoveOutcome::Reached => (move_event::Outcome::Reached, String::new()),
+                    MoveOutcome::Aborted => (move_event::Outcome::Aborted, String::new()),
+                    MoveOutcome::Stalled => (move_event::Outcome::Stalled, String::new()),
+                    MoveOutcome::Preempted { by } => (move_event::Outcome::Preempted, by),
+                };
+                E::MoveEvent(MoveEvent {
+                    id,
+                    outcome: outcome as i32,
+                    target: target.to_cm(),
+                    position: position.to_cm(),
+                    client,
+                    preempted_by,
+                })
+            }
+            Event::ManualMovement { position } => E::ManualMovement(ManualMovementEvent {
+                position: position.to_cm(),
+            }),
+            Event::ConfigReloaded => E::ConfigReloaded(ConfigReloadedEvent {}),
+            Event::Goal {
+                status,
+                standing,
+                goal,
+            } => E::Goal(GoalEvent {
+                kind: match status {
+                    GoalStatus::Reached => goal_event::Kind::Reached,
+                    GoalStatus::Behind => goal_event::Kind::Behind,
+                } as i32,
+                standing_ms: standing.as_millis() as u64,
+                goal_ms: goal.as_millis() as u64,
+            }),
+        };
+        SubscribeEventsResponse { event: Some(event) }
+    }
+}
+
+/// Peer of a request forwarded by a frontend other than gRPC, stored in the request extensions
+#[derive(Clone, Debug)]
+pub struct Peer(pub String);
+
+/// Marks moves started by deskd itself, which automatic locks refuse, stored in the request extensions
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Automatic {
+    Schedule,
+    Cycle,
+    Calendar,
+}
+
+/// Identifies the client of a request in logs and events
+fn client_of<T>(request: &Request<T>) -> String {
+    let peer = match request.remote_addr() {
+        Some(address) => address.to_string(),
+        None => match request
+            .extensions()
+            .get::<UdsConnectInfo>()
+            .and_then(|info| info.peer_cred)
+        {
+            Some(credentials) => format!("unix uid {}", credentials.uid()),
+            None => match request.extensions().get::<Peer>() {
+                Some(Peer(peer)) => peer.clone(),
+                None => "unknown".to_owned(),
+            },
+        },
+    };
+    match request.extensions().get::<Identity>() {
+        Some(identity) => format!("{} ({})", identity.name, peer),
+        None => peer,
+    }
+}
+
+/// Check the role of the token, if authentication is enabled
+#[allow(clippy::result_large_err)]
+fn authorize<T>(request: &Request<T>, role: Role) -> Result<Option<&Identity>, Status> {
+    let identity = request.extensions().get::<Identity>();
+    if let Some(identity) = identity {
+        if let Err(e) = identity.require(role) {
+            info!(client = %client_of(request), "Denied: {}", e);
+            return Err(e.into());
+        }
+    }
+    Ok(identity)
+}
+
+/// Outcome of a command in the audit log
+fn audit_outcome<T>(response: &Result<T, Status>, done: &str) -> String {
+    match response {
+        Ok(_) => done.to_owned(),
+        Err(status) => rejected(status),
+    }
+}
+
+fn rejected(status: &Status) -> String {
+    format!("rejected: {}", status.message())
+}
+
+/// Include the sources in the message, since clients only see the message
+fn error_chain(e: &dyn Error) -> String {
+    let mut message = format!("{}", e);
+    let mut source = e.source();
+    while let Some(e) = source {
+        message = format!("{}: {}", message, e);
+        source = e.source();
+    }
+    message
+}
+
+/// Lease ids grant control of the desk, so like tokens they are kept out of the logs
+fn redact_lease<T: Clone>(message: &T, lease_id: fn(&mut T) -> &mut String) -> T {
+    let mut message = message.clone();
+    let lease_id = lease_id(&mut message);
+    if !lease_id.is_empty() {
+        *lease_id = "<redacted>".to_owned();
+    }
+    message
+}
+
+fn non_empty(s: &str) -> Option<String> {
+    if s.is_empty() {
+        None
+    } else {
+        Some(s.to_owned())
     }
 }
 
 impl From<ControllerError> for Status {
     fn from(e: ControllerError) -> Status {
-        match &e {
-            ControllerError::DeskError(_) => Status::internal(format!("{}", e)),
-            ControllerError::Aborted => Status::cancelled(format!("{}", e)),
+        let (code, detail) = match &e {
+            ControllerError::DeskError(DeskError::Disconnected) => {
+                (Code::Unavailable, error_detail::Code::Disconnected)
+            }
+            ControllerError::DeskError(_) => (Code::Internal, error_detail::Code::Internal),
+            ControllerError::Aborted => (Code::Cancelled, error_detail::Code::Aborted),
+            ControllerError::Stalled => (Code::Aborted, error_detail::Code::Stalled),
+            ControllerError::Leased { holder, remaining } => {
+                let detail = ErrorDetail {
+                    lease_holder: holder.clone(),
+                    lease_remaining_ms: remaining.as_millis().try_into().unwrap_or(u32::MAX),
+                    ..ErrorDetail::new(error_detail::Code::ControlHeld)
+                };
+                return detail.into_status(Code::FailedPrecondition, format!("{}", e));
+            }
+        };
+        ErrorDetail::new(detail).into_status(code, format!("{}", e))
+    }
+}
+
+impl From<PositionError> for Status {
+    fn from(e: PositionError) -> Status {
+        let requested = match &e {
+            PositionError::OutOfBound(cm) => *cm,
+            PositionError::InvalidPosition(_) => f32::NAN,
+        };
+        ErrorDetail {
+            allowed_range: Some(Range {
+                min: Position::MIN_CM,
+                max: Position::MAX_CM,
+            }),
+            requested,
+            ..ErrorDetail::new(error_detail::Code::OutOfRange)
+        }
+        .into_status(Code::OutOfRange, format!("{}", e))
+    }
+}
+
+impl From<PresetError> for Status {
+    fn from(e: PresetError) -> Status {
+        match e {
+            PresetError::NotFound(_) => ErrorDetail::new(error_detail::Code::NotFound)
+                .into_status(Code::NotFound, format!("{}", e)),
+            PresetError::FromConfig(_) => failed_precondition(format!("{}", e)),
+            PresetError::InvalidPosition(e) => e.into(),
+            e => internal(error_chain(&e)),
         }
     }
 }
 
+impl From<ScheduleError> for Status {
+    fn from(e: ScheduleError) -> Status {
+        match e {
+            ScheduleError::NotFound(_) => ErrorDetail::new(error_detail::Code::NotFound)
+                .into_status(Code::NotFound, format!("{}", e)),
+            ScheduleError::FromConfig(_) => failed_precondition(format!("{}", e)),
+            ScheduleError::InvalidWhen(_)
+            | ScheduleError::InvalidTarget(..)
+            | ScheduleError::NoTarget => invalid_argument(error_chain(&e)),
+            e => internal(error_chain(&e)),
+        }
+    }
+}
+
+impl From<LockError> for Status {
+    fn from(e: LockError) -> Status {
+        match e {
+            LockError::Locked(_) => ErrorDetail::new(error_detail::Code::Locked)
+                .into_status(Code::FailedPrecondition, format!("{}", e)),
+            LockError::InvalidWindow(_) => invalid_argument(error_chain(&e)),
+            e => internal(error_chain(&e)),
+        }
+    }
+}
+
+impl From<ConfigError> for Status {
+    fn from(e: ConfigError) -> Status {
+        failed_precondition(error_chain(&e))
+    }
+}
+
+impl From<HistoryError> for Status {
+    fn from(e: HistoryError) -> Status {
+        internal(error_chain(&e))
+    }
+}
+
+impl From<history::Sample> for HistorySample {
+    fn from(sample: history::Sample) -> Self {
+        HistorySample {
+            timestamp_ms: sample.timestamp_ms,
+            position: sample.position,
+            velocity: sample.velocity,
+        }
+    }
+}
+
+impl From<history::MoveSegment> for MoveSegment {
+    fn from(segment: history::MoveSegment) -> Self {
+        MoveSegment {
+            start_ms: segment.start_ms,
+            end_ms: segment.end_ms,
+            start_position: segment.start_position,
+            end_position: segment.end_position,
+        }
+    }
+}
+
+impl From<stats::UsageStats> for UsageStats {
+    fn from(stats: stats::UsageStats) -> Self {
+        UsageStats {
+            sitting_ms: stats.sitting_ms,
+            standing_ms: stats.standing_ms,
+            unknown_ms: stats.unknown_ms,
+            transitions: stats.transitions,
+            longest_sit_ms: stats.longest_sit_ms,
+        }
+    }
+}
+
+impl From<schedule::ScheduleEntry> for ScheduleEntry {
+    fn from(entry: schedule::ScheduleEntry) -> Self {
+        ScheduleEntry {
+            name: entry.name,
+            when: entry.entry.when.to_string(),
+            targets: entry
+                .entry
+                .targets
+                .iter()
+                .map(ToString::to_string)
+                .collect(),
+            paused: entry.paused,
+            from_config: entry.from_config,
+            next_run_ms: entry.next_run_ms.unwrap_or(0),
+        }
+    }
+}
+
+impl From<cycle::CycleStatus> for GetCycleResponse {
+    fn from(status: cycle::CycleStatus) -> Self {
+        GetCycleResponse {
+            active: true,
+            sit_ms: status.settings.sit.as_millis() as u64,
+            stand_ms: status.settings.stand.as_millis() as u64,
+            sit_target: status.settings.sit_target.to_string(),
+            stand_target: status.settings.stand_target.to_string(),
+            posture: match status.posture {
+                Posture::Sitting => get_cycle_response::Posture::Sitting,
+                Posture::Standing => get_cycle_response::Posture::Standing,
+            } as i32,
+            paused: status.paused,
+            remaining_ms: status.remaining.as_millis() as u64,
+        }
+    }
+}
+
+impl From<LockScope> for lock::Scope {
+    fn from(scope: LockScope) -> Self {
+        match scope {
+            LockScope::Automatic => lock::Scope::Automatic,
+            LockScope::All => lock::Scope::All,
+        }
+    }
+}
+
+impl From<ActiveLock> for Lock {
+    fn from(active: ActiveLock) -> Self {
+        let scope = lock::Scope::from(active.scope) as i32;
+        match active.source {
+            LockSource::Window { name, until_ms } => Lock {
+                scope,
+                window: name,
+                until_ms,
+                ..Default::default()
+            },
+            LockSource::Calendar {
+                rule,
+                event,
+                until_ms,
+            } => Lock {
+                scope,
+                calendar_rule: rule,
+                event,
+                until_ms,
+                ..Default::default()
+            },
+            LockSource::Runtime(runtime) => Lock {
+                scope,
+                reason: runtime.reason,
+                client: runtime.client,
+                since_ms: runtime.since_ms,
+                ..Default::default()
+            },
+        }
+    }
+}
+
+/// Entries returned by `QueryHistory` if the client sets no limit
+const DEFAULT_HISTORY_LIMIT: usize = 10_000;
+const MAX_HISTORY_LIMIT: usize = 100_000;
+/// Samples or moves in an `Export` page, well below the message size limit
+const EXPORT_PAGE_LIMIT: usize = 10_000;
+
+/// Pages of an `Export` chunk, the first one with the usage stats
+fn export_chunk(
+    history: Arc<History>,
+    range: ops::Range<i64>,
+) -> impl Stream<Item = Result<ExportResponse, Status>> + Send {
+    let start = Some(PageKey::before(range.start));
+    // the keys are unset once a table is exported
+    stream::try_unfold(
+        (true, start, start),
+        move |(first, samples_after, moves_after)| {
+            let history = history.clone();
+            let range = range.clone();
+            async move {
+                if !first && samples_after.is_none() && moves_after.is_none() {
+                    return Ok(None);
+                }
+                let stats = if first {
+                    Some(history.usage(range.clone()).await?.into())
+                } else {
+                    None
+                };
+                let (samples, samples_after) = match samples_after {
+                    Some(after) => {
+                        history
+                            .sample_page(after, range.end, EXPORT_PAGE_LIMIT)
+                            .await?
+                    }
+                    None => (Vec::new(), None),
+                };
+                let (moves, moves_after) = match moves_after {
+                    Some(after) => {
+                        history
+                            .move_page(after, range.end, EXPORT_PAGE_LIMIT)
+                            .await?
+                    }
+                    None => (Vec::new(), None),
+                };
+                let page = ExportResponse {
+                    start_ms: range.start,
+                    end_ms: range.end,
+                    stats,
+                    samples: samples.into_iter().map(Into::into).collect(),
+                    moves: moves.into_iter().map(Into::into).collect(),
+                };
+                Ok(Some((page, (false, samples_after, moves_after))))
+            }
+        },
+    )
+}
+
+/// The controller dropped the command, because another one replaced it
+fn busy() -> Status {
+    ErrorDetail::new(error_detail::Code::Busy).into_status(Code::Unavailable, "Controller busy")
+}
+
+fn invalid_argument(message: impl Into<String>) -> Status {
+    ErrorDetail::new(error_detail::Code::InvalidArgument)
+        .into_status(Code::InvalidArgument, message)
+}
+
+fn failed_precondition(message: impl Into<String>) -> Status {
+    ErrorDetail::new(error_detail::Code::FailedPrecondition)
+        .into_status(Code::FailedPrecondition, message)
+}
+
+fn internal(message: impl Into<String>) -> Status {
+    ErrorDetail::new(error_detail::Code::Internal).into_status(Code::Internal, message)
+}
+
 #[async_trait]
 impl DeskServiceTrait for DeskService {
     type SubscribeStateStream =
         Pin<Box<dyn Stream<Item = Result<SubscribeStateResponse, Status>> + Send>>;
+    type SubscribeEventsStream =
+        Pin<Box<dyn Stream<Item = Result<SubscribeEventsResponse, Status>> + Send>>;
+    type ExportStream = Pin<Box<dyn Stream<Item = Result<ExportResponse, Status>> + Send>>;
 
     async fn get_state(
         &self,
         request: Request<GetStateRequest>,
     ) -> Result<Response<GetStateResponse>, Status> {
+        authorize(&request, Role::Observe)?;
         let (command, result) = Command::get_state();
         self.controller.send_command(command);
         let response = match result.await {
-            Err(_) => Err(Status::unavailable("Controller busy")),
+            Err(_) => Err(busy()),
             Ok(Err(e)) => Err(e.into()),
             Ok(Ok((position, velocity))) => {
                 let response = GetStateResponse {
@@ -55,21 +643,24 @@ impl DeskServiceTrait for DeskService {
                 Ok(Response::new(response))
             }
         };
-        info!(?request, ?response, "GetState");
+        info!(client = %client_of(&request), ?request, ?response, "GetState");
         response
     }
 
+    #[allow(clippy::result_large_err)]
     async fn subscribe_state(
         &self,
         request: Request<SubscribeStateRequest>,
     ) -> Result<Response<Self::SubscribeStateStream>, Status> {
+        authorize(&request, Role::Observe)?;
+        let filter = StateFilter::try_from(request.get_ref())?;
         let (command, result) = Command::subscribe_state();
         self.controller.send_command(command);
         let response = match result.await {
-            Err(_) => Err(Status::unavailable("Controller busy")),
+            Err(_) => Err(busy()),
             Ok(Err(e)) => Err(e.into()),
             Ok(Ok(stream)) => {
-                let response_stream = stream.map(|(position, velocity)| {
+                let response_stream = filter.apply(stream).map(|(position, velocity)| {
                     Ok(SubscribeStateResponse {
                         position: position.to_cm(),
                         velocity: velocity.to_cm_per_s(),
@@ -81,6 +672,7 @@ impl DeskServiceTrait for DeskService {
             }
         };
         info!(
+            client = %client_of(&request),
             ?request,
             response = match &response {
                 Ok(_) => "Ok(...)".to_owned(),
@@ -91,16 +683,52 @@ impl DeskServiceTrait for DeskService {
         response
     }
 
+    #[allow(clippy::result_large_err)]
+    async fn subscribe_events(
+        &self,
+        request: Request<SubscribeEventsRequest>,
+    ) -> Result<Response<Self::SubscribeEventsStream>, Status> {
+        authorize(&request, Role::Observe)?;
+        let (command, result) = Command::subscribe_events();
+        self.controller.send_command(command);
+        let response = match result.await {
+            Err(_) => Err(busy()),
+            Ok(Err(e)) => Err(e.into()),
+            Ok(Ok(stream)) => {
+                let response_stream = stream.map(|event| Ok(event.into()));
+                Ok(Response::new(
+                    Box::pin(response_stream) as Self::SubscribeEventsStream
+                ))
+            }
+        };
+        info!(
+            client = %client_of(&request),
+            ?request,
+            response = match &response {
+                Ok(_) => "Ok(...)".to_owned(),
+                Err(status) => format!("Err({:?})", status),
+            },
+            "SubscribeEvents",
+        );
+        response
+    }
+
     async fn stop(&self, request: Request<StopRequest>) -> Result<Response<StopResponse>, Status> {
+        self.authorize_audited(&request, Role::Operate, &Action::Stop)?;
         let (command, complete) = Command::stop();
         self.controller.send_command(command);
 
         let response = match complete.await {
-            Err(_) => Err(Status::unavailable("Controller busy")),
+            Err(_) => Err(busy()),
             Ok(Err(e)) => Err(e.into()),
             Ok(Ok(())) => Ok(Response::new(StopResponse {})),
         };
-        info!(?request, ?response, "Stop");
+        self.audit.record(
+            &client_of(&request),
+            Action::Stop,
+            &audit_outcome(&response, "stopped"),
+        );
+        info!(client = %client_of(&request), ?request, ?response, "Stop");
         response
     }
 
@@ -108,26 +736,593 @@ impl DeskServiceTrait for DeskService {
         &self,
         request: Request<StartMoveRequest>,
     ) -> Result<Response<StartMoveResponse>, Status> {
-        let target = match Position::from_cm(request.get_ref().target) {
-            Ok(position) => position,
-            Err(PositionError::OutOfBound(p)) => {
-                return Err(Status::out_of_range(format!(
-                    "{}",
-                    PositionError::OutOfBound(p)
-                )))
+        let preset = non_empty(&request.get_ref().preset);
+        let action = Action::Move {
+            target: preset.is_none().then_some(request.get_ref().target),
+            preset: preset.clone(),
+        };
+        let identity = self.authorize_audited(&request, Role::Operate, &action)?;
+        let reject = |status: Status| {
+            self.audit
+                .record(&client_of(&request), action.clone(), &rejected(&status));
+            status
+        };
+        let target = match &preset {
+            Some(preset) => self
+                .presets
+                .read()
+                .unwrap()
+                .get(preset)
+                .map_err(|e| reject(e.into()))?,
+            None => Position::from_cm(request.get_ref().target).map_err(|e| reject(e.into()))?,
+        };
+        if let Some(identity) = identity {
+            identity
+                .check_target(target)
+                .map_err(|e| reject(e.into()))?;
+        }
+        if request.get_ref().override_lock {
+            self.authorize_audited(&request, Role::Admin, &action)?;
+        } else {
+            let automatic = request.extensions().get::<Automatic>().copied();
+            self.locks.check(automatic).map_err(|e| reject(e.into()))?;
+        }
+
+        let lease = non_empty(&request.get_ref().lease_id);
+        let client = client_of(&request);
+        // accepted moves are recorded with the resolved target, and again when they finish
+        let accept = |report| {
+            let action = Action::Move {
+                target: Some(target.to_cm()),
+                preset: preset.clone(),
+            };
+            self.audit.record_move(client_of(&request), action, report);
+        };
+
+        let response = if request.get_ref().stop_on_disconnect {
+            let (command, complete, finished, report) =
+                Command::move_to_bound(target, client, lease);
+            self.controller.send_command(command);
+            match complete.await {
+                Err(_) => Err(reject(busy())),
+                Ok(Err(e)) => Err(reject(e.into())),
+                Ok(Ok(())) => {
+                    accept(report);
+                    // the desk is stopped if this future is dropped while waiting
+                    match finished.await {
+                        Err(_) => Err(busy()),
+                        Ok(Err(e)) => Err(e.into()),
+                        Ok(Ok(())) => Ok(Response::new(StartMoveResponse {})),
+                    }
+                }
+            }
+        } else {
+            let (command, complete, report) = Command::move_to(target, client, lease);
+            self.controller.send_command(command);
+            match complete.await {
+                Err(_) => Err(reject(busy())),
+                Ok(Err(e)) => Err(reject(e.into())),
+                Ok(Ok(())) => {
+                    accept(report);
+                    Ok(Response::new(StartMoveResponse {}))
+                }
             }
-            _ => unreachable!(),
         };
+        info!(
+            client = %client_of(&request),
+            request = ?redact_lease(request.get_ref(), |request| &mut request.lease_id),
+            ?response,
+            "StartMove"
+        );
+        response
+    }
 
-        let (command, complete) = Command::move_to(target);
+    async fn acquire_control(
+        &self,
+        request: Request<AcquireControlRequest>,
+    ) -> Result<Response<AcquireControlResponse>, Status> {
+        authorize(&request, Role::Operate)?;
+        let AcquireControlRequest {
+            holder,
+            duration_ms,
+            lease_id,
+        } = request.get_ref();
+        let holder = non_empty(holder).unwrap_or_else(|| client_of(&request));
+        let duration = match duration_ms {
+            0 => DEFAULT_LEASE_DURATION,
+            ms => Duration::from_millis((*ms).into()),
+        };
+        let (command, result) = Command::acquire_control(holder, duration, non_empty(lease_id));
+        self.controller.send_command(command);
+
+        let response = match result.await {
+            Err(_) => Err(busy()),
+            Ok(Err(e)) => Err(e.into()),
+            Ok(Ok(lease)) => Ok(Response::new(AcquireControlResponse {
+                lease_id: lease.id.clone(),
+                duration_ms: lease.remaining().as_millis().try_into().unwrap_or(u32::MAX),
+            })),
+        };
+        info!(
+            client = %client_of(&request),
+            request = ?redact_lease(request.get_ref(), |request| &mut request.lease_id),
+            response = ?response.as_ref().map(|response| {
+                redact_lease(response.get_ref(), |response| &mut response.lease_id)
+            }),
+            "AcquireControl"
+        );
+        response
+    }
+
+    async fn release_control(
+        &self,
+        request: Request<ReleaseControlRequest>,
+    ) -> Result<Response<ReleaseControlResponse>, Status> {
+        authorize(&request, Role::Operate)?;
+        let (command, complete) = Command::release_control(request.get_ref().lease_id.clone());
         self.controller.send_command(command);
 
         let response = match complete.await {
-            Err(_) => Err(Status::unavailable("Controller busy")),
+            Err(_) => Err(busy()),
             Ok(Err(e)) => Err(e.into()),
-            Ok(Ok(())) => Ok(Response::new(StartMoveResponse {})),
+            Ok(Ok(())) => Ok(Response::new(ReleaseControlResponse {})),
+        };
+        info!(
+            client = %client_of(&request),
+            request = ?redact_lease(request.get_ref(), |request| &mut request.lease_id),
+            ?response,
+            "ReleaseControl"
+        );
+        response
+    }
+
+    async fn list_presets(
+        &self,
+        request: Request<ListPresetsRequest>,
+    ) -> Result<Response<ListPresetsResponse>, Status> {
+        authorize(&request, Role::Observe)?;
+        let presets = self.presets.read().unwrap().list();
+        let response = Ok(Response::new(ListPresetsResponse {
+            presets: presets
+                .into_iter()
+                .map(|preset| Preset {
+                    name: preset.name,
+                    position: preset.position.to_cm(),
+                    from_config: preset.from_config,
+                })
+                .collect(),
+        }));
+        info!(client = %client_of(&request), ?request, ?response, "ListPresets");
+        response
+    }
+
+    async fn set_preset(
+        &self,
+        request: Request<SetPresetRequest>,
+    ) -> Result<Response<SetPresetResponse>, Status> {
+        let SetPresetRequest { name, position } = request.get_ref();
+        let action = Action::SetPreset {
+            name: name.clone(),
+            position: *position,
+        };
+        self.authorize_audited(&request, Role::Admin, &action)?;
+        let response = Position::from_cm(*position)
+            .map_err(PresetError::from)
+            .and_then(|position| self.presets.write().unwrap().set(name.clone(), position))
+            .map(|()| Response::new(SetPresetResponse {}))
+            .map_err(Into::into);
+        self.audit.record(
+            &client_of(&request),
+            action,
+            &audit_outcome(&response, "done"),
+        );
+        info!(client = %client_of(&request), ?request, ?response, "SetPreset");
+        response
+    }
+
+    async fn delete_preset(
+        &self,
+        request: Request<DeletePresetRequest>,
+    ) -> Result<Response<DeletePresetResponse>, Status> {
+        let action = Action::DeletePreset {
+            name: request.get_ref().name.clone(),
         };
-        info!(?request, ?response, "StartMove");
+        self.authorize_audited(&request, Role::Admin, &action)?;
+        let response = self
+            .presets
+            .write()
+            .unwrap()
+            .remove(&request.get_ref().name)
+            .map(|()| Response::new(DeletePresetResponse {}))
+            .map_err(Into::into);
+        self.audit.record(
+            &client_of(&request),
+            action,
+            &audit_outcome(&response, "done"),
+        );
+        info!(client = %client_of(&request), ?request, ?response, "DeletePreset");
         response
     }
+
+    async fn reload_config(
+        &self,
+        request: Request<ReloadConfigRequest>,
+    ) -> Result<Response<ReloadConfigResponse>, Status> {
+        authorize(&request, Role::Admin)?;
+        let response = self
+            .reloader
+            .reload()
+            .map(|()| Response::new(ReloadConfigResponse {}))
+            .map_err(Into::into);
+        info!(client = %client_of(&request), ?request, ?response, "ReloadConfig");
+        response
+    }
+
+    async fn query_history(
+        &self,
+        request: Request<QueryHistoryRequest>,
+    ) -> Result<Response<QueryHistoryResponse>, Status> {
+        authorize(&request, Role::Observe)?;
+        let history = self
+            .history
+            .as_ref()
+            .ok_or_else(|| failed_precondition("History is disabled on the server"))?;
+        let QueryHistoryRequest {
+            start_ms,
+            end_ms,
+            kind,
+            limit,
+        } = *request.get_ref();
+        let range = start_ms..if end_ms == 0 {
+            history::now_ms()
+        } else {
+            end_ms
+        };
+        let limit = match limit {
+            0 => DEFAULT_HISTORY_LIMIT,
+            limit => Ord::min(limit as usize, MAX_HISTORY_LIMIT),
+        };
+        let response =
+            match query_history_request::Kind::from_i32(kind) {
+                Some(query_history_request::Kind::Samples) => history
+                    .samples(range, limit)
+                    .await
+                    .map(|(samples, truncated)| QueryHistoryResponse {
+                        samples: samples.into_iter().map(Into::into).collect(),
+                        truncated,
+                        ..Default::default()
+                    }),
+                Some(query_history_request::Kind::Moves) => {
+                    history.moves(range, limit).await.map(|(moves, truncated)| {
+                        QueryHistoryResponse {
+                            moves: moves.into_iter().map(Into::into).collect(),
+                            truncated,
+                            ..Default::default()
+                        }
+                    })
+                }
+                None => return Err(invalid_argument("Unknown history kind")),
+            }
+            .map(Response::new)
+            .map_err(Into::into);
+        // the entries would flood the log
+        info!(
+            client = %client_of(&request),
+            ?request,
+            entries = response.as_ref().map_or(0, |response| {
+                response.get_ref().samples.len() + response.get_ref().moves.len()
+            }),
+            "QueryHistory",
+        );
+        response
+    }
+
+    async fn get_usage_stats(
+        &self,
+        request: Request<GetUsageStatsRequest>,
+    ) -> Result<Response<GetUsageStatsResponse>, Status> {
+        authorize(&request, Role::Observe)?;
+        let history = self
+            .history
+            .as_ref()
+            .ok_or_else(|| failed_precondition("History is disabled on the server"))?;
+        let GetUsageStatsRequest { start_ms, end_ms } = *request.get_ref();
+        // the future is not unknown, it has not happened yet
+        let now = history::now_ms();
+        let range = start_ms..if end_ms == 0 { now } else { end_ms.min(now) };
+        if range.is_empty() {
+            return Err(invalid_argument(
+                "The range starts after its end or in the future",
+            ));
+        }
+        let thresholds = history.thresholds();
+        let response = history
+            .usage(range)
+            .await
+            .map(|stats| {
+                Response::new(GetUsageStatsResponse {
+                    stats: Some(stats.into()),
+                    sitting_threshold: thresholds.sitting.to_cm(),
+                    standing_threshold: thresholds.standing.to_cm(),
+                })
+            })
+            .map_err(Into::into);
+        info!(client = %client_of(&request), ?request, ?response, "GetUsageStats");
+        response
+    }
+
+    async fn get_goal_progress(
+        &self,
+        request: Request<GetGoalProgressRequest>,
+    ) -> Result<Response<GetGoalProgressResponse>, Status> {
+        authorize(&request, Role::Observe)?;
+        let goal = self
+            .goal
+            .as_ref()
+            .ok_or_else(|| failed_precondition("No standing goal is configured"))?;
+        let response = goal
+            .progress()
+            .await
+            .map(|progress| {
+                Response::new(GetGoalProgressResponse {
+                    goal_ms: progress.goal.as_millis() as u64,
+                    standing_ms: progress.standing.as_millis() as u64,
+                    day_start_ms: progress.day_start_ms,
+                })
+            })
+            .map_err(Into::into);
+        info!(client = %client_of(&request), ?request, ?response, "GetGoalProgress");
+        response
+    }
+
+    async fn list_schedule(
+        &self,
+        request: Request<ListScheduleRequest>,
+    ) -> Result<Response<ListScheduleResponse>, Status> {
+        authorize(&request, Role::Observe)?;
+        let response = Ok(Response::new(ListScheduleResponse {
+            entries: self.scheduler.list().into_iter().map(Into::into).collect(),
+        }));
+        info!(client = %client_of(&request), ?request, ?response, "ListSchedule");
+        response
+    }
+
+    async fn set_schedule_entry(
+        &self,
+        request: Request<SetScheduleEntryRequest>,
+    ) -> Result<Response<SetScheduleEntryResponse>, Status> {
+        let ScheduleEntry {
+            name,
+            when,
+            targets,
+            ..
+        } = request.get_ref().entry.clone().unwrap_or_default();
+        let action = Action::SetScheduleEntry {
+            name: name.clone(),
+            when: when.clone(),
+            targets: targets.clone(),
+        };
+        self.authorize_audited(&request, Role::Admin, &action)?;
+        let response = Entry::parse(&when, &targets)
+            .and_then(|entry| self.scheduler.set(name, entry))
+            .map(|()| Response::new(SetScheduleEntryResponse {}))
+            .map_err(Into::into);
+        self.audit.record(
+            &client_of(&request),
+            action,
+            &audit_outcome(&response, "done"),
+        );
+        info!(client = %client_of(&request), ?request, ?response, "SetScheduleEntry");
+        response
+    }
+
+    async fn delete_schedule_entry(
+        &self,
+        request: Request<DeleteScheduleEntryRequest>,
+    ) -> Result<Response<DeleteScheduleEntryResponse>, Status> {
+        let action = Action::DeleteScheduleEntry {
+            name: request.get_ref().name.clone(),
+        };
+        self.authorize_audited(&request, Role::Admin, &action)?;
+        let response = self
+            .scheduler
+            .remove(&request.get_ref().name)
+            .map(|()| Response::new(DeleteScheduleEntryResponse {}))
+            .map_err(Into::into);
+        self.audit.record(
+            &client_of(&request),
+            action,
+            &audit_outcome(&response, "done"),
+        );
+        info!(client = %client_of(&request), ?request, ?response, "DeleteScheduleEntry");
+        response
+    }
+
+    async fn pause_schedule_entry(
+        &self,
+        request: Request<PauseScheduleEntryRequest>,
+    ) -> Result<Response<PauseScheduleEntryResponse>, Status> {
+        let PauseScheduleEntryRequest { name, paused } = request.get_ref();
+        let action = Action::PauseScheduleEntry {
+            name: name.clone(),
+            paused: *paused,
+        };
+        self.authorize_audited(&request, Role::Admin, &action)?;
+        let response = self
+            .scheduler
+            .pause(name, *paused)
+            .map(|()| Response::new(PauseScheduleEntryResponse {}))
+            .map_err(Into::into);
+        self.audit.record(
+            &client_of(&request),
+            action,
+            &audit_outcome(&response, "done"),
+        );
+        info!(client = %client_of(&request), ?request, ?response, "PauseScheduleEntry");
+        response
+    }
+
+    async fn start_cycle(
+        &self,
+        request: Request<StartCycleRequest>,
+    ) -> Result<Response<StartCycleResponse>, Status> {
+        let StartCycleRequest {
+            sit_ms,
+            stand_ms,
+            sit_target,
+            stand_target,
+        } = request.get_ref().clone();
+        let action = Action::StartCycle {
+            sit_ms,
+            stand_ms,
+            sit_target: sit_target.clone(),
+            stand_target: stand_target.clone(),
+        };
+        self.authorize_audited(&request, Role::Operate, &action)?;
+        let response = self
+            .cycle_settings(sit_ms, stand_ms, &sit_target, &stand_target)
+            .map(|settings| {
+                self.cycle.start(settings);
+                Response::new(StartCycleResponse {})
+            });
+        self.audit.record(
+            &client_of(&request),
+            action,
+            &audit_outcome(&response, "started"),
+        );
+        info!(client = %client_of(&request), ?request, ?response, "StartCycle");
+        response
+    }
+
+    async fn stop_cycle(
+        &self,
+        request: Request<StopCycleRequest>,
+    ) -> Result<Response<StopCycleResponse>, Status> {
+        self.authorize_audited(&request, Role::Operate, &Action::StopCycle)?;
+        let response = if self.cycle.stop() {
+            Ok(Response::new(StopCycleResponse {}))
+        } else {
+            Err(failed_precondition("No cycle is running"))
+        };
+        self.audit.record(
+            &client_of(&request),
+            Action::StopCycle,
+            &audit_outcome(&response, "stopped"),
+        );
+        info!(client = %client_of(&request), ?request, ?response, "StopCycle");
+        response
+    }
+
+    async fn get_cycle(
+        &self,
+        request: Request<GetCycleRequest>,
+    ) -> Result<Response<GetCycleResponse>, Status> {
+        authorize(&request, Role::Observe)?;
+        let response = Ok(Response::new(
+            self.cycle.status().map(Into::into).unwrap_or_default(),
+        ));
+        info!(client = %client_of(&request), ?request, ?response, "GetCycle");
+        response
+    }
+
+    async fn get_lock(
+        &self,
+        request: Request<GetLockRequest>,
+    ) -> Result<Response<GetLockResponse>, Status> {
+        authorize(&request, Role::Observe)?;
+        let locks = self.locks.active();
+        let scope = locks
+            .iter()
+            .map(|lock| lock.scope)
+            .max()
+            .map_or(lock::Scope::None, Into::into);
+        let response = Ok(Response::new(GetLockResponse {
+            scope: scope as i32,
+            locks: locks.into_iter().map(Into::into).collect(),
+        }));
+        info!(client = %client_of(&request), ?request, ?response, "GetLock");
+        response
+    }
+
+    async fn set_lock(
+        &self,
+        request: Request<SetLockRequest>,
+    ) -> Result<Response<SetLockResponse>, Status> {
+        let SetLockRequest { scope, reason } = request.get_ref().clone();
+        let scope = match lock::Scope::from_i32(scope) {
+            Some(lock::Scope::None) => None,
+            Some(lock::Scope::Automatic) => Some(LockScope::Automatic),
+            Some(lock::Scope::All) => Some(LockScope::All),
+            None => return Err(invalid_argument("Invalid lock scope")),
+        };
+        let action = match scope {
+            Some(scope) => Action::SetLock {
+                scope,
+                reason: reason.clone(),
+            },
+            None => Action::ClearLock,
+        };
+        self.authorize_audited(&request, Role::Operate, &action)?;
+        let client = client_of(&request);
+        let response = self
+            .locks
+            .set(scope.map(|scope| RuntimeLock::new(scope, reason, client.clone())))
+            .map(|()| Response::new(SetLockResponse {}))
+            .map_err(Into::into);
+        self.audit
+            .record(&client, action, &audit_outcome(&response, "done"));
+        info!(client = %client, ?request, ?response, "SetLock");
+        response
+    }
+
+    #[allow(clippy::result_large_err)]
+    async fn export(
+        &self,
+        request: Request<ExportRequest>,
+    ) -> Result<Response<Self::ExportStream>, Status> {
+        authorize(&request, Role::Observe)?;
+        let history = self
+            .history
+            .clone()
+            .ok_or_else(|| failed_precondition("History is disabled on the server"))?;
+        let ExportRequest {
+            start_ms,
+            end_ms,
+            day_starts_ms,
+        } = request.get_ref();
+        if day_starts_ms.windows(2).any(|days| days[0] >= days[1]) {
+            return Err(invalid_argument("Day starts are not ascending"));
+        }
+        let now = history::now_ms();
+        let end_ms = if *end_ms == 0 {
+            now
+        } else {
+            (*end_ms).min(now)
+        };
+        let mut boundaries = vec![*start_ms];
+        boundaries.extend(
+            day_starts_ms
+                .iter()
+                .copied()
+                .filter(|day_start| (*start_ms + 1..end_ms).contains(day_start)),
+        );
+        boundaries.push(end_ms);
+        let chunks: Vec<_> = boundaries
+            .windows(2)
+            .filter(|bounds| bounds[0] < bounds[1])
+            .map(|bounds| bounds[0]..bounds[1])
+            .collect();
+        info!(
+            client = %client_of(&request),
+            start_ms,
+            end_ms,
+            chunks = chunks.len(),
+            "Export",
+        );
+        let response_stream =
+            stream::iter(chunks).flat_map(move |range| export_chunk(history.clone(), range));
+        Ok(Response::new(
+            Box::pin(response_stream) as Self::ExportStream
+        ))
+    }
 }
@@ -0,0 +1,218 @@
+use crate::utils::Position;
+use std::ops::Range;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Posture {
+    Sitting,
+    Standing,
+}
+
+impl Posture {
+    pub fn name(self) -> &'static str {
+        match self {
+            Posture::Sitting => "sitting",
+            Posture::Standing => "standing",
+        }
+    }
+}
+
+/// Heights separating sitting from standing
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct PostureThresholds {
+    /// Heights below are counted as sitting
+    pub sitting: Position,
+    /// Heights at or above are counted as standing
+    pub standing: Position,
+}
+
+impl PostureThresholds {
+    /// Heights between the thresholds keep the previous posture, so small moves do not flip it
+    pub fn classify(&self, position: Position, previous: Option<Posture>) -> Posture {
+        if position >= self.standing {
+            Posture::Standing
+        } else if position < self.sitting {
+            Posture::Sitting
+        } else {
+            previous.unwrap_or(Posture::Sitting)
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct UsageStats {
+    pub sitting_ms: u64,
+    pub standing_ms: u64,
+    /// Time not covered by any sample
+    pub unknown_ms: u64,
+    /// Changes between sitting and standing
+    pub transitions: u32,
+    pub longest_sit_ms: u64,
+}
+
+/**
+ * Usage over a time range, accumulated from position samples in chronological order.
+ * Each sample holds until the next one, but at most for the maximum gap,
+ * after which the posture is unknown until the next sample.
+ */
+pub struct UsageAccumulator {
+    thresholds: PostureThresholds,
+    max_gap_ms: i64,
+    range: Range<i64>,
+    stats: UsageStats,
+    /// Time accounted until
+    cursor: i64,
+    /// Time and posture of the last sample, unless followed by a gap
+    last: Option<(i64, Posture)>,
+    /// Start of the sit in progress
+    sit_start: Option<i64>,
+}
+
+impl UsageAccumulator {
+    /// An inverted range is taken as empty
+    pub fn new(range: Range<i64>, thresholds: PostureThresholds, max_gap_ms: i64) -> Self {
+        let range = range.start..range.end.max(range.start);
+        UsageAccumulator {
+            thresholds,
+            max_gap_ms,
+            cursor: range.start,
+            range,
+            stats: UsageStats::default(),
+            last: None,
+            sit_start: None,
+        }
+    }
+
+    /// The first sample may be before the range, to give the posture at its start
+    pub fn add(&mut self, timestamp_ms: i64, position: Position) {
+        self.advance(timestamp_ms.clamp(self.range.start, self.range.end));
+        let previous = self.last.map(|(_, posture)| posture);
+        let posture = self.thresholds.classify(position, previous);
+        if previous.is_some_and(|previous| previous != posture) {
+            self.stats.transitions += 1;
+        }
+        match posture {
+            Posture::Sitting => {
+                self.sit_start.get_or_insert(self.cursor);
+            }
+            Posture::Standing => self.end_sit(self.cursor),
+        }
+        self.last = Some((timestamp_ms, posture));
+    }
+
+    pub fn finish(mut self) -> UsageStats {
+        self.advance(self.range.end);
+        self.end_sit(self.cursor);
+        self.stats
+    }
+
+    fn advance(&mut self, until: i64) {
+        if until <= self.cursor {
+            return;
+        }
+        let known_until = match self.last {
+            Some((timestamp_ms, posture)) => {
+                let known_until = (timestamp_ms + self.max_gap_ms).clamp(self.cursor, until);
+                let known = (known_until - self.cursor) as u64;
+                match posture {
+                    Posture::Sitting => self.stats.sitting_ms += known,
+                    Posture::Standing => self.stats.standing_ms += known,
+                }
+                known_until
+            }
+            None => self.cursor,
+        };
+        if known_until < until {
+            // the posture may have changed during the gap, it is neither a sit nor a transition
+            self.stats.unknown_ms += (until - known_until) as u64;
+            self.end_sit(known_until);
+            self.last = None;
+        }
+        self.cursor = until;
+    }
+
+    fn end_sit(&mut self, at: i64) {
+        if let Some(start) = self.sit_start.take() {
+            self.stats.longest_sit_ms = self.stats.longest_sit_ms.max((at - start) as u64);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_GAP_MS: i64 = 1000;
+
+    fn thresholds() -> PostureThresholds {
+        PostureThresholds {
+            sitting: Position::from_cm(90.0).unwrap(),
+            standing: Position::from_cm(100.0).unwrap(),
+        }
+    }
+
+    fn usage(range: Range<i64>, samples: &[(i64, f32)]) -> UsageStats {
+        let mut usage = UsageAccumulator::new(range, thresholds(), MAX_GAP_MS);
+        for &(timestamp_ms, cm) in samples {
+            usage.add(timestamp_ms, Position::from_cm(cm).unwrap());
+        }
+        usage.finish()
+    }
+
+    #[test]
+    fn gap_is_unknown() {
+        let stats = usage(0..12_000, &[(0, 70.0), (10_000, 110.0)]);
+        assert_eq!(stats.sitting_ms, 1000);
+        assert_eq!(stats.standing_ms, 1000);
+        assert_eq!(stats.unknown_ms, 10_000);
+        // the posture may have changed during the gap
+        assert_eq!(stats.transitions, 0);
+    }
+
+    #[test]
+    fn sample_before_range_gives_initial_posture() {
+        let stats = usage(0..1000, &[(-500, 110.0)]);
+        assert_eq!(stats.standing_ms, 500);
+        assert_eq!(stats.unknown_ms, 500);
+    }
+
+    #[test]
+    fn hysteresis_between_thresholds() {
+        let samples = [(0, 110.0), (1000, 95.0), (2000, 85.0), (3000, 95.0)];
+        let stats = usage(0..4000, &samples);
+        assert_eq!(stats.standing_ms, 2000);
+        assert_eq!(stats.sitting_ms, 2000);
+        assert_eq!(stats.unknown_ms, 0);
+        assert_eq!(stats.transitions, 1);
+        assert_eq!(stats.longest_sit_ms, 2000);
+    }
+
+    #[test]
+    fn longest_sit_across_gap() {
+        let samples = [(0, 70.0), (500, 70.0), (5000, 70.0), (5900, 70.0)];
+        let stats = usage(0..7000, &samples);
+        assert_eq!(stats.sitting_ms, 1500 + 1900);
+        assert_eq!(stats.unknown_ms, 3500 + 100);
+        assert_eq!(stats.longest_sit_ms, 1900);
+    }
+
+    #[test]
+    fn sit_ends_at_range_end() {
+        let stats = usage(
+            0..3000,
+            &[(0, 110.0), (1000, 70.0), (1900, 70.0), (2800, 70.0)],
+        );
+        assert_eq!(stats.longest_sit_ms, 2000);
+        assert_eq!(stats.transitions, 1);
+    }
+
+    #[test]
+    fn empty_and_inverted_ranges() {
+        for (start, end) in [(1000, 1000), (5000, 1000)] {
+            let stats = usage(start..end, &[(0, 70.0), (6000, 110.0)]);
+            assert_eq!(stats.sitting_ms, 0);
+            assert_eq!(stats.standing_ms, 0);
+            assert_eq!(stats.unknown_ms, 0);
+            assert_eq!(stats.longest_sit_ms, 0);
+        }
+    }
+}
@@ -0,0 +1,243 @@
+use crate::events::{ConnectionState, Event, EventStream};
+use futures::{future, StreamExt};
+use listenfd::ListenFd;
+use sd_notify::NotifyState;
+use std::{io, net::TcpListener, os::unix::net::UnixListener, time::Duration};
+use tokio::time::{self, Interval, MissedTickBehavior};
+use tracing::{debug, warn};
+
+/// Listening sockets passed in by systemd socket activation
+#[derive(Default)]
+pub struct ActivatedListeners {
+    pub tcp: Vec<TcpListener>,
+    pub unix: Vec<UnixListener>,
+}
+
+impl ActivatedListeners {
+    /// Take the sockets from `LISTEN_FDS`, empty if deskd was not socket activated
+    pub fn from_env() -> io::Result<Self> {
+        let mut fds = ListenFd::from_env();
+        let mut listeners = ActivatedListeners::default();
+        for index in 0..fds.len() {
+            if let Ok(Some(listener)) = fds.take_tcp_listener(index) {
+                listeners.tcp.push(listener);
+            } else if let Some(listener) = fds.take_unix_listener(index)? {
+                listeners.unix.push(listener);
+            }
+        }
+        Ok(listeners)
+    }
+}
+
+/// Does nothing if deskd is not run by systemd
+fn notify(states: &[NotifyState]) {
+    if let Err(e) = sd_notify::notify(false, states) {
+        warn!("Error notifying systemd: {}", e);
+    }
+}
+
+pub fn notify_ready() {
+    notify(&[
+        NotifyState::Ready,
+        NotifyState::Status(status(ConnectionState::Connected)),
+    ]);
+}
+
+pub fn notify_stopping() {
+    notify(&[NotifyState::Stopping]);
+}
+
+/// Keep the `STATUS=` shown by systemctl in sync with the desk connection
+pub async fn report_status(mut events: EventStream) {
+    while let Some(event) = events.next().await {
+        if let Event::Connection(state) = event {
+            notify(&[NotifyState::Status(status(state))]);
+        }
+    }
+}
+
+fn status(state: ConnectionState) -> &'static str {
+    match state {
+        ConnectionState::Connected => "Connected to desk",
+        ConnectionState::Disconnected => "Desk disconnected",
+        ConnectionState::Reconnecting => "Reconnecting to desk",
+    }
+}
+
+/**
+ * Pings the systemd watchdog at half of `WATCHDOG_USEC`, if the controller loop made progress.
+ * The loop reports completed updates and commands, so the pings stop if it hangs on the desk.
+ * While the desk is disconnected, only finished connection attempts count.
+ */
+pub struct Watchdog {
+    interval: Option<Interval>,
+    progressed: bool,
+}
+
+impl Watchdog {
+    pub fn from_env() -> Self {
+        let mut usec = 0;
+        let interval = sd_notify::watchdog_enabled(false, &mut usec).then(|| {
+            let period = Duration::from_micros(usec) / 2;
+            debug!(?period, "Watchdog enabled");
+            let mut interval = time::interval(period);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            interval
+        });
+        Watchdog {
+            interval,
+            progressed: false,
+        }
+    }
+
+    /// Resolves when a ping is due, never if the watchdog is disabled
+    pub async fn due(&mut self) {
+        match &mut self.interval {
+            Some(interval) => {
+                interval.tick().await;
+            }
+            None => future::pending().await,
+        }
+    }
+
+    /// The controller loop completed an update or a command
+    pub fn progress(&mut self) {
+        self.progressed = true;
+    }
+
+    /// Ping if the loop made progress since the last ping, returns whether it did
+    pub fn ping_if_progressed(&mut self) -> bool {
+        let progressed = std::mem::take(&mut self.progressed);
+        if progressed {
+            self.ping();
+        }
+        progressed
+    }
+
+    /// Ping regardless of progress, after checking the loop some other way
+    pub fn ping(&mut self) {
+        self.progressed = false;
+        notify(&[NotifyState::Watchdog]);
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::{
+        env, fs,
+        os::unix::net::UnixDatagram,
+        path::PathBuf,
+        sync::{Mutex, MutexGuard},
+    };
+
+    /// `NOTIFY_SOCKET` is shared by the whole process
+    static ENV: Mutex<()> = Mutex::new(());
+
+    /// Local socket standing in for systemd, removed when dropped
+    pub(crate) struct FakeNotifySocket {
+        socket: UnixDatagram,
+        path: PathBuf,
+        _env: MutexGuard<'static, ()>,
+    }
+
+    impl FakeNotifySocket {
+        pub(crate) fn bind(name: &str) -> Self {
+            let env = ENV.lock().unwrap_or_else(|e| e.into_inner());
+            let path =
+                env::temp_dir().join(format!("deskd-notify-{}-{}", std::process::id(), name));
+            fs::remove_file(&path).unwrap_or(());
+            let socket = UnixDatagram::bind(&path).unwrap();
+            socket.set_nonblocking(true).unwrap();
+            env::set_var("NOTIFY_SOCKET", &path);
+            FakeNotifySocket {
+                socket,
+                path,
+                _env: env,
+            }
+        }
+
+        /// Next message, if any was sent
+        pub(crate) fn recv(&self) -> Option<String> {
+            let mut buffer = [0; 1024];
+            match self.socket.recv(&mut buffer) {
+                Ok(len) => Some(String::from_utf8_lossy(&buffer[..len]).into_owned()),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => None,
+                Err(e) => panic!("Error receiving notification: {}", e),
+            }
+        }
+    }
+
+    impl Drop for FakeNotifySocket {
+        fn drop(&mut self) {
+            env::remove_var("NOTIFY_SOCKET");
+            env::remove_var("WATCHDOG_USEC");
+            env::remove_var("WATCHDOG_PID");
+            fs::remove_file(&self.path).unwrap_or(());
+        }
+    }
+
+    #[test]
+    fn ready_with_status() {
+        let systemd = FakeNotifySocket::bind("ready");
+        notify_ready();
+        assert_eq!(
+            systemd.recv().as_deref(),
+            Some("READY=1\nSTATUS=Connected to desk\n")
+        );
+        assert_eq!(systemd.recv(), None);
+    }
+
+    #[tokio::test]
+    async fn status_follows_connection() {
+        let systemd = FakeNotifySocket::bind("status");
+        let (sender, _) = tokio::sync::broadcast::channel(4);
+        let events = crate::events::subscribe(&sender);
+        sender
+            .send(Event::Connection(ConnectionState::Reconnecting))
+            .unwrap();
+        sender.send(Event::ConfigReloaded).unwrap();
+        sender
+            .send(Event::Connection(ConnectionState::Connected))
+            .unwrap();
+        drop(sender);
+        report_status(events).await;
+        assert_eq!(
+            systemd.recv().as_deref(),
+            Some("STATUS=Reconnecting to desk\n")
+        );
+        assert_eq!(
+            systemd.recv().as_deref(),
+            Some("STATUS=Connected to desk\n")
+        );
+        assert_eq!(systemd.recv(), None);
+    }
+
+    #[tokio::test]
+    async fn watchdog_pings_only_after_progress() {
+        let systemd = FakeNotifySocket::bind("watchdog");
+        env::set_var("WATCHDOG_USEC", "100000");
+        env::set_var("WATCHDOG_PID", std::process::id().to_string());
+        let mut watchdog = Watchdog::from_env();
+        watchdog.due().await;
+        assert!(!watchdog.ping_if_progressed());
+        assert_eq!(systemd.recv(), None);
+
+        watchdog.progress();
+        watchdog.due().await;
+        assert!(watchdog.ping_if_progressed());
+        assert_eq!(systemd.recv().as_deref(), Some("WATCHDOG=1\n"));
+
+        // progress is consumed by the ping
+        watchdog.due().await;
+        assert!(!watchdog.ping_if_progressed());
+        assert_eq!(systemd.recv(), None);
+    }
+
+    #[test]
+    fn watchdog_disabled_without_usec() {
+        let _systemd = FakeNotifySocket::bind("disabled");
+        env::set_var("WATCHDOG_PID", std::process::id().to_string());
+        assert!(Watchdog::from_env().interval.is_none());
+    }
+}
@@ -30,8 +30,11 @@ pub enum PositionError {
 pub struct Position(u16);
 
 impl Position {
+    pub const MIN_CM: f32 = 62.0;
+    pub const MAX_CM: f32 = 127.0;
+
     pub fn from_cm(cm: f32) -> Result<Self, PositionError> {
-        if !(62.0..=127.0).contains(&cm) {
+        if !(Self::MIN_CM..=Self::MAX_CM).contains(&cm) {
             return Err(PositionError::OutOfBound(cm));
         }
         let pos = Position((cm * 100.0) as u16 - 6200);
@@ -40,7 +43,7 @@ impl Position {
     }
 
     pub fn to_cm(self) -> f32 {
-        (self.0 as u16 + 6200) as f32 / 100.0
+        (self.0 + 6200) as f32 / 100.0
     }
 
     fn check(&self) -> Result<(), PositionError> {